@@ -0,0 +1,278 @@
+//! Passing a gate over HTTP
+//!
+//! The challenge comes from the page at `/get_challenge` and the solution goes
+//! to `/post_nonce`, as in a browser, or both go through the JSON API under
+//! `/api/v1`. Either way the session arrives as a `Set-Cookie`.
+
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::{Algorithm, Solution, Solver, Target};
+
+/// `User-Agent` sent with every request; stateless gates bind challenges to it
+const USER_AGENT: &str = concat!("mpow-solve/", env!("CARGO_PKG_VERSION"));
+
+/// Which endpoints to talk to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Flow {
+	/// `/get_challenge` and `/post_nonce`
+	#[default]
+	Page,
+	/// `/api/v1/challenge` and `/api/v1/solve`
+	Api,
+}
+
+/// Challenge to solve
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+	/// Sent back with the nonce
+	pub token: String,
+	pub challenge: String,
+	pub algorithm: Algorithm,
+	pub target: Target,
+}
+
+#[derive(Deserialize)]
+struct ApiChallenge {
+	token: String,
+	challenge: String,
+	algorithm: Algorithm,
+	target: String,
+}
+
+/// Session cookie granted for a solution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionCookie {
+	pub name: String,
+	pub value: String,
+	pub max_age: Option<u64>,
+	pub secure: bool,
+	pub http_only: bool,
+}
+
+impl SessionCookie {
+	/// Parses a `Set-Cookie` header value
+	pub fn parse(set_cookie: &str) -> Option<Self> {
+		let mut parts = set_cookie.split(';').map(str::trim);
+		let (name, value) = parts.next()?.split_once('=')?;
+		if name.is_empty() {
+			return None;
+		}
+		let mut cookie = Self {
+			name: name.to_owned(),
+			value: value.to_owned(),
+			max_age: None,
+			secure: false,
+			http_only: false,
+		};
+		for attribute in parts {
+			let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+			match key.to_ascii_lowercase().as_str() {
+				"max-age" => cookie.max_age = value.parse().ok(),
+				"secure" => cookie.secure = true,
+				"httponly" => cookie.http_only = true,
+				_ => {}
+			}
+		}
+		Some(cookie)
+	}
+
+	/// Line of a Netscape cookie jar, as read by `curl -b` and `wget --load-cookies`
+	///
+	/// # Arguments
+	/// * `host` - host the cookie was set by
+	/// * `now` - current unix time, to turn `Max-Age` into an expiry
+	pub fn to_netscape(&self, host: &str, now: u64) -> String {
+		let expires = self.max_age.map_or(0, |age| now + age);
+		format!(
+			"{prefix}{host}\tFALSE\t/\t{secure}\t{expires}\t{name}\t{value}",
+			prefix = if self.http_only { "#HttpOnly_" } else { "" },
+			host = host,
+			secure = if self.secure { "TRUE" } else { "FALSE" },
+			expires = expires,
+			name = self.name,
+			value = self.value
+		)
+	}
+}
+
+/// Reads the challenge constants out of a challenge page
+pub fn parse_challenge_page(html: &str) -> Result<Challenge, String> {
+	let constant = |name: &str| -> Result<&str, String> {
+		let prefix = format!("const {} = ", name);
+		html.lines()
+			.map(str::trim)
+			.find_map(|line| line.strip_prefix(prefix.as_str()))
+			.and_then(|rest| rest.strip_suffix(';'))
+			.ok_or_else(|| format!("challenge page has no {}", name))
+	};
+	let string = |name: &str| -> Result<String, String> {
+		let value = constant(name)?;
+		value
+			.strip_prefix('"')
+			.and_then(|v| v.strip_suffix('"'))
+			.map(str::to_owned)
+			.ok_or_else(|| format!("challenge page has a malformed {}", name))
+	};
+	let algorithm = serde_json::from_str(constant("algorithm")?)
+		.map_err(|e| format!("challenge page has an unknown algorithm: {}", e))?;
+	Ok(Challenge {
+		token: string("token")?,
+		challenge: string("challenge")?,
+		algorithm,
+		target: Target::from_hex(&string("target")?)?,
+	})
+}
+
+/// HTTP client for one gate
+pub struct Client {
+	base: Url,
+	agent: ureq::Agent,
+	flow: Flow,
+}
+
+impl Client {
+	/// # Arguments
+	/// * `base_url` - where the gate's endpoints live, e.g. `https://example.com/`
+	/// * `flow` - which endpoints to use
+	pub fn new(base_url: &str, flow: Flow) -> Result<Self, String> {
+		let base = Url::parse(base_url).map_err(|e| format!("invalid URL {:?}: {}", base_url, e))?;
+		if base.host_str().is_none() {
+			return Err(format!("invalid URL {:?}: no host", base_url));
+		}
+		let agent = ureq::AgentBuilder::new()
+			.user_agent(USER_AGENT)
+			.timeout(Duration::from_secs(30))
+			.redirects(0)
+			.build();
+		Ok(Self { base, agent, flow })
+	}
+
+	pub fn host(&self) -> &str {
+		self.base.host_str().unwrap_or_default()
+	}
+
+	fn endpoint(&self, path: &str) -> Result<Url, String> {
+		self.base.join(path).map_err(|e| format!("invalid endpoint {}: {}", path, e))
+	}
+
+	pub fn fetch_challenge(&self) -> Result<Challenge, String> {
+		match self.flow {
+			Flow::Page => {
+				let html = self
+					.agent
+					.request_url("GET", &self.endpoint("get_challenge")?)
+					.call()
+					.map_err(request_error)?
+					.into_string()
+					.map_err(|e| format!("cannot read challenge page: {}", e))?;
+				parse_challenge_page(&html)
+			}
+			Flow::Api => {
+				let challenge: ApiChallenge = self
+					.agent
+					.request_url("GET", &self.endpoint("api/v1/challenge")?)
+					.call()
+					.map_err(request_error)?
+					.into_json()
+					.map_err(|e| format!("cannot read challenge: {}", e))?;
+				Ok(Challenge {
+					token: challenge.token,
+					challenge: challenge.challenge,
+					algorithm: challenge.algorithm,
+					target: Target::from_hex(&challenge.target)?,
+				})
+			}
+		}
+	}
+
+	/// Submits a nonce and returns the session cookie it earned
+	pub fn submit(&self, challenge: &Challenge, nonce: &str) -> Result<SessionCookie, String> {
+		let response = match self.flow {
+			Flow::Page => self
+				.agent
+				.request_url("POST", &self.endpoint("post_nonce")?)
+				.send_form(&[("nonce", nonce), ("token", challenge.token.as_str())]),
+			Flow::Api => self
+				.agent
+				.request_url("POST", &self.endpoint("api/v1/solve")?)
+				.send_json(serde_json::json!({ "token": challenge.token, "nonce": nonce })),
+		}
+		.map_err(request_error)?;
+		response
+			.header("set-cookie")
+			.and_then(SessionCookie::parse)
+			.ok_or_else(|| String::from("solution accepted but no session cookie was set"))
+	}
+
+	/// Fetches a challenge, solves it with `solver` and submits the solution
+	///
+	/// # Returns
+	/// The session cookie and the solution it was earned with
+	pub fn pass(&self, solver: impl Fn(Algorithm) -> Solver) -> Result<(SessionCookie, Solution), String> {
+		let challenge = self.fetch_challenge()?;
+		let solution = solver(challenge.algorithm)
+			.solve(&challenge.challenge, &challenge.target)?
+			.ok_or_else(|| String::from("no solution found within the attempt limit"))?;
+		let cookie = self.submit(&challenge, &solution.nonce)?;
+		Ok((cookie, solution))
+	}
+}
+
+fn request_error(error: ureq::Error) -> String {
+	match error {
+		ureq::Error::Status(code, response) => {
+			let body = response.into_string().unwrap_or_default();
+			format!("gate answered {}: {}", code, body.trim())
+		}
+		other => format!("request failed: {}", other),
+	}
+}
+
+/// Current unix time
+pub fn current_timestamp() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_challenge_page() {
+		let html = format!(
+			"<script>\n  const challenge = \"abc.def\";\n  const token = \"tok\";\n  const algorithm = {{\"name\":\"scrypt\",\"logN\":14,\"r\":8,\"p\":1}};\n  const target = \"{}\";\n  const returnTo = \"/\";\n</script>",
+			"0f".repeat(32)
+		);
+		let challenge = parse_challenge_page(&html).unwrap();
+		assert_eq!(challenge.token, "tok");
+		assert_eq!(challenge.challenge, "abc.def");
+		assert_eq!(challenge.algorithm, Algorithm::Scrypt { log_n: 14, r: 8, p: 1 });
+		assert!(parse_challenge_page("<html></html>").is_err());
+	}
+
+	#[test]
+	fn test_session_cookie() {
+		let cookie = SessionCookie::parse("mpow_token=abc.def; HttpOnly; Secure; SameSite=Strict; Max-Age=60").unwrap();
+		assert_eq!((cookie.name.as_str(), cookie.value.as_str()), ("mpow_token", "abc.def"));
+		assert_eq!(cookie.max_age, Some(60));
+		assert_eq!(
+			cookie.to_netscape("example.com", 1_000),
+			"#HttpOnly_example.com\tFALSE\t/\tTRUE\t1060\tmpow_token\tabc.def"
+		);
+		assert!(SessionCookie::parse("garbage").is_none());
+	}
+
+	#[test]
+	fn test_endpoints_relative_to_base() {
+		let client = Client::new("https://example.com", Flow::Page).unwrap();
+		assert_eq!(client.endpoint("get_challenge").unwrap().as_str(), "https://example.com/get_challenge");
+		let client = Client::new("https://example.com/gate/", Flow::Api).unwrap();
+		assert_eq!(client.endpoint("api/v1/solve").unwrap().as_str(), "https://example.com/gate/api/v1/solve");
+		assert!(Client::new("not a url", Flow::Page).is_err());
+	}
+}
@@ -0,0 +1,223 @@
+//! Native solver for mpow challenges
+//!
+//! A solution is a nonce such that the digest of `challenge || nonce`, read as
+//! a 256-bit big-endian number, is at most the challenge's target. The digest
+//! is SHA-256 or, for memory-hard challenges, scrypt salted with the challenge.
+//! [`Solver`] searches decimal nonces on several threads at once; [`client`]
+//! fetches a challenge from a gate, solves it and returns the session cookie.
+
+pub mod client;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+	thread,
+};
+
+/// Hash function of a challenge, as described by the challenge page and API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum Algorithm {
+	Sha256,
+	Scrypt {
+		#[serde(rename = "logN")]
+		log_n: u8,
+		r: u32,
+		p: u32,
+	},
+}
+
+impl Algorithm {
+	/// Digest a nonce is judged on
+	pub fn digest(&self, challenge: &str, nonce: &str) -> Result<[u8; 32], String> {
+		match *self {
+			Algorithm::Sha256 => {
+				let mut hasher = Sha256::new();
+				hasher.update(challenge.as_bytes());
+				hasher.update(nonce.as_bytes());
+				Ok(hasher.finalize().into())
+			}
+			Algorithm::Scrypt { log_n, r, p } => {
+				let params = scrypt::Params::new(log_n, r, p, 32)
+					.map_err(|e| format!("invalid scrypt parameters: {}", e))?;
+				let password = format!("{}{}", challenge, nonce);
+				let mut output = [0u8; 32];
+				scrypt::scrypt(password.as_bytes(), challenge.as_bytes(), &params, &mut output)
+					.map_err(|e| format!("scrypt failed: {}", e))?;
+				Ok(output)
+			}
+		}
+	}
+}
+
+/// Largest digest accepted as a solution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target([u8; 32]);
+
+impl Target {
+	/// Target requiring at least `bits` leading zero bits
+	pub fn from_bits(bits: u32) -> Self {
+		let mut target = [0xffu8; 32];
+		for (i, byte) in target.iter_mut().enumerate() {
+			let zeros = bits.saturating_sub(8 * i as u32).min(8);
+			*byte = (0xffu16 >> zeros) as u8;
+		}
+		Self(target)
+	}
+
+	/// Parses the 64 hex digit form used by the challenge page and API
+	pub fn from_hex(target: &str) -> Result<Self, String> {
+		let bytes = hex::decode(target).map_err(|e| format!("invalid target: {}", e))?;
+		let bytes: [u8; 32] = bytes
+			.try_into()
+			.map_err(|_| String::from("invalid target: expected 32 bytes"))?;
+		Ok(Self(bytes))
+	}
+
+	pub fn is_met_by(&self, digest: &[u8; 32]) -> bool {
+		digest <= &self.0
+	}
+}
+
+/// Nonce meeting a challenge's target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Solution {
+	pub nonce: String,
+	/// Nonces tried across all threads
+	pub attempts: u64,
+}
+
+/// Multi-threaded nonce search
+#[derive(Debug, Clone, Copy)]
+pub struct Solver {
+	algorithm: Algorithm,
+	threads: usize,
+	max_attempts: u64,
+}
+
+impl Solver {
+	/// Solver using every available core and searching until it succeeds
+	pub fn new(algorithm: Algorithm) -> Self {
+		let threads = thread::available_parallelism().map_or(1, |n| n.get());
+		Self {
+			algorithm,
+			threads,
+			max_attempts: u64::MAX,
+		}
+	}
+
+	/// Number of threads searching at once, at least 1
+	pub fn threads(mut self, threads: usize) -> Self {
+		self.threads = threads.max(1);
+		self
+	}
+
+	/// Gives up after roughly this many nonces
+	pub fn max_attempts(mut self, max_attempts: u64) -> Self {
+		self.max_attempts = max_attempts;
+		self
+	}
+
+	/// Searches for a nonce meeting `target`
+	///
+	/// # Arguments
+	/// * `challenge` - challenge string to solve
+	/// * `target` - largest acceptable digest
+	///
+	/// # Returns
+	/// The first nonce found, `Ok(None)` if `max_attempts` ran out, or `Err(String)`
+	/// if the algorithm's parameters are invalid
+	pub fn solve(&self, challenge: &str, target: &Target) -> Result<Option<Solution>, String> {
+		// Fail on bad parameters before starting any thread
+		self.algorithm.digest(challenge, "0")?;
+
+		let found = AtomicBool::new(false);
+		let winner = AtomicU64::new(u64::MAX);
+		let attempts = AtomicU64::new(0);
+		let threads = self.threads as u64;
+		thread::scope(|scope| {
+			for offset in 0..threads {
+				let (found, winner, attempts) = (&found, &winner, &attempts);
+				scope.spawn(move || {
+					let mut nonce = offset;
+					let mut tried = 0u64;
+					while !found.load(Ordering::Relaxed) && nonce < self.max_attempts {
+						let candidate = nonce.to_string();
+						tried += 1;
+						let digest = self.algorithm.digest(challenge, &candidate).unwrap_or([0xff; 32]);
+						if target.is_met_by(&digest) {
+							winner.fetch_min(nonce, Ordering::Relaxed);
+							found.store(true, Ordering::Relaxed);
+							break;
+						}
+						nonce = match nonce.checked_add(threads) {
+							Some(next) => next,
+							None => break,
+						};
+					}
+					attempts.fetch_add(tried, Ordering::Relaxed);
+				});
+			}
+		});
+
+		if !found.load(Ordering::Relaxed) {
+			return Ok(None);
+		}
+		Ok(Some(Solution {
+			nonce: winner.load(Ordering::Relaxed).to_string(),
+			attempts: attempts.load(Ordering::Relaxed),
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_target_forms_agree() {
+		let target = Target::from_bits(12);
+		assert_eq!(Target::from_hex(&format!("000f{}", "f".repeat(60))).unwrap(), target);
+		assert!(Target::from_hex("00ff").is_err());
+		assert!(Target::from_hex(&"g".repeat(64)).is_err());
+	}
+
+	#[test]
+	fn test_solves_sha256() {
+		let target = Target::from_bits(12);
+		let solution = Solver::new(Algorithm::Sha256)
+			.threads(4)
+			.solve("challenge", &target)
+			.unwrap()
+			.unwrap();
+		let digest = Algorithm::Sha256.digest("challenge", &solution.nonce).unwrap();
+		assert!(target.is_met_by(&digest));
+		assert!(solution.attempts > 0);
+	}
+
+	#[test]
+	fn test_solves_scrypt() {
+		let algorithm = Algorithm::Scrypt { log_n: 4, r: 1, p: 1 };
+		let target = Target::from_bits(4);
+		let solution = Solver::new(algorithm).solve("challenge", &target).unwrap().unwrap();
+		assert!(target.is_met_by(&algorithm.digest("challenge", &solution.nonce).unwrap()));
+
+		let invalid = Algorithm::Scrypt { log_n: 0, r: 0, p: 0 };
+		assert!(Solver::new(invalid).solve("challenge", &target).is_err());
+	}
+
+	#[test]
+	fn test_gives_up() {
+		let solver = Solver::new(Algorithm::Sha256).threads(2).max_attempts(16);
+		assert_eq!(solver.solve("challenge", &Target::from_bits(64)).unwrap(), None);
+	}
+
+	#[test]
+	fn test_algorithm_descriptions() {
+		let parsed: Algorithm = serde_json::from_str(r#"{"name":"scrypt","logN":14,"r":8,"p":1}"#).unwrap();
+		assert_eq!(parsed, Algorithm::Scrypt { log_n: 14, r: 8, p: 1 });
+		let parsed: Algorithm = serde_json::from_str(r#"{"name":"sha256"}"#).unwrap();
+		assert_eq!(parsed, Algorithm::Sha256);
+	}
+}
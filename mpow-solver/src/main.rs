@@ -0,0 +1,129 @@
+use std::{fs, path::PathBuf, process::ExitCode, time::Instant};
+
+use mpow_solver::{
+	client::{current_timestamp, Client, Flow},
+	Solver,
+};
+
+const USAGE: &str = "usage: mpow-solve [--api] [--threads N] [--max-attempts N] [--cookie-jar FILE] URL
+
+Fetches a challenge from the mpow gate at URL, solves it on every core and
+prints the session cookie as name=value.
+
+  --api             use /api/v1 instead of /get_challenge and /post_nonce
+  --threads N       solver threads (default: all cores)
+  --max-attempts N  give up after N nonces
+  --cookie-jar FILE also write the cookie to FILE in Netscape format (curl -b FILE)";
+
+/// Command-line options
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Options {
+	url: String,
+	flow: Flow,
+	threads: Option<usize>,
+	max_attempts: Option<u64>,
+	cookie_jar: Option<PathBuf>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+	let mut options = Options::default();
+	let mut url = None;
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+		match arg.as_str() {
+			"--api" => options.flow = Flow::Api,
+			"--threads" => {
+				let threads = value("--threads")?;
+				options.threads = Some(threads.parse().map_err(|_| format!("invalid --threads {:?}", threads))?);
+			}
+			"--max-attempts" => {
+				let max = value("--max-attempts")?;
+				options.max_attempts = Some(max.parse().map_err(|_| format!("invalid --max-attempts {:?}", max))?);
+			}
+			"--cookie-jar" => options.cookie_jar = Some(PathBuf::from(value("--cookie-jar")?)),
+			"-h" | "--help" => return Err(String::new()),
+			flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+			_ if url.is_some() => return Err(String::from("only one URL may be given")),
+			_ => url = Some(arg),
+		}
+	}
+	options.url = url.ok_or_else(|| String::from("missing URL"))?;
+	Ok(options)
+}
+
+fn run(options: &Options) -> Result<(), String> {
+	let client = Client::new(&options.url, options.flow)?;
+	let started = Instant::now();
+	let (cookie, solution) = client.pass(|algorithm| {
+		let mut solver = Solver::new(algorithm);
+		if let Some(threads) = options.threads {
+			solver = solver.threads(threads);
+		}
+		if let Some(max_attempts) = options.max_attempts {
+			solver = solver.max_attempts(max_attempts);
+		}
+		solver
+	})?;
+	eprintln!(
+		"solved with nonce {} after {} attempts in {:.1}s",
+		solution.nonce,
+		solution.attempts,
+		started.elapsed().as_secs_f64()
+	);
+
+	if let Some(path) = &options.cookie_jar {
+		let jar = format!("# Netscape HTTP Cookie File\n{}\n", cookie.to_netscape(client.host(), current_timestamp()));
+		fs::write(path, jar).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+	}
+	println!("{}={}", cookie.name, cookie.value);
+	Ok(())
+}
+
+fn main() -> ExitCode {
+	let options = match parse_args(std::env::args().skip(1)) {
+		Ok(options) => options,
+		Err(e) => {
+			if !e.is_empty() {
+				eprintln!("mpow-solve: {}", e);
+			}
+			eprintln!("{}", USAGE);
+			return ExitCode::from(2);
+		}
+	};
+	match run(&options) {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(e) => {
+			eprintln!("mpow-solve: {}", e);
+			ExitCode::FAILURE
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(list: &[&str]) -> Vec<String> {
+		list.iter().map(|s| s.to_string()).collect()
+	}
+
+	#[test]
+	fn test_parse_args() {
+		let options = parse_args(args(&["--api", "--threads", "2", "--cookie-jar", "jar.txt", "https://example.com"])).unwrap();
+		assert_eq!(
+			options,
+			Options {
+				url: String::from("https://example.com"),
+				flow: Flow::Api,
+				threads: Some(2),
+				max_attempts: None,
+				cookie_jar: Some(PathBuf::from("jar.txt")),
+			}
+		);
+		assert!(parse_args(args(&[])).is_err());
+		assert!(parse_args(args(&["--threads", "many", "https://example.com"])).is_err());
+		assert!(parse_args(args(&["--bogus", "https://example.com"])).is_err());
+		assert!(parse_args(args(&["https://a.example", "https://b.example"])).is_err());
+	}
+}
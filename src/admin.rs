@@ -0,0 +1,285 @@
+use axum::{
+	body::Bytes,
+	extract::State,
+	http::{header, HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	routing::post,
+	Json, Router,
+};
+use serde::Serialize;
+use std::{
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+	config::AdminConfig,
+	keyring::rotate_and_store,
+	revocation::{revoke_and_store, Revocation},
+	routing::AppState,
+};
+
+#[derive(Debug, Serialize)]
+struct RotateKeyResponse {
+	kid: String,
+	retired: Vec<String>,
+}
+
+/// Routes under `/admin`, all requiring the configured bearer token
+pub fn admin_router() -> Router<AppState> {
+	Router::new()
+		.route("/admin/rotate_key", post(handle_rotate_key))
+		.route("/admin/revoke", post(handle_revoke))
+}
+
+/// Checks the `Authorization: Bearer` header against the configured admin token
+///
+/// # Returns
+/// `Ok(())` if authorised, `404` when no admin token is configured, `401` otherwise
+pub fn authorize(headers: &HeaderMap, admin: &AdminConfig) -> Result<(), StatusCode> {
+	let expected = admin.token.as_deref().ok_or(StatusCode::NOT_FOUND)?;
+	let provided = headers
+		.get(header::AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "))
+		.ok_or(StatusCode::UNAUTHORIZED)?;
+
+	if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+		Ok(())
+	} else {
+		Err(StatusCode::UNAUTHORIZED)
+	}
+}
+
+async fn handle_rotate_key(
+	headers: HeaderMap,
+	State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+	authorize(&headers, &state.config.admin)?;
+
+	let ring = state.keyring.clone();
+	let path = state.config.session.keyring_file().map(Path::to_path_buf);
+	let grace_secs = state.config.session.rotation_grace();
+	let keyring = tokio::task::spawn_blocking(move || rotate_and_store(&ring, path.as_deref(), grace_secs))
+		.await
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+		.map_err(|e| {
+			tracing::error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+	tracing::info!("rotated JWT signing key via admin endpoint, new kid {}", keyring.current().kid);
+
+	let body = RotateKeyResponse {
+		kid: keyring.current().kid.clone(),
+		retired: keyring.retired().iter().map(|r| r.key.kid.clone()).collect(),
+	};
+	Ok(Json(body).into_response())
+}
+
+/// Revokes one token by `jti`, every token issued to an address or network,
+/// or every token issued before a timestamp
+///
+/// The body is only parsed once the caller is authorised, so the endpoint answers
+/// anyone else the same way whatever they send.
+async fn handle_revoke(
+	headers: HeaderMap,
+	State(state): State<AppState>,
+	body: Bytes,
+) -> Result<Response, StatusCode> {
+	authorize(&headers, &state.config.admin)?;
+	let revocation: Revocation = match serde_json::from_slice(&body) {
+		Ok(revocation) => revocation,
+		Err(e) => return Ok((StatusCode::UNPROCESSABLE_ENTITY, format!("invalid revocation: {}", e)).into_response()),
+	};
+
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	if let Err(e) = revocation.validate() {
+		return Ok((StatusCode::BAD_REQUEST, e).into_response());
+	}
+
+	let revocations = state.revocations.clone();
+	let path = state.config.session.revocation_file.clone();
+	let lifetime_secs = state.config.session.session_lifetime();
+	let applied = revocation.clone();
+	tokio::task::spawn_blocking(move || {
+		revoke_and_store(&revocations, path.as_deref(), &applied, now, lifetime_secs)
+	})
+	.await
+	.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+	.map_err(|e| {
+		tracing::error!("{}", e);
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+	tracing::info!("revoked session tokens via admin endpoint: {:?}", revocation);
+
+	Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		config::Config,
+		jwt::{issue_jwt, sign_jwt, Claims},
+		routing::create_router,
+		values::COOKIE_NAME,
+	};
+	use axum::{
+		body::Body,
+		http::{Method, Request},
+	};
+	use tower::ServiceExt;
+
+	const ADMIN_TOKEN: &str = "test-admin-token-0123456789";
+
+	fn admin_state() -> AppState {
+		let mut config = Config::default();
+		config.admin.token = Some(ADMIN_TOKEN.to_owned());
+		AppState::new(config).unwrap()
+	}
+
+	fn rotate_request(token: Option<&str>) -> Request<Body> {
+		let mut builder = Request::builder()
+			.method(Method::POST)
+			.uri("/admin/rotate_key");
+		if let Some(token) = token {
+			builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+		}
+		builder.body(Body::empty()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_rotate_requires_token() {
+		let app = create_router(admin_state());
+
+		let response = app.clone().oneshot(rotate_request(None)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		let response = app.oneshot(rotate_request(Some("wrong"))).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	}
+
+	#[tokio::test]
+	async fn test_admin_disabled_without_token() {
+		let app = create_router(AppState::new(Config::default()).unwrap());
+		let response = app.oneshot(rotate_request(Some(ADMIN_TOKEN))).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn test_rotate_retires_previous_key() {
+		let state = admin_state();
+		let old_kid = state.keyring.read().unwrap().current().kid.clone();
+		let app = create_router(state.clone());
+
+		let response = app.oneshot(rotate_request(Some(ADMIN_TOKEN))).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let keyring = state.keyring.read().unwrap();
+		assert_ne!(keyring.current().kid, old_kid);
+		assert_eq!(keyring.retired().len(), 1);
+		assert_eq!(keyring.retired()[0].key.kid, old_kid);
+	}
+
+	fn revoke_request(body: &str) -> Request<Body> {
+		Request::builder()
+			.method(Method::POST)
+			.uri("/admin/revoke")
+			.header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+			.header(header::CONTENT_TYPE, "application/json")
+			.body(Body::from(body.to_owned()))
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_revoke_token_and_network() {
+		let state = admin_state();
+		let scope = state.config.session.token_scope();
+		let mut claims = Claims::new("user", &scope, 60).unwrap();
+		claims.cip = Some(String::from("203.0.113.7"));
+		let token = sign_jwt(&claims, &state.keyring.read().unwrap()).unwrap();
+		let other = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let app = create_router(state.clone());
+
+		let validate = |token: &str| {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/validate")
+				.header(header::COOKIE, format!("{}={}", COOKIE_NAME, token))
+				.body(Body::empty())
+				.unwrap()
+		};
+		let response = app.clone().oneshot(validate(&token)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let body = format!(r#"{{"jti": "{}"}}"#, claims.jti);
+		let response = app.clone().oneshot(revoke_request(&body)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let response = app.clone().oneshot(validate(&token)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let response = app.clone().oneshot(validate(&other)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response = app.clone().oneshot(revoke_request(r#"{"ip": "not an ip"}"#)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+		let response = app.clone().oneshot(revoke_request(r#"{"ip": "203.0.113.0/24"}"#)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		claims.jti = String::from("another");
+		let token = sign_jwt(&claims, &state.keyring.read().unwrap()).unwrap();
+		let response = app.oneshot(validate(&token)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	}
+
+	#[tokio::test]
+	async fn test_revoke_issued_before_requires_token() {
+		let state = admin_state();
+		let app = create_router(state.clone());
+
+		let request = Request::builder()
+			.method(Method::POST)
+			.uri("/admin/revoke")
+			.header(header::CONTENT_TYPE, "application/json")
+			.body(Body::from(r#"{"issued_before": 1}"#))
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		// Malformed bodies are not looked at before the token is checked
+		let request = Request::builder()
+			.method(Method::POST)
+			.uri("/admin/revoke")
+			.body(Body::from("{"))
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let response = create_router(AppState::new(Config::default()).unwrap())
+			.oneshot(revoke_request("{"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		let response = app.clone().oneshot(revoke_request(r#"{"everything": true}"#)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+		let response = app.oneshot(revoke_request(r#"{"issued_before": 4102444800}"#)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let claims = Claims::new("user", &state.config.session.token_scope(), 60).unwrap();
+		assert!(state.revocations.read().unwrap().is_revoked(&claims));
+	}
+
+	#[test]
+	fn test_constant_time_eq() {
+		assert!(constant_time_eq(b"abc", b"abc"));
+		assert!(!constant_time_eq(b"abc", b"abd"));
+		assert!(!constant_time_eq(b"abc", b"abcd"));
+	}
+}
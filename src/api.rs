@@ -0,0 +1,172 @@
+//! JSON API for clients that cannot run the challenge page
+//!
+//! `GET /api/v1/challenge` hands out a challenge as JSON and
+//! `POST /api/v1/solve` takes `{"token": ..., "nonce": ...}`, answering with the
+//! session token in the body as well as in the cookie. Challenges issued here
+//! share limits, difficulty and reputation with the HTML flow.
+
+use axum::{
+	extract::State,
+	http::{header, HeaderMap, HeaderValue, StatusCode},
+	response::{IntoResponse, Response},
+	routing::{get, post},
+	Json, Router,
+};
+use serde::Serialize;
+
+use crate::{
+	client_ip::ClientIp,
+	pow::{Algorithm, Target},
+	routing::{issue_challenge, solve_challenge, AppState, NonceSubmission, Refusal},
+};
+
+#[derive(Debug, Serialize)]
+struct ChallengeResponse {
+	/// Sent back with the nonce
+	token: String,
+	challenge: String,
+	algorithm: Algorithm,
+	difficulty_bits: u32,
+	/// Highest accepted digest as 64 hex digits
+	target: String,
+	issued_at: u64,
+	expires_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct SolveResponse {
+	/// Session token, also set as the cookie named `cookie_name`
+	token: String,
+	cookie_name: String,
+	expires_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+	error: &'static str,
+}
+
+/// Routes under `/api/v1`
+pub fn api_router() -> Router<AppState> {
+	Router::new()
+		.route("/api/v1/challenge", get(handle_challenge))
+		.route("/api/v1/solve", post(handle_solve))
+}
+
+fn refusal_response(refusal: Refusal) -> Response {
+	refusal.with_body(Json(ErrorResponse {
+		error: refusal.message,
+	}))
+}
+
+async fn handle_challenge(
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+	State(state): State<AppState>,
+) -> Response {
+	let issued = match issue_challenge(&state, ip, &headers, None).await {
+		Ok(issued) => issued,
+		Err(refusal) => return refusal_response(refusal),
+	};
+	Json(ChallengeResponse {
+		token: issued.token,
+		challenge: issued.challenge,
+		algorithm: state.verifier.algorithm(),
+		difficulty_bits: issued.difficulty_bits,
+		target: Target::from_bits(issued.difficulty_bits).to_hex(),
+		issued_at: issued.issued_at,
+		expires_at: issued.issued_at + state.config.pow.challenge_expiry_secs,
+	})
+	.into_response()
+}
+
+async fn handle_solve(
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+	State(state): State<AppState>,
+	Json(submission): Json<NonceSubmission>,
+) -> Result<Response, StatusCode> {
+	let session = match solve_challenge(&state, ip, &headers, &submission).await {
+		Ok(session) => session,
+		Err(refusal) => return Ok(refusal_response(refusal)),
+	};
+	let cookie = HeaderValue::from_str(&session.cookie).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	let body = SolveResponse {
+		token: session.token,
+		cookie_name: state.config.session.cookie_name.clone(),
+		expires_at: session.expires_at,
+	};
+	Ok(([(header::SET_COOKIE, cookie)], Json(body)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+	use crate::jwt::validate_jwt;
+	use crate::pow::meets_difficulty;
+	use crate::routing::create_router;
+	use axum::{body::Body, http::{Method, Request}};
+	use serde_json::Value;
+	use tower::ServiceExt;
+
+	async fn json_body(response: Response) -> Value {
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		serde_json::from_slice(&body).unwrap()
+	}
+
+	fn solve_request(token: &str, nonce: &str) -> Request<Body> {
+		Request::builder()
+			.method(Method::POST)
+			.uri("/api/v1/solve")
+			.header(header::CONTENT_TYPE, "application/json")
+			.body(Body::from(serde_json::json!({ "token": token, "nonce": nonce }).to_string()))
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_challenge_and_solve() {
+		let mut config = Config::default();
+		config.pow.difficulty_bits = 4;
+		let state = AppState::new(config).unwrap();
+		let app = create_router(state.clone());
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/api/v1/challenge")
+			.body(Body::empty())
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let challenge = json_body(response).await;
+		assert_eq!(challenge["algorithm"]["name"], "sha256");
+		assert_eq!(challenge["difficulty_bits"], 4);
+		assert_eq!(challenge["target"].as_str().unwrap().len(), 64);
+		assert_eq!(
+			challenge["expires_at"].as_u64().unwrap() - challenge["issued_at"].as_u64().unwrap(),
+			state.config.pow.challenge_expiry_secs
+		);
+
+		let token = challenge["token"].as_str().unwrap();
+		let text = challenge["challenge"].as_str().unwrap();
+		let nonce = (0u64..)
+			.find(|n| meets_difficulty(text, &n.to_string(), 4))
+			.unwrap()
+			.to_string();
+		let response = app.clone().oneshot(solve_request(token, &nonce)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap().to_owned();
+		let session = json_body(response).await;
+		let jwt = session["token"].as_str().unwrap();
+		assert!(cookie.starts_with(&format!("mpow_token={};", jwt)));
+		assert_eq!(session["cookie_name"], "mpow_token");
+		let claims = validate_jwt(jwt, &state.keyring.read().unwrap(), &state.config.session.token_scope()).unwrap();
+		assert_eq!(session["expires_at"].as_u64().unwrap(), claims.exp as u64);
+
+		let response = app.oneshot(solve_request(token, &nonce)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		assert_eq!(json_body(response).await["error"], "No active challenge");
+	}
+}
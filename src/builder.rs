@@ -0,0 +1,276 @@
+//! Builder for an mpow gate
+//!
+//! [`MpowBuilder`] starts from the defaults (or a loaded [`Config`]), takes the
+//! settings most deployments change, and validates the result once in
+//! [`MpowBuilder::build`]. The [`Mpow`] it returns can be served on its own,
+//! mounted as a router, or layered in front of another service.
+
+use axum::Router;
+use ipnet::IpNet;
+use std::{net::SocketAddr, path::PathBuf};
+
+use crate::{
+	config::Config,
+	gate::PowGateLayer,
+	jwt::{sign_jwt, validate_jwt, Claims},
+	pow::PowAlgorithm,
+	routing::{create_router, serve, spawn_background_tasks, AppState},
+	stateless::ChallengeMode,
+	store::StoreBackend,
+};
+
+/// Where outstanding challenges are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Store {
+	/// One in-memory map behind a single lock
+	Memory,
+	/// In-memory maps split across this many locks
+	Sharded(usize),
+	/// Embedded database file that survives restarts
+	File(PathBuf),
+	/// Redis-protocol server shared by every replica, e.g. `redis://redis:6379/0`
+	Redis(String),
+}
+
+/// Builder for [`Mpow`]
+#[derive(Debug, Clone, Default)]
+pub struct MpowBuilder {
+	config: Config,
+}
+
+impl MpowBuilder {
+	/// Builder starting from the built-in defaults
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Builder starting from `config`, e.g. one from [`Config::load`]
+	pub fn from_config(config: Config) -> Self {
+		Self { config }
+	}
+
+	/// Address [`Mpow::serve`] listens on
+	pub fn bind(mut self, addr: SocketAddr) -> Self {
+		self.config.server.bind = addr;
+		self
+	}
+
+	/// Leading zero bits a solution needs
+	pub fn difficulty(mut self, bits: u32) -> Self {
+		self.config.pow.difficulty_bits = bits;
+		self.config.pow.difficulty = None;
+		self
+	}
+
+	/// Hash function solutions are judged on
+	pub fn algorithm(mut self, algorithm: PowAlgorithm) -> Self {
+		self.config.pow.algorithm = algorithm;
+		self
+	}
+
+	/// Stateful challenges, or stateless ones signed with `challenge_secret`
+	pub fn mode(mut self, mode: ChallengeMode) -> Self {
+		self.config.pow.mode = mode;
+		self
+	}
+
+	/// Hex-encoded key signing stateless challenges, shared by all replicas
+	pub fn challenge_secret(mut self, secret: impl Into<String>) -> Self {
+		self.config.pow.challenge_secret = Some(secret.into());
+		self
+	}
+
+	/// Backend for outstanding challenges
+	pub fn store(mut self, store: Store) -> Self {
+		let config = &mut self.config.store;
+		config.backend = match store {
+			Store::Memory => StoreBackend::Memory,
+			Store::Sharded(shards) => {
+				config.shards = shards;
+				StoreBackend::Sharded
+			}
+			Store::File(path) => {
+				config.path = Some(path);
+				StoreBackend::File
+			}
+			Store::Redis(url) => {
+				config.redis_url = Some(url);
+				StoreBackend::Redis
+			}
+		};
+		self
+	}
+
+	/// Name of the session cookie
+	pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+		self.config.session.cookie_name = name.into();
+		self
+	}
+
+	/// Lifetime of session tokens
+	pub fn token_expiry_secs(mut self, secs: u64) -> Self {
+		self.config.session.token_expiry_secs = secs;
+		self
+	}
+
+	/// Hex-encoded token signing key; replicas must share it
+	pub fn jwt_secret(mut self, secret: impl Into<String>) -> Self {
+		self.config.session.jwt_secret = Some(secret.into());
+		self
+	}
+
+	/// File holding the token signing keys, created on first run
+	pub fn jwt_secret_file(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.session.jwt_secret_file = Some(path.into());
+		self
+	}
+
+	/// Reverse proxies whose forwarding headers are believed
+	pub fn trusted_proxies(mut self, networks: Vec<IpNet>) -> Self {
+		self.config.server.trusted_proxies = networks;
+		self
+	}
+
+	/// Bearer token enabling `/admin/*`
+	pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+		self.config.admin.token = Some(token.into());
+		self
+	}
+
+	/// Forwards authorised requests to this `http://` URL when served with
+	/// [`Mpow::serve`], instead of answering nginx `auth_request`
+	pub fn upstream(mut self, url: impl Into<String>) -> Self {
+		self.config.proxy.upstream = Some(url.into());
+		self
+	}
+
+	/// Changes any other setting of the underlying [`Config`]
+	pub fn configure(mut self, change: impl FnOnce(&mut Config)) -> Self {
+		change(&mut self.config);
+		self
+	}
+
+	/// Validates the settings and sets up the gate
+	///
+	/// # Returns
+	/// `Err(String)` describing the first invalid setting, or why the store or keys
+	/// could not be opened
+	pub fn build(self) -> Result<Mpow, String> {
+		self.config.validate()?;
+		Ok(Mpow {
+			state: AppState::new(self.config)?,
+		})
+	}
+}
+
+/// A configured gate
+#[derive(Clone)]
+pub struct Mpow {
+	state: AppState,
+}
+
+impl Mpow {
+	/// Validated configuration the gate runs with
+	pub fn config(&self) -> &Config {
+		&self.state.config
+	}
+
+	/// Shared state behind every endpoint
+	pub fn state(&self) -> &AppState {
+		&self.state
+	}
+
+	/// Router serving the gate's endpoints, for mounting next to other routes
+	///
+	/// Background tasks are not started; see [`Mpow::spawn_background_tasks`].
+	pub fn router(&self) -> Router {
+		create_router(self.state.clone())
+	}
+
+	/// Layer gating another service, sharing this gate's state
+	pub fn layer(&self) -> PowGateLayer {
+		PowGateLayer::from_state(self.state.clone())
+	}
+
+	/// Starts the janitor, key rotation and other periodic tasks when the gate is
+	/// used through [`Mpow::router`] or [`Mpow::layer`]; must be called within a
+	/// Tokio runtime
+	pub fn spawn_background_tasks(&self) {
+		spawn_background_tasks(&self.state);
+	}
+
+	/// Starts the background tasks and serves the gate on the configured address
+	/// until the server fails
+	pub async fn serve(self) -> Result<(), String> {
+		spawn_background_tasks(&self.state);
+		serve(self.state).await
+	}
+
+	/// Signs a session token for `subject`, as if it had solved a challenge
+	pub fn issue_token(&self, subject: &str) -> Result<String, String> {
+		let session = &self.state.config.session;
+		let claims = Claims::new(subject, &session.token_scope(), session.token_expiry_secs)?;
+		let keyring = self
+			.state
+			.keyring
+			.read()
+			.map_err(|_| String::from("keyring lock poisoned"))?;
+		sign_jwt(&claims, &keyring)
+	}
+
+	/// Checks a session token's signature, expiry, issuer and audience
+	///
+	/// Revocation, binding and quotas are only applied by `/validate`.
+	pub fn validate_token(&self, token: &str) -> Result<Claims, String> {
+		let keyring = self
+			.state
+			.keyring
+			.read()
+			.map_err(|_| String::from("keyring lock poisoned"))?;
+		validate_jwt(token, &keyring, &self.state.config.session.token_scope())
+			.map_err(|e| format!("invalid token: {}", e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jwt::generate_secret;
+
+	#[test]
+	fn test_builder_sets_config() {
+		let secret = hex::encode(generate_secret());
+		let mpow = MpowBuilder::new()
+			.difficulty(20)
+			.store(Store::Sharded(8))
+			.cookie_name("gate")
+			.jwt_secret(secret.clone())
+			.configure(|config| config.pow.max_attempts = 3)
+			.build()
+			.unwrap();
+		let config = mpow.config();
+		assert_eq!(config.pow.difficulty_bits(), 20);
+		assert_eq!((config.store.backend, config.store.shards), (StoreBackend::Sharded, 8));
+		assert_eq!(config.session.cookie_name, "gate");
+		assert_eq!(config.session.jwt_secret.as_deref(), Some(secret.as_str()));
+		assert_eq!(config.pow.max_attempts, 3);
+	}
+
+	#[test]
+	fn test_build_validates() {
+		assert!(MpowBuilder::new().store(Store::Sharded(0)).build().is_err());
+		assert!(MpowBuilder::new().cookie_name("").build().is_err());
+		assert!(MpowBuilder::new().difficulty(300).build().is_err());
+		assert!(MpowBuilder::new().upstream("ftp://app").build().is_err());
+	}
+
+	#[test]
+	fn test_tokens_round_trip() {
+		let mpow = MpowBuilder::new().build().unwrap();
+		let token = mpow.issue_token("crawler").unwrap();
+		assert_eq!(mpow.validate_token(&token).unwrap().sub, "crawler");
+
+		let other = MpowBuilder::new().build().unwrap();
+		assert!(other.validate_token(&token).is_err());
+	}
+}
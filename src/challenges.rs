@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{HashMap, VecDeque},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::store::SharedStore;
+use crate::values::POW_DIFFICULTY_BITS;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Challenge {
+	pub token: String,
+	pub challenge: String,
+	pub created_at: u64,
+	pub attempts: u32,
+	/// Difficulty the challenge was issued at; solutions are checked against it
+	#[serde(default = "default_difficulty_bits")]
+	pub difficulty_bits: u32,
+	/// Local path to send the visitor to once solved
+	#[serde(default)]
+	pub return_to: Option<String>,
+}
+
+fn default_difficulty_bits() -> u32 {
+	POW_DIFFICULTY_BITS
+}
+
+/// What to do with a new challenge when `max_outstanding_challenges` is reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+	/// Drop the oldest outstanding challenge to make room
+	#[default]
+	Oldest,
+	/// Refuse to issue new challenges until room frees up
+	Reject,
+}
+
+impl std::str::FromStr for EvictionPolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"oldest" => Ok(EvictionPolicy::Oldest),
+			"reject" => Ok(EvictionPolicy::Reject),
+			_ => Err(String::from("expected one of oldest, reject")),
+		}
+	}
+}
+
+/// Outstanding challenges keyed by token, remembering issue order so the
+/// oldest entries can be expired or evicted without scanning the whole map
+#[derive(Debug, Default)]
+pub struct ChallengeMap {
+	entries: HashMap<String, Challenge>,
+	/// Tokens in issue order; may still hold tokens already removed from `entries`
+	order: VecDeque<String>,
+}
+
+impl ChallengeMap {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	pub fn contains_key(&self, token: &str) -> bool {
+		self.entries.contains_key(token)
+	}
+
+	pub fn get(&self, token: &str) -> Option<&Challenge> {
+		self.entries.get(token)
+	}
+
+	pub fn get_mut(&mut self, token: &str) -> Option<&mut Challenge> {
+		self.entries.get_mut(token)
+	}
+
+	pub fn remove(&mut self, token: &str) -> Option<Challenge> {
+		let removed = self.entries.remove(token);
+		if self.order.len() > 2 * self.entries.len() + 1024 {
+			let entries = &self.entries;
+			self.order.retain(|t| entries.contains_key(t));
+		}
+		removed
+	}
+
+	/// Inserts a challenge without checking capacity
+	pub fn insert(&mut self, challenge: Challenge) {
+		self.order.push_back(challenge.token.clone());
+		self.entries.insert(challenge.token.clone(), challenge);
+	}
+
+	/// Inserts a challenge while keeping at most `capacity` outstanding
+	///
+	/// Expired entries are dropped first. If the map is still full, `policy`
+	/// decides whether the oldest challenge is evicted or the new one refused.
+	///
+	/// # Returns
+	/// `true` if the challenge was stored
+	pub fn insert_bounded(
+		&mut self,
+		challenge: Challenge,
+		capacity: usize,
+		policy: EvictionPolicy,
+		expiry_secs: u64,
+	) -> bool {
+		if self.entries.len() >= capacity {
+			self.sweep(challenge.created_at, expiry_secs);
+		}
+		while self.entries.len() >= capacity {
+			if policy == EvictionPolicy::Reject || !self.evict_oldest() {
+				return false;
+			}
+		}
+		self.insert(challenge);
+		true
+	}
+
+	/// Removes every challenge older than `expiry_secs`
+	///
+	/// # Returns
+	/// The number of challenges removed
+	pub fn sweep(&mut self, now: u64, expiry_secs: u64) -> usize {
+		let before = self.entries.len();
+		while let Some(token) = self.order.front() {
+			match self.entries.get(token) {
+				Some(c) if now.saturating_sub(c.created_at) <= expiry_secs => break,
+				Some(_) => {
+					self.entries.remove(token);
+				}
+				None => {}
+			}
+			self.order.pop_front();
+		}
+		before - self.entries.len()
+	}
+
+	fn evict_oldest(&mut self) -> bool {
+		while let Some(token) = self.order.pop_front() {
+			if self.entries.remove(&token).is_some() {
+				return true;
+			}
+		}
+		false
+	}
+}
+
+/// Periodically purges expired challenges
+pub fn spawn_janitor(store: SharedStore, interval_secs: u64) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(Duration::from_secs(interval_secs));
+		loop {
+			ticker.tick().await;
+			let now = SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.unwrap_or_default()
+				.as_secs();
+			let removed = match store.sweep(now).await {
+				Ok(removed) => removed,
+				Err(e) => {
+					tracing::warn!("janitor sweep failed: {}", e);
+					continue;
+				}
+			};
+			if removed > 0 {
+				let outstanding = store.len().await.unwrap_or_default();
+				tracing::debug!(
+					"janitor removed {} expired challenges, {} outstanding",
+					removed,
+					outstanding
+				);
+			}
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::{MemoryStore, StoreLimits};
+	use std::sync::Arc;
+
+	fn challenge(token: &str, created_at: u64) -> Challenge {
+		Challenge {
+			token: token.to_owned(),
+			challenge: format!("challenge-{}", token),
+			created_at,
+			attempts: 0,
+			difficulty_bits: 1,
+			return_to: None,
+		}
+	}
+
+	#[test]
+	fn test_sweep_removes_only_expired() {
+		let mut map = ChallengeMap::new();
+		map.insert(challenge("old", 100));
+		map.insert(challenge("edge", 150));
+		map.insert(challenge("new", 190));
+
+		assert_eq!(map.sweep(200, 50), 1);
+		assert!(!map.contains_key("old"));
+		assert!(map.contains_key("edge"));
+		assert!(map.contains_key("new"));
+	}
+
+	#[test]
+	fn test_sweep_skips_already_removed() {
+		let mut map = ChallengeMap::new();
+		map.insert(challenge("solved", 100));
+		map.insert(challenge("pending", 100));
+		map.remove("solved");
+
+		assert_eq!(map.sweep(1000, 50), 1);
+		assert!(map.is_empty());
+	}
+
+	#[test]
+	fn test_bounded_insert_evicts_oldest() {
+		let mut map = ChallengeMap::new();
+		for i in 0..3 {
+			assert!(map.insert_bounded(challenge(&i.to_string(), 100 + i), 3, EvictionPolicy::Oldest, 300));
+		}
+
+		assert!(map.insert_bounded(challenge("3", 103), 3, EvictionPolicy::Oldest, 300));
+		assert_eq!(map.len(), 3);
+		assert!(!map.contains_key("0"));
+		assert!(map.contains_key("3"));
+	}
+
+	#[test]
+	fn test_bounded_insert_rejects_when_full() {
+		let mut map = ChallengeMap::new();
+		map.insert(challenge("a", 100));
+		map.insert(challenge("b", 100));
+
+		assert!(!map.insert_bounded(challenge("c", 101), 2, EvictionPolicy::Reject, 300));
+		assert!(!map.contains_key("c"));
+
+		// Expired entries make room even under the reject policy
+		assert!(map.insert_bounded(challenge("d", 500), 2, EvictionPolicy::Reject, 300));
+		assert!(map.contains_key("d"));
+	}
+
+	#[test]
+	fn test_order_queue_is_compacted() {
+		let mut map = ChallengeMap::new();
+		for i in 0..5000 {
+			map.insert(challenge(&i.to_string(), 100));
+			map.remove(&i.to_string());
+		}
+		assert!(map.order.len() <= 1024 + 1);
+	}
+
+	#[tokio::test]
+	async fn test_janitor_purges_expired() {
+		let limits = StoreLimits {
+			capacity: 10,
+			policy: EvictionPolicy::Oldest,
+			expiry_secs: 300,
+		};
+		let store: SharedStore = Arc::new(MemoryStore::new(limits, 1));
+		store.insert(challenge("stale", 0)).await.unwrap();
+
+		// The first tick fires immediately
+		let handle = spawn_janitor(store.clone(), 60);
+		tokio::time::sleep(Duration::from_millis(50)).await;
+
+		assert!(store.is_empty().await.unwrap());
+		handle.abort();
+	}
+}
@@ -0,0 +1,211 @@
+//! Client address behind reverse proxies
+//!
+//! `Forwarded`, `X-Forwarded-For` and `X-Real-IP` are only believed when the
+//! connection comes from a trusted proxy. The forwarding chain is then walked
+//! from the nearest hop outwards, skipping further trusted proxies, and the
+//! first address that is not one of them is the client. Anyone connecting
+//! directly gets their socket address, whatever headers they send.
+
+use axum::{
+	extract::{ConnectInfo, FromRef, FromRequestParts},
+	http::{request::Parts, Extensions, HeaderMap},
+};
+use ipnet::IpNet;
+use std::{
+	convert::Infallible,
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+};
+
+/// Networks whose forwarding headers are believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+	pub fn new(networks: Vec<IpNet>) -> Self {
+		Self(networks)
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		let ip = ip.to_canonical();
+		self.0.iter().any(|net| net.contains(&ip))
+	}
+
+	/// Address of the client on whose behalf `peer` made the request
+	///
+	/// # Arguments
+	/// * `peer` - socket address of the connection
+	/// * `headers` - request headers, consulted only if `peer` is trusted
+	pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+		let mut client = peer.to_canonical();
+		if !self.contains(client) {
+			return client;
+		}
+		for hop in forwarding_chain(headers).into_iter().rev() {
+			// An obfuscated or malformed hop ends what can be known
+			let Some(ip) = hop else {
+				return client;
+			};
+			client = ip.to_canonical();
+			if !self.contains(client) {
+				return client;
+			}
+		}
+		client
+	}
+}
+
+/// Addresses the request passed through, client first
+///
+/// Taken from `Forwarded` if present, else `X-Forwarded-For`, else `X-Real-IP`.
+fn forwarding_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+	let values = |name: &str| -> Vec<&str> {
+		headers
+			.get_all(name)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.collect()
+	};
+
+	let forwarded = values("forwarded");
+	if !forwarded.is_empty() {
+		return forwarded
+			.iter()
+			.flat_map(|v| v.split(','))
+			.map(|element| {
+				element
+					.split(';')
+					.filter_map(|pair| pair.split_once('='))
+					.find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+					.and_then(|(_, value)| parse_hop(value.trim().trim_matches('"')))
+			})
+			.collect();
+	}
+
+	let forwarded_for = values("x-forwarded-for");
+	if !forwarded_for.is_empty() {
+		return forwarded_for
+			.iter()
+			.flat_map(|v| v.split(','))
+			.map(|hop| parse_hop(hop.trim()))
+			.collect();
+	}
+
+	values("x-real-ip")
+		.first()
+		.map(|v| vec![parse_hop(v.trim())])
+		.unwrap_or_default()
+}
+
+/// Parses `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+	if let Ok(ip) = hop.parse() {
+		return Some(ip);
+	}
+	if let Some(rest) = hop.strip_prefix('[') {
+		return rest.split(']').next()?.parse().ok();
+	}
+	hop.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// Client address of the request
+///
+/// `None` when the connection's socket address is not known, as for requests
+/// not served through `into_make_service_with_connect_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+	S: Send + Sync,
+	Arc<TrustedProxies>: FromRef<S>,
+{
+	type Rejection = Infallible;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let proxies = Arc::<TrustedProxies>::from_ref(state);
+		Ok(ClientIp(client_ip(&proxies, &parts.extensions, &parts.headers)))
+	}
+}
+
+/// Client address of a request, from its `ConnectInfo` and forwarding headers
+pub fn client_ip(proxies: &TrustedProxies, extensions: &Extensions, headers: &HeaderMap) -> Option<IpAddr> {
+	extensions
+		.get::<ConnectInfo<SocketAddr>>()
+		.map(|ConnectInfo(addr)| proxies.resolve(addr.ip(), headers))
+}
+
+/// Parses a comma-separated list of CIDR networks
+pub fn parse_networks(list: &str) -> Result<Vec<IpNet>, String> {
+	list.split(',')
+		.map(str::trim)
+		.filter(|net| !net.is_empty())
+		.map(|net| net.parse().map_err(|_| format!("invalid network {:?}, expected CIDR notation", net)))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn proxies() -> TrustedProxies {
+		TrustedProxies::new(parse_networks("127.0.0.0/8, 10.0.0.0/8, fd00::/8").unwrap())
+	}
+
+	fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.append(*name, value.parse().unwrap());
+		}
+		headers
+	}
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_untrusted_peer_headers_ignored() {
+		let spoofed = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "1.2.3.4")]);
+		assert_eq!(proxies().resolve(ip("203.0.113.5"), &spoofed), ip("203.0.113.5"));
+	}
+
+	#[test]
+	fn test_forwarded_for_chain() {
+		let chain = headers(&[("x-forwarded-for", "1.2.3.4, 198.51.100.7, 10.0.0.2")]);
+		// The leftmost entry was written by the client and is not trusted
+		assert_eq!(proxies().resolve(ip("127.0.0.1"), &chain), ip("198.51.100.7"));
+
+		let split = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-forwarded-for", "10.0.0.2")]);
+		assert_eq!(proxies().resolve(ip("10.0.0.3"), &split), ip("198.51.100.7"));
+
+		let all_trusted = headers(&[("x-forwarded-for", "10.0.0.9, 10.0.0.2")]);
+		assert_eq!(proxies().resolve(ip("127.0.0.1"), &all_trusted), ip("10.0.0.9"));
+	}
+
+	#[test]
+	fn test_forwarded_header_preferred() {
+		let both = headers(&[
+			("forwarded", "for=192.0.2.60;proto=http, for=\"[2001:db8::1]:4711\""),
+			("x-forwarded-for", "1.2.3.4"),
+		]);
+		assert_eq!(proxies().resolve(ip("127.0.0.1"), &both), ip("2001:db8::1"));
+
+		let hidden = headers(&[("forwarded", "for=_hidden, for=10.0.0.7")]);
+		assert_eq!(proxies().resolve(ip("127.0.0.1"), &hidden), ip("10.0.0.7"));
+	}
+
+	#[test]
+	fn test_real_ip_and_mapped_peer() {
+		let real_ip = headers(&[("x-real-ip", "198.51.100.7")]);
+		assert_eq!(proxies().resolve(ip("::ffff:127.0.0.1"), &real_ip), ip("198.51.100.7"));
+		assert_eq!(proxies().resolve(ip("::ffff:127.0.0.1"), &HeaderMap::new()), ip("127.0.0.1"));
+	}
+
+	#[test]
+	fn test_parse_networks() {
+		assert_eq!(parse_networks("10.0.0.0/8,::1/128").unwrap().len(), 2);
+		assert!(parse_networks("10.0.0.1").is_err());
+		assert!(parse_networks("").unwrap().is_empty());
+	}
+}
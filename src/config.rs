@@ -0,0 +1,1082 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{
+	fs,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+};
+
+use crate::challenges::EvictionPolicy;
+use crate::client_ip::parse_networks;
+use crate::jwt::{decode_secret, BindingPolicy, RefreshPolicy, TokenScope};
+use crate::keyring::KeyAlgorithm;
+use crate::pow::{scrypt_params_valid, Algorithm, PowAlgorithm};
+use crate::stateless::ChallengeMode;
+use crate::store::StoreBackend;
+use crate::values::{
+	ADAPTIVE_FAILURE_RATIO_HIGH, ADAPTIVE_HEADROOM_BITS, ADAPTIVE_ISSUE_RATE_HIGH, ADAPTIVE_OUTSTANDING_HIGH,
+	ADAPTIVE_WINDOW_SECS, BINDING_IPV4_PREFIX, BINDING_IPV6_PREFIX, BIND_ADDR, JWT_AUDIENCE, JWT_ISSUER, CHALLENGE_EXPIRY_SECS, CONFIG_PATH, COOKIE_NAME, JANITOR_INTERVAL_SECS, MAX_ATTEMPTS,
+	MAX_NONCE_LENGTH, MAX_OUTSTANDING_CHALLENGES, MAX_SESSION_SECS, POW_DIFFICULTY_BITS, QUOTA_MAX_TRACKED_TOKENS,
+	QUOTA_RATE_WINDOW_SECS, REDIS_PREFIX, REPUTATION_HALF_LIFE_SECS,
+	REPUTATION_MAX_CLIENTS, REPUTATION_MAX_EXTRA_BITS, REPUTATION_POINTS_PER_BIT, REPUTATION_SUBNET_POINTS_PER_BIT,
+	SCRYPT_LOG_N,
+	SCRYPT_MAX_DIFFICULTY_BITS, SCRYPT_P, SCRYPT_R, STORE_SHARDS, TOKEN_EXPIRY_SECS, TRUSTED_PROXIES,
+};
+
+/// Runtime configuration, loaded from a TOML file and `MPOW_*` environment variables
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub server: ServerConfig,
+	pub pow: PowConfig,
+	pub session: SessionConfig,
+	pub store: StoreConfig,
+	pub admin: AdminConfig,
+	pub proxy: ProxyConfig,
+}
+
+/// Listener settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+	pub bind: SocketAddr,
+	/// Networks of reverse proxies allowed to set `Forwarded`, `X-Forwarded-For`
+	/// and `X-Real-IP`; other peers are identified by their socket address
+	pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Proof-of-work challenge settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowConfig {
+	/// Number of leading zero bits the SHA-256 digest must have
+	pub difficulty_bits: u32,
+	/// Deprecated: leading zero hex digits; overrides `difficulty_bits` (4 bits each) when set
+	pub difficulty: Option<usize>,
+	/// `sha256`, or the memory-hard `scrypt`
+	pub algorithm: PowAlgorithm,
+	/// scrypt cost: `2^scrypt_log_n` blocks of `128 * scrypt_r` bytes
+	pub scrypt_log_n: u8,
+	pub scrypt_r: u32,
+	pub scrypt_p: u32,
+	/// Memory-hard verifications run at once; defaults to the number of CPUs
+	pub max_concurrent_verifications: Option<usize>,
+	/// Load-driven difficulty between a floor and a ceiling
+	pub adaptive: AdaptiveConfig,
+	/// Extra difficulty for clients and subnets with a bad recent history
+	pub reputation: ReputationConfig,
+	pub challenge_expiry_secs: u64,
+	pub max_attempts: u32,
+	pub max_nonce_length: usize,
+	/// Upper bound on challenges waiting for a solution
+	pub max_outstanding_challenges: usize,
+	/// What happens to new challenges once the bound is reached
+	pub eviction_policy: EvictionPolicy,
+	/// How often expired challenges are purged
+	pub janitor_interval_secs: u64,
+	/// `stateful` keeps challenges in memory; `stateless` signs them instead
+	pub mode: ChallengeMode,
+	/// Hex-encoded HMAC key for stateless challenges; random per process when unset,
+	/// so replicas behind a load balancer must share one
+	pub challenge_secret: Option<String>,
+	/// Bind stateless challenges to the client's address and `User-Agent`
+	pub bind_client: bool,
+}
+
+/// Adaptive difficulty settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveConfig {
+	pub enabled: bool,
+	/// Lowest difficulty; defaults to `difficulty_bits`
+	pub min_bits: Option<u32>,
+	/// Highest difficulty; defaults to four bits above `min_bits`
+	pub max_bits: Option<u32>,
+	/// How often the difficulty is reconsidered
+	pub window_secs: u64,
+	/// Challenges issued per second above which difficulty rises
+	pub issue_rate_high: f64,
+	/// Share of rejected submissions above which difficulty rises
+	pub failure_ratio_high: f64,
+	/// Outstanding challenges above which difficulty rises
+	pub outstanding_high: usize,
+}
+
+/// Per-client reputation settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReputationConfig {
+	pub enabled: bool,
+	/// Time for a client's score to halve
+	pub half_life_secs: u64,
+	/// Score of one address that adds one bit of difficulty
+	pub points_per_bit: f64,
+	/// Score of a whole /24 or /64 that adds one bit of difficulty
+	pub subnet_points_per_bit: f64,
+	/// Most bits a bad reputation can add
+	pub max_extra_bits: u32,
+	/// Addresses and subnets remembered at once
+	pub max_clients: usize,
+}
+
+/// Session cookie settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+	pub cookie_name: String,
+	pub token_expiry_secs: u64,
+	/// Age at which `/validate` reissues a valid token; 0 disables sliding sessions
+	pub refresh_after_secs: u64,
+	/// Time since the challenge was solved after which refreshing stops; 0 means never
+	pub max_session_secs: u64,
+	/// Signature algorithm for new tokens: `HS256`, `EdDSA` or `ES256`
+	pub jwt_algorithm: KeyAlgorithm,
+	/// Hex-encoded HMAC key (or PKCS#8 private key for `EdDSA`/`ES256`);
+	/// takes precedence over `jwt_secret_file`
+	pub jwt_secret: Option<String>,
+	/// File holding the keyring, created on first run if absent
+	pub jwt_secret_file: Option<PathBuf>,
+	/// Rotate the signing key once it is this old; 0 disables scheduled rotation
+	pub rotation_interval_secs: u64,
+	/// How long a rotated-out key keeps validating tokens; defaults to `token_expiry_secs`
+	pub rotation_grace_secs: Option<u64>,
+	/// Previous keys that are still accepted until their deadline
+	pub retired_keys: Vec<RetiredKeyConfig>,
+	/// Bind tokens to the solver's address prefix and `User-Agent`:
+	/// `off`, `log`, `user_agent` or `strict`
+	pub binding: BindingPolicy,
+	/// Prefix length an IPv4 address is bound at
+	pub binding_ipv4_prefix: u8,
+	/// Prefix length an IPv6 address is bound at
+	pub binding_ipv6_prefix: u8,
+	/// `iss` claim of issued tokens, required on validation
+	pub issuer: String,
+	/// `aud` claim of issued tokens, required on validation
+	pub audience: String,
+	/// File the revocation list is persisted to and shared through; in memory only when unset
+	pub revocation_file: Option<PathBuf>,
+	/// Requests a token may be used for
+	pub quota: QuotaConfig,
+}
+
+/// Per-token request quota settings; 0 means unlimited
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+	/// Requests one token may make over its lifetime
+	pub max_requests: u64,
+	/// Requests one token may make per `rate_window_secs`
+	pub rate_limit: u64,
+	pub rate_window_secs: u64,
+	/// Tokens counted at once
+	pub max_tracked_tokens: usize,
+}
+
+/// A previous key accepted for validation until `retire_at` (unix seconds)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetiredKeyConfig {
+	#[serde(default)]
+	pub algorithm: KeyAlgorithm,
+	pub secret: String,
+	pub retire_at: u64,
+}
+
+/// Challenge storage settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+	/// `memory`, `sharded`, `file` or `redis`
+	pub backend: StoreBackend,
+	/// Number of independently locked shards for the `sharded` backend
+	pub shards: usize,
+	/// Database file for the `file` backend
+	pub path: Option<PathBuf>,
+	/// Server for the `redis` backend, e.g. `redis://redis:6379/0`
+	pub redis_url: Option<String>,
+	/// Prepended to every key written by the `redis` backend
+	pub redis_prefix: String,
+}
+
+/// Administrative endpoint settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+	/// Bearer token for `/admin/*`; the endpoints are disabled when unset
+	pub token: Option<String>,
+}
+
+/// Built-in reverse proxy settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+	/// `http://` URL authorised requests are forwarded to; when set, mpow fronts
+	/// the upstream itself instead of answering nginx `auth_request`
+	pub upstream: Option<String>,
+	/// Forward the visitor's `Host` header rather than the upstream's
+	pub preserve_host: bool,
+}
+
+impl Default for ServerConfig {
+	fn default() -> Self {
+		Self {
+			bind: BIND_ADDR.parse().expect("default bind address is valid"),
+			trusted_proxies: parse_networks(TRUSTED_PROXIES).expect("default proxies are valid"),
+		}
+	}
+}
+
+impl Default for PowConfig {
+	fn default() -> Self {
+		Self {
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			difficulty: None,
+			algorithm: PowAlgorithm::default(),
+			scrypt_log_n: SCRYPT_LOG_N,
+			scrypt_r: SCRYPT_R,
+			scrypt_p: SCRYPT_P,
+			max_concurrent_verifications: None,
+			adaptive: AdaptiveConfig::default(),
+			reputation: ReputationConfig::default(),
+			challenge_expiry_secs: CHALLENGE_EXPIRY_SECS,
+			max_attempts: MAX_ATTEMPTS,
+			max_nonce_length: MAX_NONCE_LENGTH,
+			max_outstanding_challenges: MAX_OUTSTANDING_CHALLENGES,
+			eviction_policy: EvictionPolicy::default(),
+			janitor_interval_secs: JANITOR_INTERVAL_SECS,
+			mode: ChallengeMode::default(),
+			challenge_secret: None,
+			bind_client: true,
+		}
+	}
+}
+
+impl Default for AdaptiveConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			min_bits: None,
+			max_bits: None,
+			window_secs: ADAPTIVE_WINDOW_SECS,
+			issue_rate_high: ADAPTIVE_ISSUE_RATE_HIGH,
+			failure_ratio_high: ADAPTIVE_FAILURE_RATIO_HIGH,
+			outstanding_high: ADAPTIVE_OUTSTANDING_HIGH,
+		}
+	}
+}
+
+impl Default for ReputationConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			half_life_secs: REPUTATION_HALF_LIFE_SECS,
+			points_per_bit: REPUTATION_POINTS_PER_BIT,
+			subnet_points_per_bit: REPUTATION_SUBNET_POINTS_PER_BIT,
+			max_extra_bits: REPUTATION_MAX_EXTRA_BITS,
+			max_clients: REPUTATION_MAX_CLIENTS,
+		}
+	}
+}
+
+impl Default for SessionConfig {
+	fn default() -> Self {
+		Self {
+			cookie_name: COOKIE_NAME.to_owned(),
+			token_expiry_secs: TOKEN_EXPIRY_SECS,
+			refresh_after_secs: 0,
+			max_session_secs: MAX_SESSION_SECS,
+			jwt_algorithm: KeyAlgorithm::default(),
+			jwt_secret: None,
+			jwt_secret_file: None,
+			rotation_interval_secs: 0,
+			rotation_grace_secs: None,
+			retired_keys: Vec::new(),
+			binding: BindingPolicy::default(),
+			binding_ipv4_prefix: BINDING_IPV4_PREFIX,
+			binding_ipv6_prefix: BINDING_IPV6_PREFIX,
+			issuer: JWT_ISSUER.to_owned(),
+			audience: JWT_AUDIENCE.to_owned(),
+			revocation_file: None,
+			quota: QuotaConfig::default(),
+		}
+	}
+}
+
+impl Default for QuotaConfig {
+	fn default() -> Self {
+		Self {
+			max_requests: 0,
+			rate_limit: 0,
+			rate_window_secs: QUOTA_RATE_WINDOW_SECS,
+			max_tracked_tokens: QUOTA_MAX_TRACKED_TOKENS,
+		}
+	}
+}
+
+impl Default for StoreConfig {
+	fn default() -> Self {
+		Self {
+			backend: StoreBackend::default(),
+			shards: STORE_SHARDS,
+			path: None,
+			redis_url: None,
+			redis_prefix: REDIS_PREFIX.to_owned(),
+		}
+	}
+}
+
+impl Default for ProxyConfig {
+	fn default() -> Self {
+		Self {
+			upstream: None,
+			preserve_host: true,
+		}
+	}
+}
+
+impl Config {
+	/// Loads the configuration used by the server binary
+	///
+	/// The file named by `MPOW_CONFIG` is read if set (and must exist),
+	/// otherwise `mpow.toml` in the working directory is read if present.
+	/// `MPOW_*` environment variables are applied on top and the result is validated.
+	///
+	/// # Returns
+	/// `Ok(Config)` or `Err(String)` describing the first problem found
+	pub fn load() -> Result<Self, String> {
+		let mut config = match std::env::var("MPOW_CONFIG") {
+			Ok(path) => Self::from_file(Path::new(&path))?,
+			Err(_) if Path::new(CONFIG_PATH).exists() => Self::from_file(Path::new(CONFIG_PATH))?,
+			Err(_) => Self::default(),
+		};
+		config.apply_env(|name| std::env::var(name).ok())?;
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// Parses a TOML configuration file; missing keys keep their defaults
+	pub fn from_file(path: &Path) -> Result<Self, String> {
+		let contents = fs::read_to_string(path)
+			.map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+		Self::from_toml(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+	}
+
+	/// Parses a TOML configuration document; missing keys keep their defaults
+	pub fn from_toml(contents: &str) -> Result<Self, String> {
+		toml::from_str(contents).map_err(|e| format!("invalid config: {}", e))
+	}
+
+	/// Applies `MPOW_*` overrides using `lookup` to read variables
+	///
+	/// # Arguments
+	/// * `lookup` - returns the value of an environment variable, if set
+	pub fn apply_env<F>(&mut self, lookup: F) -> Result<(), String>
+	where
+		F: Fn(&str) -> Option<String>,
+	{
+		if let Some(value) = lookup("MPOW_BIND") {
+			self.server.bind = parse_env("MPOW_BIND", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_TRUSTED_PROXIES") {
+			self.server.trusted_proxies =
+				parse_networks(&value).map_err(|e| format!("MPOW_TRUSTED_PROXIES: {}", e))?;
+		}
+		if let Some(value) = lookup("MPOW_DIFFICULTY_BITS") {
+			self.pow.difficulty_bits = parse_env("MPOW_DIFFICULTY_BITS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_DIFFICULTY") {
+			self.pow.difficulty = Some(parse_env("MPOW_DIFFICULTY", &value)?);
+		}
+		if let Some(value) = lookup("MPOW_POW_ALGORITHM") {
+			self.pow.algorithm = parse_env("MPOW_POW_ALGORITHM", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_SCRYPT_LOG_N") {
+			self.pow.scrypt_log_n = parse_env("MPOW_SCRYPT_LOG_N", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_SCRYPT_R") {
+			self.pow.scrypt_r = parse_env("MPOW_SCRYPT_R", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_SCRYPT_P") {
+			self.pow.scrypt_p = parse_env("MPOW_SCRYPT_P", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_MAX_CONCURRENT_VERIFICATIONS") {
+			self.pow.max_concurrent_verifications =
+				Some(parse_env("MPOW_MAX_CONCURRENT_VERIFICATIONS", &value)?);
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE") {
+			self.pow.adaptive.enabled = parse_env("MPOW_ADAPTIVE", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE_MIN_BITS") {
+			self.pow.adaptive.min_bits = Some(parse_env("MPOW_ADAPTIVE_MIN_BITS", &value)?);
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE_MAX_BITS") {
+			self.pow.adaptive.max_bits = Some(parse_env("MPOW_ADAPTIVE_MAX_BITS", &value)?);
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE_WINDOW_SECS") {
+			self.pow.adaptive.window_secs = parse_env("MPOW_ADAPTIVE_WINDOW_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE_ISSUE_RATE_HIGH") {
+			self.pow.adaptive.issue_rate_high = parse_env("MPOW_ADAPTIVE_ISSUE_RATE_HIGH", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE_FAILURE_RATIO_HIGH") {
+			self.pow.adaptive.failure_ratio_high = parse_env("MPOW_ADAPTIVE_FAILURE_RATIO_HIGH", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_ADAPTIVE_OUTSTANDING_HIGH") {
+			self.pow.adaptive.outstanding_high = parse_env("MPOW_ADAPTIVE_OUTSTANDING_HIGH", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REPUTATION") {
+			self.pow.reputation.enabled = parse_env("MPOW_REPUTATION", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REPUTATION_HALF_LIFE_SECS") {
+			self.pow.reputation.half_life_secs = parse_env("MPOW_REPUTATION_HALF_LIFE_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REPUTATION_POINTS_PER_BIT") {
+			self.pow.reputation.points_per_bit = parse_env("MPOW_REPUTATION_POINTS_PER_BIT", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REPUTATION_SUBNET_POINTS_PER_BIT") {
+			self.pow.reputation.subnet_points_per_bit =
+				parse_env("MPOW_REPUTATION_SUBNET_POINTS_PER_BIT", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REPUTATION_MAX_EXTRA_BITS") {
+			self.pow.reputation.max_extra_bits = parse_env("MPOW_REPUTATION_MAX_EXTRA_BITS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REPUTATION_MAX_CLIENTS") {
+			self.pow.reputation.max_clients = parse_env("MPOW_REPUTATION_MAX_CLIENTS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_CHALLENGE_EXPIRY_SECS") {
+			self.pow.challenge_expiry_secs = parse_env("MPOW_CHALLENGE_EXPIRY_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_MAX_ATTEMPTS") {
+			self.pow.max_attempts = parse_env("MPOW_MAX_ATTEMPTS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_MAX_NONCE_LENGTH") {
+			self.pow.max_nonce_length = parse_env("MPOW_MAX_NONCE_LENGTH", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_MAX_OUTSTANDING_CHALLENGES") {
+			self.pow.max_outstanding_challenges = parse_env("MPOW_MAX_OUTSTANDING_CHALLENGES", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_EVICTION_POLICY") {
+			self.pow.eviction_policy = parse_env("MPOW_EVICTION_POLICY", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_JANITOR_INTERVAL_SECS") {
+			self.pow.janitor_interval_secs = parse_env("MPOW_JANITOR_INTERVAL_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_CHALLENGE_MODE") {
+			self.pow.mode = parse_env("MPOW_CHALLENGE_MODE", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_CHALLENGE_SECRET") {
+			self.pow.challenge_secret = Some(value);
+		}
+		if let Some(value) = lookup("MPOW_BIND_CLIENT") {
+			self.pow.bind_client = parse_env("MPOW_BIND_CLIENT", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_COOKIE_NAME") {
+			self.session.cookie_name = value;
+		}
+		if let Some(value) = lookup("MPOW_TOKEN_EXPIRY_SECS") {
+			self.session.token_expiry_secs = parse_env("MPOW_TOKEN_EXPIRY_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_REFRESH_AFTER_SECS") {
+			self.session.refresh_after_secs = parse_env("MPOW_REFRESH_AFTER_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_MAX_SESSION_SECS") {
+			self.session.max_session_secs = parse_env("MPOW_MAX_SESSION_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_SESSION_BINDING") {
+			self.session.binding = parse_env("MPOW_SESSION_BINDING", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_BINDING_IPV4_PREFIX") {
+			self.session.binding_ipv4_prefix = parse_env("MPOW_BINDING_IPV4_PREFIX", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_BINDING_IPV6_PREFIX") {
+			self.session.binding_ipv6_prefix = parse_env("MPOW_BINDING_IPV6_PREFIX", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_JWT_ALGORITHM") {
+			self.session.jwt_algorithm = parse_env("MPOW_JWT_ALGORITHM", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_JWT_SECRET") {
+			self.session.jwt_secret = Some(value);
+		}
+		if let Some(value) = lookup("MPOW_JWT_SECRET_FILE") {
+			self.session.jwt_secret_file = Some(PathBuf::from(value));
+		}
+		if let Some(value) = lookup("MPOW_JWT_ISSUER") {
+			self.session.issuer = value;
+		}
+		if let Some(value) = lookup("MPOW_JWT_AUDIENCE") {
+			self.session.audience = value;
+		}
+		if let Some(value) = lookup("MPOW_REVOCATION_FILE") {
+			self.session.revocation_file = Some(PathBuf::from(value));
+		}
+		if let Some(value) = lookup("MPOW_QUOTA_MAX_REQUESTS") {
+			self.session.quota.max_requests = parse_env("MPOW_QUOTA_MAX_REQUESTS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_QUOTA_RATE_LIMIT") {
+			self.session.quota.rate_limit = parse_env("MPOW_QUOTA_RATE_LIMIT", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_QUOTA_RATE_WINDOW_SECS") {
+			self.session.quota.rate_window_secs = parse_env("MPOW_QUOTA_RATE_WINDOW_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_QUOTA_MAX_TRACKED_TOKENS") {
+			self.session.quota.max_tracked_tokens = parse_env("MPOW_QUOTA_MAX_TRACKED_TOKENS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_ROTATION_INTERVAL_SECS") {
+			self.session.rotation_interval_secs = parse_env("MPOW_ROTATION_INTERVAL_SECS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_ROTATION_GRACE_SECS") {
+			self.session.rotation_grace_secs = Some(parse_env("MPOW_ROTATION_GRACE_SECS", &value)?);
+		}
+		if let Some(value) = lookup("MPOW_STORE") {
+			self.store.backend = parse_env("MPOW_STORE", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_STORE_SHARDS") {
+			self.store.shards = parse_env("MPOW_STORE_SHARDS", &value)?;
+		}
+		if let Some(value) = lookup("MPOW_STORE_PATH") {
+			self.store.path = Some(PathBuf::from(value));
+		}
+		if let Some(value) = lookup("MPOW_REDIS_URL") {
+			self.store.redis_url = Some(value);
+		}
+		if let Some(value) = lookup("MPOW_REDIS_PREFIX") {
+			self.store.redis_prefix = value;
+		}
+		if let Some(value) = lookup("MPOW_ADMIN_TOKEN") {
+			self.admin.token = Some(value);
+		}
+		if let Some(value) = lookup("MPOW_PROXY_UPSTREAM") {
+			self.proxy.upstream = Some(value);
+		}
+		if let Some(value) = lookup("MPOW_PROXY_PRESERVE_HOST") {
+			self.proxy.preserve_host = parse_env("MPOW_PROXY_PRESERVE_HOST", &value)?;
+		}
+		Ok(())
+	}
+
+	/// Checks that every value is usable
+	pub fn validate(&self) -> Result<(), String> {
+		if let Some(digits) = self.pow.difficulty {
+			if digits == 0 || digits > 64 {
+				return Err(format!("pow.difficulty must be between 1 and 64, got {}", digits));
+			}
+		}
+		if self.pow.difficulty_bits == 0 || self.pow.difficulty_bits > 256 {
+			return Err(format!(
+				"pow.difficulty_bits must be between 1 and 256, got {}",
+				self.pow.difficulty_bits
+			));
+		}
+		if self.pow.algorithm == PowAlgorithm::Scrypt {
+			scrypt_params_valid(self.pow.scrypt_log_n, self.pow.scrypt_r, self.pow.scrypt_p)
+				.map_err(|e| format!("pow: {}", e))?;
+			if self.pow.scrypt_log_n > 20 {
+				return Err(String::from("pow.scrypt_log_n above 20 needs too much memory in browsers"));
+			}
+			if self.pow.difficulty_bits() > SCRYPT_MAX_DIFFICULTY_BITS {
+				return Err(format!(
+					"pow.difficulty_bits must be at most {} with scrypt, got {}",
+					SCRYPT_MAX_DIFFICULTY_BITS,
+					self.pow.difficulty_bits()
+				));
+			}
+			if self.pow.max_difficulty_bits() > SCRYPT_MAX_DIFFICULTY_BITS {
+				return Err(format!(
+					"pow.adaptive.max_bits plus pow.reputation.max_extra_bits must be at most {} with scrypt",
+					SCRYPT_MAX_DIFFICULTY_BITS
+				));
+			}
+		}
+		let adaptive = &self.pow.adaptive;
+		if adaptive.enabled {
+			let floor = adaptive.min_bits.unwrap_or_else(|| self.pow.difficulty_bits());
+			if floor == 0 || self.pow.max_difficulty_bits() > 256 {
+				return Err(String::from("pow.adaptive bits must be between 1 and 256"));
+			}
+			if adaptive.max_bits.is_some_and(|max| max < floor) {
+				return Err(String::from("pow.adaptive.max_bits must not be below min_bits"));
+			}
+			if adaptive.window_secs == 0 {
+				return Err(String::from("pow.adaptive.window_secs must be greater than 0"));
+			}
+			let ratio_valid = adaptive.failure_ratio_high > 0.0 && adaptive.failure_ratio_high <= 1.0;
+			if adaptive.issue_rate_high <= 0.0 || !ratio_valid {
+				return Err(String::from(
+					"pow.adaptive.issue_rate_high must be positive and failure_ratio_high in (0, 1]",
+				));
+			}
+		}
+		let reputation = &self.pow.reputation;
+		if reputation.enabled {
+			if reputation.half_life_secs == 0 || reputation.max_clients == 0 {
+				return Err(String::from(
+					"pow.reputation.half_life_secs and max_clients must be greater than 0",
+				));
+			}
+			if reputation.points_per_bit <= 0.0 || reputation.subnet_points_per_bit <= 0.0 {
+				return Err(String::from("pow.reputation points per bit must be positive"));
+			}
+			if self.pow.max_difficulty_bits() > 256 {
+				return Err(String::from("pow.reputation.max_extra_bits takes difficulty above 256 bits"));
+			}
+		}
+		if self.pow.max_concurrent_verifications == Some(0) {
+			return Err(String::from("pow.max_concurrent_verifications must be greater than 0"));
+		}
+		if self.pow.challenge_expiry_secs == 0 {
+			return Err(String::from("pow.challenge_expiry_secs must be greater than 0"));
+		}
+		if self.pow.max_attempts == 0 {
+			return Err(String::from("pow.max_attempts must be greater than 0"));
+		}
+		if self.pow.max_nonce_length == 0 {
+			return Err(String::from("pow.max_nonce_length must be greater than 0"));
+		}
+		if self.pow.max_outstanding_challenges == 0 {
+			return Err(String::from("pow.max_outstanding_challenges must be greater than 0"));
+		}
+		if self.pow.janitor_interval_secs == 0 {
+			return Err(String::from("pow.janitor_interval_secs must be greater than 0"));
+		}
+		if let Some(secret) = &self.pow.challenge_secret {
+			decode_secret(secret).map_err(|e| format!("pow.challenge_secret: {}", e))?;
+		}
+		if self.session.token_expiry_secs == 0 {
+			return Err(String::from("session.token_expiry_secs must be greater than 0"));
+		}
+		if self.session.rotation_interval_secs > 0 && self.session.jwt_secret.is_some() {
+			return Err(String::from(
+				"session.rotation_interval_secs needs session.jwt_secret_file instead of an inline jwt_secret, \
+				 which replicas and restarts would not see rotated",
+			));
+		}
+		if self.session.refresh_after_secs >= self.session.token_expiry_secs {
+			return Err(String::from(
+				"session.refresh_after_secs must be less than session.token_expiry_secs",
+			));
+		}
+		let quota = &self.session.quota;
+		if quota.rate_limit > 0 && quota.rate_window_secs == 0 {
+			return Err(String::from("session.quota.rate_window_secs must be greater than 0"));
+		}
+		if (quota.max_requests > 0 || quota.rate_limit > 0) && quota.max_tracked_tokens == 0 {
+			return Err(String::from("session.quota.max_tracked_tokens must be greater than 0"));
+		}
+		if self.session.issuer.is_empty() || self.session.audience.is_empty() {
+			return Err(String::from("session.issuer and session.audience must not be empty"));
+		}
+		if self.session.binding_ipv4_prefix > 32 || self.session.binding_ipv6_prefix > 128 {
+			return Err(String::from(
+				"session.binding_ipv4_prefix must be at most 32 and binding_ipv6_prefix at most 128",
+			));
+		}
+		let valid_cookie_name = !self.session.cookie_name.is_empty()
+			&& self
+				.session
+				.cookie_name
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+		if !valid_cookie_name {
+			return Err(format!(
+				"session.cookie_name must be non-empty and contain only [A-Za-z0-9_-], got {:?}",
+				self.session.cookie_name
+			));
+		}
+		if self.store.shards == 0 {
+			return Err(String::from("store.shards must be greater than 0"));
+		}
+		if self.store.backend == StoreBackend::File && self.store.path.is_none() {
+			return Err(String::from("store.path is required for the file backend"));
+		}
+		if self.store.backend == StoreBackend::Redis && self.store.redis_url.is_none() {
+			return Err(String::from("store.redis_url is required for the redis backend"));
+		}
+		if self.admin.token.as_deref().is_some_and(|t| t.len() < 16) {
+			return Err(String::from("admin.token must be at least 16 characters"));
+		}
+		if let Some(upstream) = &self.proxy.upstream {
+			let url = url::Url::parse(upstream)
+				.map_err(|e| format!("proxy.upstream {:?} is not a URL: {}", upstream, e))?;
+			if url.scheme() != "http" || url.host_str().is_none() {
+				return Err(format!("proxy.upstream must be an http:// URL, got {:?}", upstream));
+			}
+			if url.query().is_some() || url.fragment().is_some() {
+				return Err(String::from("proxy.upstream must not have a query or fragment"));
+			}
+		}
+		Ok(())
+	}
+}
+
+impl PowConfig {
+	/// Selected algorithm with its cost parameters
+	pub fn algorithm(&self) -> Algorithm {
+		match self.algorithm {
+			PowAlgorithm::Sha256 => Algorithm::Sha256,
+			PowAlgorithm::Scrypt => Algorithm::Scrypt {
+				log_n: self.scrypt_log_n,
+				r: self.scrypt_r,
+				p: self.scrypt_p,
+			},
+		}
+	}
+
+	/// Highest difficulty a challenge may be issued at, adaptive ceiling and
+	/// reputation penalty included
+	pub fn max_difficulty_bits(&self) -> u32 {
+		let ceiling = if self.adaptive.enabled {
+			let floor = self.adaptive.min_bits.unwrap_or_else(|| self.difficulty_bits());
+			self.adaptive
+				.max_bits
+				.unwrap_or(floor + ADAPTIVE_HEADROOM_BITS)
+				.max(floor)
+		} else {
+			self.difficulty_bits()
+		};
+		if self.reputation.enabled {
+			ceiling.saturating_add(self.reputation.max_extra_bits)
+		} else {
+			ceiling
+		}
+	}
+
+	/// Limit on memory-hard verifications running at once
+	pub fn max_concurrent_verifications(&self) -> usize {
+		self.max_concurrent_verifications.unwrap_or_else(|| {
+			std::thread::available_parallelism().map_or(1, |n| n.get())
+		})
+	}
+
+	/// Effective difficulty in leading zero bits
+	pub fn difficulty_bits(&self) -> u32 {
+		match self.difficulty {
+			Some(digits) => digits as u32 * 4,
+			None => self.difficulty_bits,
+		}
+	}
+}
+
+impl SessionConfig {
+	/// Grace window for rotated-out keys
+	pub fn rotation_grace(&self) -> u64 {
+		self.rotation_grace_secs.unwrap_or(self.token_expiry_secs)
+	}
+
+	/// Longest a session lasts: one token lifetime, or `max_session_secs` when tokens
+	/// are refreshed, without bound if that is 0
+	pub fn session_lifetime(&self) -> u64 {
+		match (self.refresh_after_secs, self.max_session_secs) {
+			(0, _) => self.token_expiry_secs,
+			(_, 0) => u64::MAX,
+			(_, max_session_secs) => max_session_secs.max(self.token_expiry_secs),
+		}
+	}
+
+	/// When `/validate` reissues session tokens
+	pub fn refresh_policy(&self) -> RefreshPolicy {
+		RefreshPolicy {
+			refresh_after_secs: self.refresh_after_secs,
+			token_expiry_secs: self.token_expiry_secs,
+			max_session_secs: self.max_session_secs,
+		}
+	}
+
+	/// Issuer and audience of session tokens
+	pub fn token_scope(&self) -> TokenScope {
+		TokenScope {
+			issuer: self.issuer.clone(),
+			audience: self.audience.clone(),
+		}
+	}
+
+	/// File the keyring is persisted to; an inline `jwt_secret` takes precedence
+	pub fn keyring_file(&self) -> Option<&Path> {
+		match self.jwt_secret {
+			Some(_) => None,
+			None => self.jwt_secret_file.as_deref(),
+		}
+	}
+}
+
+fn parse_env<T>(name: &str, value: &str) -> Result<T, String>
+where
+	T: std::str::FromStr,
+	T::Err: std::fmt::Display,
+{
+	value
+		.trim()
+		.parse()
+		.map_err(|e| format!("invalid value {:?} for {}: {}", value, name, e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	#[test]
+	fn test_defaults_match_values() {
+		let config = Config::default();
+		assert_eq!(config.server.bind.to_string(), BIND_ADDR);
+		assert_eq!(config.pow.difficulty_bits(), POW_DIFFICULTY_BITS);
+		assert_eq!(config.pow.max_attempts, MAX_ATTEMPTS);
+		assert_eq!(config.session.cookie_name, COOKIE_NAME);
+		assert_eq!(config.session.token_expiry_secs, TOKEN_EXPIRY_SECS);
+		config.validate().expect("defaults should be valid");
+	}
+
+	#[test]
+	fn test_partial_toml_keeps_defaults() {
+		let config = Config::from_toml(
+			r#"
+			[server]
+			bind = "127.0.0.1:8080"
+
+			[pow]
+			difficulty_bits = 18
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(config.server.bind.to_string(), "127.0.0.1:8080");
+		assert_eq!(config.pow.difficulty_bits(), 18);
+		assert_eq!(config.pow.challenge_expiry_secs, CHALLENGE_EXPIRY_SECS);
+		assert_eq!(config.session.cookie_name, COOKIE_NAME);
+	}
+
+	#[test]
+	fn test_legacy_hex_difficulty() {
+		let config = Config::from_toml("[pow]\ndifficulty = 5\n").unwrap();
+		assert_eq!(config.pow.difficulty_bits(), 20);
+		config.validate().unwrap();
+	}
+
+	#[test]
+	fn test_scrypt_settings() {
+		let config = Config::from_toml("[pow]\nalgorithm = \"scrypt\"\ndifficulty_bits = 4\n").unwrap();
+		config.validate().unwrap();
+		assert_eq!(
+			config.pow.algorithm(),
+			Algorithm::Scrypt {
+				log_n: SCRYPT_LOG_N,
+				r: SCRYPT_R,
+				p: SCRYPT_P
+			}
+		);
+
+		// The SHA-256 default difficulty would take visitors hours with scrypt
+		let config = Config::from_toml("[pow]\nalgorithm = \"scrypt\"\n").unwrap();
+		assert!(config.validate().is_err());
+
+		let config = Config::from_toml("[pow]\nalgorithm = \"scrypt\"\ndifficulty_bits = 4\nscrypt_r = 0\n").unwrap();
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_adaptive_section() {
+		let config = Config::from_toml(
+			r#"
+			[pow]
+			difficulty_bits = 14
+
+			[pow.adaptive]
+			enabled = true
+			max_bits = 20
+			"#,
+		)
+		.unwrap();
+		config.validate().unwrap();
+		assert!(config.pow.adaptive.enabled);
+		assert_eq!(config.pow.max_difficulty_bits(), 20);
+
+		let mut config = Config::default();
+		config.pow.adaptive.enabled = true;
+		config.pow.adaptive.min_bits = Some(18);
+		config.pow.adaptive.max_bits = Some(12);
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_reputation_section() {
+		let config = Config::from_toml(
+			r#"
+			[pow.reputation]
+			enabled = true
+			max_extra_bits = 2
+			"#,
+		)
+		.unwrap();
+		config.validate().unwrap();
+		assert_eq!(config.pow.max_difficulty_bits(), POW_DIFFICULTY_BITS + 2);
+
+		let mut config = Config::default();
+		config.pow.algorithm = PowAlgorithm::Scrypt;
+		config.pow.difficulty_bits = 10;
+		config.pow.reputation.enabled = true;
+		config.pow.reputation.max_extra_bits = 4;
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_session_binding() {
+		let mut config = Config::from_toml("[session]\nbinding = \"strict\"\nbinding_ipv6_prefix = 56\n").unwrap();
+		config.validate().unwrap();
+		assert_eq!(config.session.binding, BindingPolicy::Strict);
+		assert_eq!(config.session.binding_ipv6_prefix, 56);
+
+		config.apply_env(|name| (name == "MPOW_BINDING_IPV4_PREFIX").then(|| String::from("33"))).unwrap();
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_sliding_session() {
+		let mut config = Config::from_toml("[session]\ntoken_expiry_secs = 3600\nrefresh_after_secs = 600\n").unwrap();
+		config.validate().unwrap();
+		let policy = config.session.refresh_policy();
+		assert_eq!(policy.refresh_after_secs, 600);
+		assert_eq!(policy.max_session_secs, MAX_SESSION_SECS);
+		assert_eq!(config.session.session_lifetime(), MAX_SESSION_SECS);
+		config.session.max_session_secs = 0;
+		assert_eq!(config.session.session_lifetime(), u64::MAX);
+
+		config.apply_env(|name| (name == "MPOW_REFRESH_AFTER_SECS").then(|| String::from("3600"))).unwrap();
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_quota_section() {
+		let mut config = Config::from_toml("[session.quota]\nmax_requests = 500\nrate_limit = 30\n").unwrap();
+		config.validate().unwrap();
+		assert_eq!(config.session.quota.max_requests, 500);
+		assert_eq!(config.session.quota.rate_window_secs, QUOTA_RATE_WINDOW_SECS);
+
+		config.apply_env(|name| (name == "MPOW_QUOTA_RATE_WINDOW_SECS").then(|| String::from("0"))).unwrap();
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_unknown_key_rejected() {
+		let err = Config::from_toml("[pow]\ndificulty = 5\n").unwrap_err();
+		assert!(err.contains("dificulty"), "unexpected error: {}", err);
+	}
+
+	#[test]
+	fn test_env_overrides_file() {
+		let mut config = Config::from_toml("[pow]\ndifficulty_bits = 18\n").unwrap();
+		let env: HashMap<&str, &str> = HashMap::from([
+			("MPOW_DIFFICULTY_BITS", "20"),
+			("MPOW_COOKIE_NAME", "gate"),
+			("MPOW_BIND", "[::1]:4000"),
+			("MPOW_TRUSTED_PROXIES", "172.28.0.10/32, fd00::/8"),
+		]);
+
+		config
+			.apply_env(|name| env.get(name).map(|v| v.to_string()))
+			.unwrap();
+
+		assert_eq!(config.pow.difficulty_bits(), 20);
+		assert_eq!(config.session.cookie_name, "gate");
+		assert_eq!(config.server.bind.to_string(), "[::1]:4000");
+		assert_eq!(config.server.trusted_proxies.len(), 2);
+	}
+
+	#[test]
+	fn test_invalid_env_value() {
+		let mut config = Config::default();
+		let err = config
+			.apply_env(|name| (name == "MPOW_MAX_ATTEMPTS").then(|| String::from("lots")))
+			.unwrap_err();
+		assert!(err.contains("MPOW_MAX_ATTEMPTS"), "unexpected error: {}", err);
+	}
+
+	#[test]
+	fn test_stateless_mode_from_env() {
+		let mut config = Config::default();
+		let env: HashMap<&str, &str> = HashMap::from([
+			("MPOW_CHALLENGE_MODE", "stateless"),
+			("MPOW_BIND_CLIENT", "false"),
+		]);
+
+		config
+			.apply_env(|name| env.get(name).map(|v| v.to_string()))
+			.unwrap();
+
+		assert_eq!(config.pow.mode, ChallengeMode::Stateless);
+		assert!(!config.pow.bind_client);
+
+		config.pow.challenge_secret = Some(String::from("abcd"));
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_store_section() {
+		let config = Config::from_toml("[store]\nbackend = \"file\"\npath = \"/data/challenges.redb\"\n").unwrap();
+		assert_eq!(config.store.backend, StoreBackend::File);
+		assert_eq!(config.store.shards, STORE_SHARDS);
+		config.validate().unwrap();
+
+		let config = Config::from_toml("[store]\nbackend = \"file\"\n").unwrap();
+		assert!(config.validate().is_err());
+
+		let mut config = Config::default();
+		let env: HashMap<&str, &str> = HashMap::from([
+			("MPOW_STORE", "redis"),
+			("MPOW_REDIS_URL", "redis://redis:6379/0"),
+		]);
+		config
+			.apply_env(|name| env.get(name).map(|v| v.to_string()))
+			.unwrap();
+		assert_eq!(config.store.backend, StoreBackend::Redis);
+		assert_eq!(config.store.redis_prefix, REDIS_PREFIX);
+		config.validate().unwrap();
+	}
+
+	#[test]
+	fn test_proxy_section() {
+		let config = Config::from_toml("[proxy]\nupstream = \"http://app:8080/\"\n").unwrap();
+		assert_eq!(config.proxy.upstream.as_deref(), Some("http://app:8080/"));
+		assert!(config.proxy.preserve_host);
+		config.validate().unwrap();
+
+		let mut config = Config::default();
+		let env: HashMap<&str, &str> = HashMap::from([
+			("MPOW_PROXY_UPSTREAM", "https://app:8443"),
+			("MPOW_PROXY_PRESERVE_HOST", "false"),
+		]);
+		config
+			.apply_env(|name| env.get(name).map(|v| v.to_string()))
+			.unwrap();
+		assert!(!config.proxy.preserve_host);
+		assert!(config.validate().is_err());
+
+		config.proxy.upstream = Some(String::from("not a url"));
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_validate_rejects_bad_values() {
+		let mut config = Config::default();
+		config.pow.difficulty_bits = 0;
+		assert!(config.validate().is_err());
+
+		let mut config = Config::default();
+		config.pow.difficulty = Some(65);
+		assert!(config.validate().is_err());
+
+		let mut config = Config::default();
+		config.session.cookie_name = String::from("bad;name");
+		assert!(config.validate().is_err());
+
+		let mut config = Config::default();
+		config.session.token_expiry_secs = 0;
+		assert!(config.validate().is_err());
+
+		let mut config = Config::default();
+		config.session.rotation_interval_secs = 86_400;
+		config.session.jwt_secret = Some(hex::encode([7u8; 32]));
+		assert!(config.validate().is_err());
+		config.session.jwt_secret = None;
+		config.session.jwt_secret_file = Some(PathBuf::from("/data/jwt.key"));
+		config.validate().unwrap();
+	}
+}
@@ -0,0 +1,240 @@
+//! Adaptive proof-of-work difficulty
+//!
+//! Handlers report every issued challenge and every good or bad submission;
+//! once per window the controller compares the observed issuance rate, failure
+//! ratio and outstanding challenge count against the configured thresholds and
+//! moves the difficulty one bit up under pressure or one bit down when quiet.
+
+use std::{
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use crate::config::PowConfig;
+use crate::store::SharedStore;
+use crate::values::ADAPTIVE_HEADROOM_BITS;
+
+/// Something a handler observed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+	Issued,
+	Solved,
+	Failed,
+}
+
+/// Counters for the current observation window
+#[derive(Debug, Default)]
+struct Window {
+	current: u32,
+	issued: u64,
+	solved: u64,
+	failed: u64,
+}
+
+/// Chooses the difficulty for new challenges
+pub struct DifficultyController {
+	enabled: bool,
+	floor: u32,
+	ceiling: u32,
+	issue_rate_high: f64,
+	failure_ratio_high: f64,
+	outstanding_high: usize,
+	window: Mutex<Window>,
+}
+
+impl DifficultyController {
+	pub fn new(pow: &PowConfig) -> Self {
+		let adaptive = &pow.adaptive;
+		let (floor, ceiling) = if adaptive.enabled {
+			let floor = adaptive.min_bits.unwrap_or_else(|| pow.difficulty_bits());
+			(floor, adaptive.max_bits.unwrap_or(floor + ADAPTIVE_HEADROOM_BITS).max(floor))
+		} else {
+			(pow.difficulty_bits(), pow.difficulty_bits())
+		};
+		Self {
+			enabled: adaptive.enabled,
+			floor,
+			ceiling,
+			issue_rate_high: adaptive.issue_rate_high,
+			failure_ratio_high: adaptive.failure_ratio_high,
+			outstanding_high: adaptive.outstanding_high,
+			window: Mutex::new(Window {
+				current: floor,
+				..Window::default()
+			}),
+		}
+	}
+
+	/// Difficulty in bits for a challenge issued now
+	pub fn current(&self) -> u32 {
+		self.window.lock().map_or(self.floor, |w| w.current)
+	}
+
+	pub fn record(&self, event: Event) {
+		if !self.enabled {
+			return;
+		}
+		if let Ok(mut window) = self.window.lock() {
+			match event {
+				Event::Issued => window.issued += 1,
+				Event::Solved => window.solved += 1,
+				Event::Failed => window.failed += 1,
+			}
+		}
+	}
+
+	/// Closes the current window and moves the difficulty by at most one bit
+	///
+	/// # Arguments
+	/// * `elapsed_secs` - length of the window being closed
+	/// * `outstanding` - challenges currently waiting for a solution
+	///
+	/// # Returns
+	/// The difficulty for the next window
+	pub fn adjust(&self, elapsed_secs: u64, outstanding: usize) -> u32 {
+		let Ok(mut window) = self.window.lock() else {
+			return self.floor;
+		};
+		if !self.enabled {
+			return window.current;
+		}
+
+		let issue_rate = window.issued as f64 / elapsed_secs.max(1) as f64;
+		let submissions = window.solved + window.failed;
+		let failure_ratio = if submissions == 0 {
+			0.0
+		} else {
+			window.failed as f64 / submissions as f64
+		};
+
+		let under_pressure = issue_rate > self.issue_rate_high
+			|| failure_ratio > self.failure_ratio_high
+			|| outstanding > self.outstanding_high;
+		let quiet = issue_rate < self.issue_rate_high / 2.0
+			&& failure_ratio < self.failure_ratio_high / 2.0
+			&& outstanding < self.outstanding_high / 2;
+
+		let previous = window.current;
+		if under_pressure {
+			window.current = (window.current + 1).min(self.ceiling);
+		} else if quiet {
+			window.current = window.current.saturating_sub(1).max(self.floor);
+		}
+		if window.current != previous {
+			tracing::info!(
+				"difficulty {} -> {} bits (issued {:.1}/s, failures {:.0}%, outstanding {})",
+				previous,
+				window.current,
+				issue_rate,
+				failure_ratio * 100.0,
+				outstanding
+			);
+		}
+
+		let current = window.current;
+		*window = Window {
+			current,
+			..Window::default()
+		};
+		current
+	}
+}
+
+/// Periodically feeds the controller the outstanding challenge count and adjusts it
+pub fn spawn_controller(
+	controller: Arc<DifficultyController>,
+	store: SharedStore,
+	window_secs: u64,
+) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		let period = Duration::from_secs(window_secs);
+		let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+		loop {
+			ticker.tick().await;
+			let outstanding = match store.len().await {
+				Ok(outstanding) => outstanding,
+				Err(e) => {
+					tracing::warn!("difficulty controller cannot count challenges: {}", e);
+					0
+				}
+			};
+			controller.adjust(window_secs, outstanding);
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn controller() -> DifficultyController {
+		let mut pow = PowConfig::default();
+		pow.adaptive.enabled = true;
+		pow.adaptive.min_bits = Some(10);
+		pow.adaptive.max_bits = Some(12);
+		pow.adaptive.issue_rate_high = 5.0;
+		pow.adaptive.failure_ratio_high = 0.5;
+		pow.adaptive.outstanding_high = 100;
+		DifficultyController::new(&pow)
+	}
+
+	#[test]
+	fn test_starts_at_floor() {
+		assert_eq!(controller().current(), 10);
+	}
+
+	#[test]
+	fn test_raises_under_issuance_wave_up_to_ceiling() {
+		let controller = controller();
+		for expected in [11, 12, 12] {
+			for _ in 0..100 {
+				controller.record(Event::Issued);
+			}
+			assert_eq!(controller.adjust(10, 0), expected);
+		}
+	}
+
+	#[test]
+	fn test_raises_on_failures_and_backlog() {
+		let controller = controller();
+		for _ in 0..8 {
+			controller.record(Event::Failed);
+		}
+		controller.record(Event::Solved);
+		assert_eq!(controller.adjust(10, 0), 11);
+
+		assert_eq!(controller.adjust(10, 500), 12);
+	}
+
+	#[test]
+	fn test_relaxes_when_quiet() {
+		let controller = controller();
+		controller.adjust(10, 500);
+		controller.adjust(10, 500);
+		assert_eq!(controller.current(), 12);
+
+		// Moderate load holds the level
+		for _ in 0..30 {
+			controller.record(Event::Issued);
+		}
+		assert_eq!(controller.adjust(10, 0), 12);
+
+		assert_eq!(controller.adjust(10, 0), 11);
+		assert_eq!(controller.adjust(10, 0), 10);
+		assert_eq!(controller.adjust(10, 0), 10);
+	}
+
+	#[test]
+	fn test_disabled_is_fixed() {
+		let pow = PowConfig {
+			difficulty_bits: 14,
+			..PowConfig::default()
+		};
+		let controller = DifficultyController::new(&pow);
+		for _ in 0..1000 {
+			controller.record(Event::Issued);
+		}
+		assert_eq!(controller.adjust(1, 1_000_000), 14);
+		assert_eq!(controller.current(), 14);
+	}
+}
@@ -0,0 +1,258 @@
+//! Envoy external authorisation
+//!
+//! Envoy's `ext_authz` filter asks mpow about every request, over HTTP or gRPC,
+//! and both are served on the main listener:
+//!
+//! - HTTP: Envoy sends the original method, path and allowed headers under the
+//!   `/ext_authz` prefix. A `200` lets the request through, anything else is
+//!   returned to the visitor, so a request without a session gets the challenge
+//!   page, as with [`forward_auth`](crate::forward_auth).
+//! - gRPC: `envoy.service.auth.v3.Authorization/Check` over HTTP/2 cleartext.
+//!   Denials carry the challenge page as a `DeniedHttpResponse`; a refreshed
+//!   session cookie is added to the response of an allowed request.
+
+pub mod proto;
+
+use axum::{
+	body::Body,
+	extract::{Request, State},
+	http::{header, HeaderMap, HeaderName, Method, Uri},
+	response::{IntoResponse, Response},
+	routing::{any, post},
+	Router,
+};
+use std::net::IpAddr;
+use tonic::{codec::ProstCodec, server::Grpc, Status};
+
+use crate::{
+	client_ip::ClientIp,
+	forward_auth::{check_forwarded, revisitable, Decision},
+	routing::AppState,
+};
+use proto::{
+	code, CheckRequest, CheckResponse, DeniedHttpResponse, HeaderValueOption, HttpResponse, HttpStatus,
+	OkHttpResponse, RpcStatus, CHECK_PATH,
+};
+
+/// Prefix Envoy's HTTP mode is configured with (`path_prefix`)
+const HTTP_PREFIX: &str = "/ext_authz";
+
+/// Routes for the HTTP check under `/ext_authz` and the gRPC `Check` method
+pub fn ext_authz_router() -> Router<AppState> {
+	Router::new()
+		.route(HTTP_PREFIX, any(handle_http_check))
+		.route(&format!("{}/{{*path}}", HTTP_PREFIX), any(handle_http_check))
+		.route(CHECK_PATH, post(handle_grpc_check))
+}
+
+async fn handle_http_check(
+	ClientIp(ip): ClientIp,
+	method: Method,
+	uri: Uri,
+	headers: HeaderMap,
+	State(state): State<AppState>,
+) -> Response {
+	let original = uri
+		.path_and_query()
+		.map_or("/", |pq| pq.as_str())
+		.strip_prefix(HTTP_PREFIX)
+		.filter(|rest| rest.starts_with('/'))
+		.unwrap_or("/");
+	check_forwarded(&state, ip, &headers, revisitable(&method, Some(original)))
+		.await
+		.into_response()
+}
+
+async fn handle_grpc_check(State(state): State<AppState>, request: Request) -> Response {
+	let service = tower::service_fn(move |request: tonic::Request<CheckRequest>| {
+		let state = state.clone();
+		async move { Ok::<_, Status>(tonic::Response::new(check(&state, request.into_inner()).await)) }
+	});
+	Grpc::new(ProstCodec::default())
+		.unary(service, request)
+		.await
+		.map(Body::new)
+}
+
+/// Answers a gRPC `CheckRequest`
+pub async fn check(state: &AppState, request: CheckRequest) -> CheckResponse {
+	let attributes = request.attributes.unwrap_or_default();
+	let http = attributes.request.and_then(|r| r.http).unwrap_or_default();
+	let headers = header_map(&http.headers);
+	let ip = attributes
+		.source
+		.and_then(|peer| peer.address)
+		.and_then(|address| address.socket_address)
+		.and_then(|socket| socket.address.parse::<IpAddr>().ok())
+		.map(|peer| state.proxies.resolve(peer, &headers));
+	let method = Method::from_bytes(http.method.as_bytes()).unwrap_or(Method::GET);
+
+	match check_forwarded(state, ip, &headers, revisitable(&method, Some(&http.path))).await {
+		Decision::Allow { refreshed_cookie } => CheckResponse {
+			status: Some(rpc_status(code::OK, "")),
+			http_response: Some(HttpResponse::OkResponse(OkHttpResponse {
+				response_headers_to_add: refreshed_cookie
+					.map(|cookie| HeaderValueOption::new(header::SET_COOKIE.as_str(), cookie))
+					.into_iter()
+					.collect(),
+			})),
+		},
+		Decision::Challenge(html) => denied(
+			code::UNAUTHENTICATED,
+			"proof of work required",
+			401,
+			vec![
+				HeaderValueOption::new(header::CONTENT_TYPE.as_str(), "text/html; charset=utf-8"),
+				HeaderValueOption::new(header::CACHE_CONTROL.as_str(), "no-store"),
+			],
+			html,
+		),
+		Decision::Refuse(refusal) => denied(
+			code::PERMISSION_DENIED,
+			refusal.message,
+			refusal.status.as_u16().into(),
+			refusal
+				.retry_after
+				.map(|secs| HeaderValueOption::new(header::RETRY_AFTER.as_str(), secs.to_string()))
+				.into_iter()
+				.collect(),
+			refusal.message.to_owned(),
+		),
+	}
+}
+
+fn rpc_status(code: i32, message: &str) -> RpcStatus {
+	RpcStatus {
+		code,
+		message: message.to_owned(),
+	}
+}
+
+fn denied(code: i32, message: &str, http_status: i32, headers: Vec<HeaderValueOption>, body: String) -> CheckResponse {
+	CheckResponse {
+		status: Some(rpc_status(code, message)),
+		http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+			status: Some(HttpStatus { code: http_status }),
+			headers,
+			body,
+		})),
+	}
+}
+
+/// Headers of the original request, without pseudo-headers
+fn header_map(headers: &std::collections::HashMap<String, String>) -> HeaderMap {
+	headers
+		.iter()
+		.filter(|(name, _)| !name.starts_with(':'))
+		.filter_map(|(name, value)| Some((HeaderName::try_from(name.as_str()).ok()?, value.parse().ok()?)))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+	use crate::jwt::issue_jwt;
+	use crate::routing::create_router;
+	use crate::values::COOKIE_NAME;
+	use http_body_util::BodyExt;
+	use proto::{Address, AttributeContext, AttributeRequest, HttpRequest, Peer, SocketAddress};
+	use prost::Message;
+	use tower::ServiceExt;
+
+	fn check_request(path: &str, cookie: Option<&str>) -> CheckRequest {
+		let mut headers = std::collections::HashMap::from([
+			(String::from(":authority"), String::from("app.example.com")),
+			(String::from("user-agent"), String::from("test")),
+		]);
+		if let Some(token) = cookie {
+			headers.insert(String::from("cookie"), format!("{}={}", COOKIE_NAME, token));
+		}
+		CheckRequest {
+			attributes: Some(AttributeContext {
+				source: Some(Peer {
+					address: Some(Address {
+						socket_address: Some(SocketAddress {
+							address: String::from("203.0.113.7"),
+							port_value: 51234,
+						}),
+					}),
+				}),
+				request: Some(AttributeRequest {
+					http: Some(HttpRequest {
+						method: String::from("GET"),
+						headers,
+						path: path.to_owned(),
+						host: String::from("app.example.com"),
+					}),
+				}),
+			}),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_http_check() {
+		let state = AppState::new(Config::default()).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let app = create_router(state);
+
+		let request = Request::builder()
+			.uri("/ext_authz/articles/1?page=2")
+			.body(Body::empty())
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), 401);
+		let body = response.into_body().collect().await.unwrap().to_bytes();
+		assert!(std::str::from_utf8(&body).unwrap().contains(r#"const returnTo = "/articles/1?page=2";"#));
+
+		let request = Request::builder()
+			.uri("/ext_authz/articles/1")
+			.header(header::COOKIE, format!("{}={}", COOKIE_NAME, token))
+			.body(Body::empty())
+			.unwrap();
+		assert_eq!(app.oneshot(request).await.unwrap().status(), 200);
+	}
+
+	#[tokio::test]
+	async fn test_grpc_check_decisions() {
+		let state = AppState::new(Config::default()).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+
+		let response = check(&state, check_request("/articles/1", None)).await;
+		assert_eq!(response.status.unwrap().code, code::UNAUTHENTICATED);
+		let Some(HttpResponse::DeniedResponse(denied)) = response.http_response else {
+			panic!("expected a denied response");
+		};
+		assert_eq!(denied.status.unwrap().code, 401);
+		assert!(denied.body.contains(r#"const returnTo = "/articles/1";"#));
+
+		let response = check(&state, check_request("/articles/1", Some(&token))).await;
+		assert_eq!(response.status.unwrap().code, code::OK);
+		assert!(matches!(response.http_response, Some(HttpResponse::OkResponse(_))));
+	}
+
+	#[tokio::test]
+	async fn test_grpc_wire_format() {
+		let app = create_router(AppState::new(Config::default()).unwrap());
+		let message = check_request("/", None).encode_to_vec();
+		let mut frame = vec![0u8];
+		frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+		frame.extend_from_slice(&message);
+		let request = Request::builder()
+			.method(Method::POST)
+			.uri(CHECK_PATH)
+			.header(header::CONTENT_TYPE, "application/grpc")
+			.header("te", "trailers")
+			.body(Body::from(frame))
+			.unwrap();
+
+		let response = app.oneshot(request).await.unwrap();
+		assert_eq!(response.status(), 200);
+		let collected = response.into_body().collect().await.unwrap();
+		assert_eq!(collected.trailers().unwrap()["grpc-status"], "0");
+		let body = collected.to_bytes();
+		let length = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+		let response = CheckResponse::decode(&body[5..5 + length]).unwrap();
+		assert_eq!(response.status.unwrap().code, code::UNAUTHENTICATED);
+	}
+}
@@ -0,0 +1,158 @@
+//! Messages of `envoy.service.auth.v3.Authorization/Check`
+//!
+//! Written by hand after the Envoy API protos, keeping only the fields mpow
+//! reads or sets; protobuf skips the others when decoding. Tags match upstream.
+
+use std::collections::HashMap;
+
+/// Fully qualified path of the `Check` method
+pub const CHECK_PATH: &str = "/envoy.service.auth.v3.Authorization/Check";
+
+/// `google.rpc.Code` values used in [`RpcStatus`]
+pub mod code {
+	pub const OK: i32 = 0;
+	pub const PERMISSION_DENIED: i32 = 7;
+	pub const UNAUTHENTICATED: i32 = 16;
+}
+
+/// `envoy.service.auth.v3.CheckRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckRequest {
+	#[prost(message, optional, tag = "1")]
+	pub attributes: Option<AttributeContext>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttributeContext {
+	/// Downstream peer as seen by Envoy
+	#[prost(message, optional, tag = "1")]
+	pub source: Option<Peer>,
+	#[prost(message, optional, tag = "4")]
+	pub request: Option<AttributeRequest>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext.Peer`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Peer {
+	#[prost(message, optional, tag = "1")]
+	pub address: Option<Address>,
+}
+
+/// `envoy.config.core.v3.Address`, socket addresses only
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Address {
+	#[prost(message, optional, tag = "1")]
+	pub socket_address: Option<SocketAddress>,
+}
+
+/// `envoy.config.core.v3.SocketAddress`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SocketAddress {
+	#[prost(string, tag = "2")]
+	pub address: String,
+	#[prost(uint32, tag = "3")]
+	pub port_value: u32,
+}
+
+/// `envoy.service.auth.v3.AttributeContext.Request`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttributeRequest {
+	#[prost(message, optional, tag = "2")]
+	pub http: Option<HttpRequest>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext.HttpRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpRequest {
+	#[prost(string, tag = "2")]
+	pub method: String,
+	/// Lower-case header names, including pseudo-headers such as `:authority`
+	#[prost(map = "string, string", tag = "3")]
+	pub headers: HashMap<String, String>,
+	/// Request target, query string included
+	#[prost(string, tag = "4")]
+	pub path: String,
+	#[prost(string, tag = "5")]
+	pub host: String,
+}
+
+/// `envoy.service.auth.v3.CheckResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckResponse {
+	#[prost(message, optional, tag = "1")]
+	pub status: Option<RpcStatus>,
+	#[prost(oneof = "HttpResponse", tags = "2, 3")]
+	pub http_response: Option<HttpResponse>,
+}
+
+/// `google.rpc.Status`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RpcStatus {
+	#[prost(int32, tag = "1")]
+	pub code: i32,
+	#[prost(string, tag = "2")]
+	pub message: String,
+}
+
+/// `CheckResponse.http_response`
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum HttpResponse {
+	#[prost(message, tag = "2")]
+	DeniedResponse(DeniedHttpResponse),
+	#[prost(message, tag = "3")]
+	OkResponse(OkHttpResponse),
+}
+
+/// `envoy.service.auth.v3.DeniedHttpResponse`, returned to the downstream client
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeniedHttpResponse {
+	#[prost(message, optional, tag = "1")]
+	pub status: Option<HttpStatus>,
+	#[prost(message, repeated, tag = "2")]
+	pub headers: Vec<HeaderValueOption>,
+	#[prost(string, tag = "3")]
+	pub body: String,
+}
+
+/// `envoy.service.auth.v3.OkHttpResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OkHttpResponse {
+	/// Added to the downstream response once the upstream answers
+	#[prost(message, repeated, tag = "6")]
+	pub response_headers_to_add: Vec<HeaderValueOption>,
+}
+
+/// `envoy.type.v3.HttpStatus`; the enum's values are the status codes themselves
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpStatus {
+	#[prost(int32, tag = "1")]
+	pub code: i32,
+}
+
+/// `envoy.config.core.v3.HeaderValueOption`; the default action appends
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValueOption {
+	#[prost(message, optional, tag = "1")]
+	pub header: Option<HeaderValue>,
+}
+
+/// `envoy.config.core.v3.HeaderValue`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValue {
+	#[prost(string, tag = "1")]
+	pub key: String,
+	#[prost(string, tag = "2")]
+	pub value: String,
+}
+
+impl HeaderValueOption {
+	pub fn new(key: impl Into<String>, value: impl Into<String>) -> Self {
+		Self {
+			header: Some(HeaderValue {
+				key: key.into(),
+				value: value.into(),
+			}),
+		}
+	}
+}
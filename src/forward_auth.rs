@@ -0,0 +1,230 @@
+//! Forward-auth endpoint for Traefik and Caddy
+//!
+//! Traefik's `ForwardAuth` and Caddy's `forward_auth` send every request to
+//! `/forward_auth` first, describing it in `X-Forwarded-Method`,
+//! `X-Forwarded-Host` and `X-Forwarded-Uri`. A `2xx` lets the request through;
+//! anything else is returned to the visitor as is, so a request without a
+//! session gets the challenge page itself, set to return to the requested page.
+//! [`check_forwarded`] holds the decision, shared with the Envoy integration.
+
+use axum::{
+	extract::State,
+	http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+	response::{Html, IntoResponse, Response},
+	routing::any,
+	Router,
+};
+use std::net::IpAddr;
+
+use crate::{
+	client_ip::ClientIp,
+	html::generate_challenge_html,
+	redirect::{sanitize_return_to, DEFAULT_RETURN_TO},
+	routing::{authenticate, issue_challenge, AppState, Refusal},
+	values::REFRESH_COOKIE_HEADER,
+};
+
+const FORWARDED_METHOD_HEADER: &str = "x-forwarded-method";
+const FORWARDED_HOST_HEADER: &str = "x-forwarded-host";
+const FORWARDED_URI_HEADER: &str = "x-forwarded-uri";
+
+/// Request being authorised, as described by the proxy
+#[derive(Debug, PartialEq, Eq)]
+struct ForwardedRequest {
+	method: Method,
+	host: Option<String>,
+	uri: Option<String>,
+}
+
+impl ForwardedRequest {
+	fn from_headers(headers: &HeaderMap) -> Self {
+		let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+		Self {
+			method: header(FORWARDED_METHOD_HEADER)
+				.and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+				.unwrap_or(Method::GET),
+			host: header(FORWARDED_HOST_HEADER),
+			uri: header(FORWARDED_URI_HEADER),
+		}
+	}
+
+	fn return_to(&self) -> Option<String> {
+		revisitable(&self.method, self.uri.as_deref())
+	}
+}
+
+/// Page to come back to after the challenge; only pages that can be revisited
+/// with a plain `GET` qualify
+pub fn revisitable(method: &Method, uri: Option<&str>) -> Option<String> {
+	if method != Method::GET && method != Method::HEAD {
+		return None;
+	}
+	uri.and_then(sanitize_return_to)
+}
+
+/// Outcome of checking a request on behalf of a proxy
+#[derive(Debug)]
+pub enum Decision {
+	/// Valid session; carries the `Set-Cookie` value of a refreshed token
+	Allow { refreshed_cookie: Option<String> },
+	/// No session; carries the challenge page to show instead
+	Challenge(String),
+	/// Turned away without a challenge, e.g. over quota or while the gate is busy
+	Refuse(Refusal),
+}
+
+impl IntoResponse for Decision {
+	fn into_response(self) -> Response {
+		match self {
+			Decision::Allow { refreshed_cookie } => {
+				let mut response = StatusCode::OK.into_response();
+				if let Some(cookie) = refreshed_cookie.and_then(|c| HeaderValue::from_str(&c).ok()) {
+					response.headers_mut().insert(REFRESH_COOKIE_HEADER, cookie.clone());
+					response.headers_mut().insert(header::SET_COOKIE, cookie);
+				}
+				response
+			}
+			Decision::Challenge(html) => (
+				StatusCode::UNAUTHORIZED,
+				[(header::CACHE_CONTROL, "no-store")],
+				Html(html),
+			)
+				.into_response(),
+			Decision::Refuse(refusal) => refusal.into_response(),
+		}
+	}
+}
+
+/// Checks the session of a request a proxy asks about
+///
+/// # Arguments
+/// * `ip` - client address
+/// * `headers` - headers of the original request, with its cookies
+/// * `return_to` - page to come back to after a challenge, see [`revisitable`]
+pub async fn check_forwarded(
+	state: &AppState,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+	return_to: Option<String>,
+) -> Decision {
+	match authenticate(state, ip, headers) {
+		Ok(Some(session)) => {
+			return Decision::Allow {
+				refreshed_cookie: session.refreshed_cookie,
+			}
+		}
+		Ok(None) => {}
+		Err(status) => return Decision::Refuse(status.into()),
+	}
+	let issued = match issue_challenge(state, ip, headers, return_to).await {
+		Ok(issued) => issued,
+		Err(refusal) => return Decision::Refuse(refusal),
+	};
+	Decision::Challenge(generate_challenge_html(
+		&issued.token,
+		&issued.challenge,
+		issued.difficulty_bits,
+		state.verifier.algorithm(),
+		issued.return_to.as_deref().unwrap_or(DEFAULT_RETURN_TO),
+	))
+}
+
+/// Route for `/forward_auth`
+pub fn forward_auth_router() -> Router<AppState> {
+	Router::new().route("/forward_auth", any(handle_forward_auth))
+}
+
+async fn handle_forward_auth(
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+	State(state): State<AppState>,
+) -> Response {
+	let forwarded = ForwardedRequest::from_headers(&headers);
+	let decision = check_forwarded(&state, ip, &headers, forwarded.return_to()).await;
+	if matches!(decision, Decision::Challenge(_)) {
+		tracing::debug!(
+			"challenging {} {}{}",
+			forwarded.method,
+			forwarded.host.as_deref().unwrap_or("-"),
+			forwarded.uri.as_deref().unwrap_or("")
+		);
+	}
+	decision.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+	use crate::jwt::issue_jwt;
+	use crate::routing::create_router;
+	use crate::values::COOKIE_NAME;
+	use axum::{body::Body, http::Request};
+	use tower::ServiceExt;
+
+	fn forward_request(method: &str, uri: &str, cookie: Option<&str>) -> Request<Body> {
+		let mut request = Request::builder()
+			.uri("/forward_auth")
+			.header(FORWARDED_METHOD_HEADER, method)
+			.header(FORWARDED_HOST_HEADER, "app.example.com")
+			.header(FORWARDED_URI_HEADER, uri);
+		if let Some(token) = cookie {
+			request = request.header(header::COOKIE, format!("{}={}", COOKIE_NAME, token));
+		}
+		request.body(Body::empty()).unwrap()
+	}
+
+	async fn body_text(response: Response) -> String {
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		String::from_utf8(body.to_vec()).unwrap()
+	}
+
+	#[test]
+	fn test_return_to_from_forwarded_request() {
+		let mut headers = HeaderMap::new();
+		headers.insert(FORWARDED_URI_HEADER, HeaderValue::from_static("/articles/1?page=2"));
+		let forwarded = ForwardedRequest::from_headers(&headers);
+		assert_eq!(forwarded.method, Method::GET);
+		assert_eq!(forwarded.return_to().as_deref(), Some("/articles/1?page=2"));
+
+		headers.insert(FORWARDED_METHOD_HEADER, HeaderValue::from_static("POST"));
+		assert_eq!(ForwardedRequest::from_headers(&headers).return_to(), None);
+
+		headers.insert(FORWARDED_METHOD_HEADER, HeaderValue::from_static("GET"));
+		headers.insert(FORWARDED_URI_HEADER, HeaderValue::from_static("//evil.example/"));
+		assert_eq!(ForwardedRequest::from_headers(&headers).return_to(), None);
+	}
+
+	#[tokio::test]
+	async fn test_denial_carries_challenge_page() {
+		let app = create_router(AppState::new(Config::default()).unwrap());
+
+		let response = app
+			.clone()
+			.oneshot(forward_request("GET", "/articles/1", None))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+		let html = body_text(response).await;
+		assert!(html.contains("const challenge = "));
+		assert!(html.contains(r#"const returnTo = "/articles/1";"#));
+
+		let response = app.oneshot(forward_request("POST", "/articles/1", None)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		assert!(body_text(response).await.contains(r#"const returnTo = "/";"#));
+	}
+
+	#[tokio::test]
+	async fn test_session_allows() {
+		let state = AppState::new(Config::default()).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let response = create_router(state)
+			.oneshot(forward_request("GET", "/articles/1", Some(&token)))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+}
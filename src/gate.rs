@@ -0,0 +1,297 @@
+//! In-process gate for axum and tower services
+//!
+//! [`PowGateLayer`] puts the gate in front of any service: the endpoints of
+//! [`gate_router`] (`/get_challenge`, `/post_nonce`, `/api/v1/*`, ...) are served
+//! by mpow, every other request is passed to the inner service only with a
+//! valid session cookie. Visitors without one are redirected to the
+//! challenge and come back to the page they asked for once it is solved.
+//!
+//! ```ignore
+//! let app = Router::new()
+//!     .route("/", get(index))
+//!     .layer(PowGateLayer::new(Config::load()?)?);
+//! ```
+
+use axum::{
+	body::Body,
+	http::{header, HeaderValue, Request, StatusCode},
+	response::{IntoResponse, Response},
+	routing::future::RouteFuture,
+	Router,
+};
+use futures_util::future::BoxFuture;
+use std::{
+	convert::Infallible,
+	marker::PhantomData,
+	task::{Context, Poll},
+};
+use tower::{Layer, Service, ServiceExt};
+
+use crate::{
+	client_ip::client_ip,
+	config::Config,
+	redirect::sanitize_return_to,
+	routing::{authenticate, gate_router, spawn_background_tasks, AppState},
+};
+
+/// Layer gating an inner service behind a proof-of-work challenge
+#[derive(Clone)]
+pub struct PowGateLayer {
+	state: AppState,
+}
+
+impl PowGateLayer {
+	/// Gate configured by `config`, with its background tasks started
+	///
+	/// Must be called within a Tokio runtime.
+	pub fn new(config: Config) -> Result<Self, String> {
+		config.validate()?;
+		let state = AppState::new(config)?;
+		spawn_background_tasks(&state);
+		Ok(Self { state })
+	}
+
+	/// Gate sharing `state`, e.g. with a standalone router; starting its
+	/// background tasks is left to the caller
+	pub fn from_state(state: AppState) -> Self {
+		Self { state }
+	}
+
+	pub fn state(&self) -> &AppState {
+		&self.state
+	}
+}
+
+impl<S> Layer<S> for PowGateLayer
+where
+	S: Service<Request<Body>, Error = Infallible> + Clone + Send + Sync + 'static,
+	S::Response: IntoResponse,
+	S::Future: Send + 'static,
+{
+	type Service = PowGate<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		let protected = RequireSession {
+			inner,
+			state: self.state.clone(),
+		};
+		PowGate {
+			router: gate_router(self.state.clone()).fallback_service(protected),
+			_inner: PhantomData,
+		}
+	}
+}
+
+/// Service produced by [`PowGateLayer`]
+pub struct PowGate<S> {
+	router: Router,
+	_inner: PhantomData<fn() -> S>,
+}
+
+impl<S> Clone for PowGate<S> {
+	fn clone(&self) -> Self {
+		Self {
+			router: self.router.clone(),
+			_inner: PhantomData,
+		}
+	}
+}
+
+impl<S> Service<Request<Body>> for PowGate<S> {
+	type Response = Response;
+	type Error = Infallible;
+	type Future = RouteFuture<Infallible>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Service::<Request<Body>>::poll_ready(&mut self.router, cx)
+	}
+
+	fn call(&mut self, request: Request<Body>) -> Self::Future {
+		self.router.call(request)
+	}
+}
+
+/// Passes requests with a valid session to `inner`, sends the rest to the challenge
+#[derive(Clone)]
+struct RequireSession<S> {
+	inner: S,
+	state: AppState,
+}
+
+impl<S> Service<Request<Body>> for RequireSession<S>
+where
+	S: Service<Request<Body>, Error = Infallible> + Clone + Send + 'static,
+	S::Response: IntoResponse,
+	S::Future: Send + 'static,
+{
+	type Response = Response;
+	type Error = Infallible;
+	type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+	fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		// `inner` is driven to readiness in `call`
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, request: Request<Body>) -> Self::Future {
+		let ip = client_ip(&self.state.proxies, request.extensions(), request.headers());
+		let session = match authenticate(&self.state, ip, request.headers()) {
+			Ok(Some(session)) => session,
+			Ok(None) => {
+				let response = challenge_redirect(&request);
+				return Box::pin(async move { Ok(response) });
+			}
+			Err(status) => return Box::pin(async move { Ok(status.into_response()) }),
+		};
+
+		let inner = self.inner.clone();
+		Box::pin(async move {
+			let mut response = inner.oneshot(request).await?.into_response();
+			if let Some(cookie) = session.refreshed_cookie.and_then(|c| HeaderValue::from_str(&c).ok()) {
+				response.headers_mut().append(header::SET_COOKIE, cookie);
+			}
+			Ok(response)
+		})
+	}
+}
+
+/// Redirect to the challenge, returning to the requested page afterwards
+fn challenge_redirect(request: &Request<Body>) -> Response {
+	let requested = request.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+	let location = match sanitize_return_to(requested) {
+		Some(return_to) => format!(
+			"/get_challenge?return_to={}",
+			url::form_urlencoded::byte_serialize(return_to.as_bytes()).collect::<String>()
+		),
+		None => String::from("/get_challenge"),
+	};
+	(
+		StatusCode::SEE_OTHER,
+		[(header::LOCATION, location)],
+		"Proof of work required. Redirecting to challenge...",
+	)
+		.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jwt::issue_jwt;
+	use crate::revocation::Revocation;
+	use crate::values::COOKIE_NAME;
+	use axum::{http::Method, routing::get};
+
+	fn gated_app(state: &AppState) -> Router {
+		Router::new()
+			.route("/articles/{id}", get(|| async { "article" }))
+			.layer(PowGateLayer::from_state(state.clone()))
+	}
+
+	fn get_request(uri: &str, cookie: Option<&str>) -> Request<Body> {
+		let mut request = Request::builder().method(Method::GET).uri(uri);
+		if let Some(token) = cookie {
+			request = request.header(header::COOKIE, format!("{}={}", COOKIE_NAME, token));
+		}
+		request.body(Body::empty()).unwrap()
+	}
+
+	async fn body_text(response: Response) -> String {
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		String::from_utf8(body.to_vec()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_redirects_without_session() {
+		let state = AppState::new(Config::default()).unwrap();
+		let response = gated_app(&state)
+			.oneshot(get_request("/articles/7?page=2", None))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		assert_eq!(
+			response.headers()[header::LOCATION],
+			"/get_challenge?return_to=%2Farticles%2F7%3Fpage%3D2"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_serves_gate_endpoints() {
+		let state = AppState::new(Config::default()).unwrap();
+		let response = gated_app(&state)
+			.oneshot(get_request("/get_challenge?return_to=%2Farticles%2F7", None))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(body_text(response).await.contains(r#"const returnTo = "/articles/7";"#));
+	}
+
+	#[tokio::test]
+	async fn test_leaves_other_auth_endpoints_to_inner_service() {
+		let state = AppState::new(Config::default()).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let inner = tower::service_fn(|request: Request<Body>| async move {
+			Ok::<_, Infallible>(format!("inner {}", request.uri().path()))
+		});
+		let gate = PowGateLayer::from_state(state).layer(inner);
+
+		for path in [
+			"/validate",
+			"/forward_auth",
+			"/ext_authz/articles",
+			"/envoy.service.auth.v3.Authorization/Check",
+			"/admin/revoke",
+		] {
+			let response = gate.clone().oneshot(get_request(path, None)).await.unwrap();
+			assert_eq!(response.status(), StatusCode::SEE_OTHER, "{}", path);
+			let response = gate.clone().oneshot(get_request(path, Some(&token))).await.unwrap();
+			assert_eq!(body_text(response).await, format!("inner {}", path));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_serves_admin_with_token() {
+		let mut config = Config::default();
+		config.admin.token = Some(String::from("secret"));
+		let state = AppState::new(config).unwrap();
+		let request = Request::builder()
+			.method(Method::POST)
+			.uri("/admin/revoke")
+			.body(Body::empty())
+			.unwrap();
+		let response = gated_app(&state).oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	}
+
+	#[tokio::test]
+	async fn test_passes_valid_session() {
+		let state = AppState::new(Config::default()).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let app = gated_app(&state);
+
+		let response = app.clone().oneshot(get_request("/articles/7", Some(&token))).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(body_text(response).await, "article");
+
+		let jti = crate::jwt::validate_jwt(&token, &state.keyring.read().unwrap(), &state.config.session.token_scope())
+			.unwrap()
+			.jti;
+		state.revocations.write().unwrap().revoke(Revocation::Jti(jti), 0).unwrap();
+		let response = app.oneshot(get_request("/articles/7", Some(&token))).await.unwrap();
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	}
+
+	#[tokio::test]
+	async fn test_wraps_plain_tower_service() {
+		let state = AppState::new(Config::default()).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let inner = tower::service_fn(|_: Request<Body>| async { Ok::<_, Infallible>("inner") });
+		let gate = PowGateLayer::from_state(state).layer(inner);
+
+		let response = gate.clone().oneshot(get_request("/", None)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let response = gate.oneshot(get_request("/", Some(&token))).await.unwrap();
+		assert_eq!(body_text(response).await, "inner");
+	}
+}
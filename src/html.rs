@@ -1,5 +1,7 @@
 use html_escape::encode_text;
 
+use crate::pow::{Algorithm, Target};
+
 pub const STYLE_CSS: &str = r#"
 :root {
   --color-primary: #1e90ff;
@@ -106,12 +108,85 @@ pub const JS_SCRIPT: &str = r#"
 
   // Improved worker code with better buffer management
   const workerCode = `
+      async function pbkdf2(password, salt, length) {
+        const key = await crypto.subtle.importKey("raw", password, "PBKDF2", false, ["deriveBits"]);
+        const bits = await crypto.subtle.deriveBits(
+          { name: "PBKDF2", hash: "SHA-256", salt, iterations: 1 }, key, length * 8);
+        return new Uint8Array(bits);
+      }
+
+      const rotl = (a, b) => (a << b) | (a >>> (32 - b));
+
+      function salsa208(B, x) {
+        x.set(B);
+        for (let i = 0; i < 8; i += 2) {
+          x[4] ^= rotl(x[0] + x[12], 7); x[8] ^= rotl(x[4] + x[0], 9);
+          x[12] ^= rotl(x[8] + x[4], 13); x[0] ^= rotl(x[12] + x[8], 18);
+          x[9] ^= rotl(x[5] + x[1], 7); x[13] ^= rotl(x[9] + x[5], 9);
+          x[1] ^= rotl(x[13] + x[9], 13); x[5] ^= rotl(x[1] + x[13], 18);
+          x[14] ^= rotl(x[10] + x[6], 7); x[2] ^= rotl(x[14] + x[10], 9);
+          x[6] ^= rotl(x[2] + x[14], 13); x[10] ^= rotl(x[6] + x[2], 18);
+          x[3] ^= rotl(x[15] + x[11], 7); x[7] ^= rotl(x[3] + x[15], 9);
+          x[11] ^= rotl(x[7] + x[3], 13); x[15] ^= rotl(x[11] + x[7], 18);
+          x[1] ^= rotl(x[0] + x[3], 7); x[2] ^= rotl(x[1] + x[0], 9);
+          x[3] ^= rotl(x[2] + x[1], 13); x[0] ^= rotl(x[3] + x[2], 18);
+          x[6] ^= rotl(x[5] + x[4], 7); x[7] ^= rotl(x[6] + x[5], 9);
+          x[4] ^= rotl(x[7] + x[6], 13); x[5] ^= rotl(x[4] + x[7], 18);
+          x[11] ^= rotl(x[10] + x[9], 7); x[8] ^= rotl(x[11] + x[10], 9);
+          x[9] ^= rotl(x[8] + x[11], 13); x[10] ^= rotl(x[9] + x[8], 18);
+          x[12] ^= rotl(x[15] + x[14], 7); x[13] ^= rotl(x[12] + x[15], 9);
+          x[14] ^= rotl(x[13] + x[12], 13); x[15] ^= rotl(x[14] + x[13], 18);
+        }
+        for (let i = 0; i < 16; i++) B[i] += x[i];
+      }
+
+      // scrypt BlockMix: B holds 2r 64-byte blocks, Y is scratch of the same size
+      function blockMix(B, Y, X, tmp, r) {
+        X.set(B.subarray((2 * r - 1) * 16, 2 * r * 16));
+        for (let i = 0; i < 2 * r; i++) {
+          for (let j = 0; j < 16; j++) X[j] ^= B[i * 16 + j];
+          salsa208(X, tmp);
+          Y.set(X, i * 16);
+        }
+        for (let i = 0; i < r; i++) {
+          B.set(Y.subarray(2 * i * 16, (2 * i + 1) * 16), i * 16);
+          B.set(Y.subarray((2 * i + 1) * 16, (2 * i + 2) * 16), (r + i) * 16);
+        }
+      }
+
+      let scryptV = null;
+      async function scrypt(password, salt, logN, r, p) {
+        const N = 1 << logN;
+        const len = 32 * r;
+        const bytes = await pbkdf2(password, salt, 128 * r * p);
+        const view = new DataView(bytes.buffer);
+        const B = new Uint32Array(len * p);
+        for (let i = 0; i < B.length; i++) B[i] = view.getUint32(i * 4, true);
+        if (!scryptV || scryptV.length !== N * len) scryptV = new Uint32Array(N * len);
+        const V = scryptV, Y = new Uint32Array(len), X = new Uint32Array(16), tmp = new Uint32Array(16);
+        for (let k = 0; k < p; k++) {
+          const block = B.subarray(k * len, (k + 1) * len);
+          for (let i = 0; i < N; i++) {
+            V.set(block, i * len);
+            blockMix(block, Y, X, tmp, r);
+          }
+          for (let i = 0; i < N; i++) {
+            const j = block[(2 * r - 1) * 16] & (N - 1);
+            for (let m = 0; m < len; m++) block[m] ^= V[j * len + m];
+            blockMix(block, Y, X, tmp, r);
+          }
+        }
+        for (let i = 0; i < B.length; i++) view.setUint32(i * 4, B[i], true);
+        return (await pbkdf2(password, bytes, 32)).buffer;
+      }
+
     self.onmessage = async function(e) {
-      const { challenge, difficultyPrefix, startNonce, chunkSize, workerId } = e.data;
+      const { challenge, algorithm, target, startNonce, chunkSize, workerId } = e.data;
       
       const encoder = new TextEncoder();
       const challengeBuf = encoder.encode(challenge);
-      const difficultyLen = difficultyPrefix.length;
+      // Solutions are digests no larger than the target, compared as big-endian numbers
+      const targetBytes = new Uint8Array(target.match(/../g).map(h => parseInt(h, 16)));
 
       // Pre-allocate buffer to avoid repeated allocations
       const maxNonceLen = 20; // Reasonable max for nonce string length
@@ -130,23 +205,17 @@ pub const JS_SCRIPT: &str = r#"
           const nonceBytes = encoder.encode(nonceStr);
           workBuffer.set(nonceBytes, challengeBuf.length);
           
-          const hashBuffer = await crypto.subtle.digest("SHA-256", 
-            workBuffer.subarray(0, challengeBuf.length + nonceBytes.length));
+          const input = workBuffer.slice(0, challengeBuf.length + nonceBytes.length);
+          const hashBuffer = algorithm.name === "scrypt"
+            ? await scrypt(input, challengeBuf, algorithm.logN, algorithm.r, algorithm.p)
+            : await crypto.subtle.digest("SHA-256", input);
           
-          // Fast prefix check without string conversion
+          // Byte-wise comparison against the target; the first differing byte decides
           const hashArray = new Uint8Array(hashBuffer);
           let matches = true;
-          for (let j = 0; j < Math.ceil(difficultyLen / 2); j++) {
-            const byte = hashArray[j];
-            const hex1 = (byte >> 4).toString(16);
-            const hex2 = (byte & 0xf).toString(16);
-            
-            if (j * 2 < difficultyLen && hex1 !== difficultyPrefix[j * 2]) {
-              matches = false;
-              break;
-            }
-            if (j * 2 + 1 < difficultyLen && hex2 !== difficultyPrefix[j * 2 + 1]) {
-              matches = false;
+          for (let j = 0; j < targetBytes.length; j++) {
+            if (hashArray[j] !== targetBytes[j]) {
+              matches = hashArray[j] < targetBytes[j];
               break;
             }
           }
@@ -183,10 +252,11 @@ pub const JS_SCRIPT: &str = r#"
     return worker;
   });
 
-  // Adaptive chunk size based on performance
-  let chunkSize = 5000;
-  const minChunkSize = 1000;
-  const maxChunkSize = 50000;
+  // Adaptive chunk size based on performance; memory-hard hashes are far slower
+  const memoryHard = algorithm.name !== "sha256";
+  let chunkSize = memoryHard ? 2 : 5000;
+  const minChunkSize = memoryHard ? 1 : 1000;
+  const maxChunkSize = memoryHard ? 16 : 50000;
 
   workers.forEach((worker, index) => {
     worker.onmessage = function(e) {
@@ -219,9 +289,9 @@ pub const JS_SCRIPT: &str = r#"
           
           // Adaptive chunk size based on hash rate
           if (hashRate > 0) {
-            if (hashRate < 1000 && chunkSize > minChunkSize) {
+            if (hashRate < (memoryHard ? 2 : 1000) && chunkSize > minChunkSize) {
               chunkSize = Math.max(minChunkSize, chunkSize * 0.8);
-            } else if (hashRate > 5000 && chunkSize < maxChunkSize) {
+            } else if (hashRate > (memoryHard ? 20 : 5000) && chunkSize < maxChunkSize) {
               chunkSize = Math.min(maxChunkSize, chunkSize * 1.2);
             }
           }
@@ -236,7 +306,8 @@ pub const JS_SCRIPT: &str = r#"
         
         worker.postMessage({
           challenge,
-          difficultyPrefix,
+          algorithm,
+          target,
           startNonce,
           chunkSize: Math.floor(chunkSize),
           workerId
@@ -255,6 +326,7 @@ pub const JS_SCRIPT: &str = r#"
       const params = new URLSearchParams();
       params.append('nonce', nonce.toString());
       params.append('token', token);
+      params.append('return_to', returnTo);
       
       const res = await fetch("/post_nonce", {
         method: "POST",
@@ -267,7 +339,7 @@ pub const JS_SCRIPT: &str = r#"
       
       if (res.ok) {
         updateStatus(statusEl.textContent.replace("📤 Submitting...", "") + " ✅ Server accepted!");
-        setTimeout(() => window.location.href = "/validate", 1500);
+        setTimeout(() => window.location.href = returnTo, 1500);
       } else {
         const errorText = await res.text().catch(() => 'Unknown error');
         updateStatus(statusEl.textContent.replace("📤 Submitting...", "") + ` ❌ Server rejected: ${errorText}`);
@@ -288,7 +360,8 @@ pub const JS_SCRIPT: &str = r#"
   workers.forEach((worker, index) => {
     worker.postMessage({
       challenge,
-      difficultyPrefix,
+      algorithm,
+      target,
       startNonce: globalNonce + (index * chunkSize),
       chunkSize: Math.floor(chunkSize),
       workerId: index
@@ -301,10 +374,21 @@ pub const JS_SCRIPT: &str = r#"
 });
 "#;
 
-pub fn generate_challenge_html(token: &str, challenge: &str, difficulty: usize) -> String {
+pub fn generate_challenge_html(
+	token: &str,
+	challenge: &str,
+	difficulty_bits: u32,
+	algorithm: Algorithm,
+	return_to: &str,
+) -> String {
 	let sanitized_challenge = encode_text(challenge);
 	let sanitized_token = encode_text(token);
-	let difficulty_prefix = "0".repeat(difficulty);
+	let target = Target::from_bits(difficulty_bits).to_hex();
+	let algorithm_json = serde_json::to_string(&algorithm).expect("algorithm serializes");
+	// A JSON string cannot end the script element once `<` is escaped
+	let return_to_json = serde_json::to_string(return_to)
+		.expect("string serializes")
+		.replace('<', "\\u003c");
 
 	format!(
 		r#"<!DOCTYPE html>
@@ -322,6 +406,7 @@ pub fn generate_challenge_html(token: &str, challenge: &str, difficulty: usize)
   </a>
 </h1>
 <p>Challenge string: <code>{challenge}</code></p>
+<p>Difficulty: <code>{difficulty_bits} bits</code></p>
 
 <div class="atom">
   <div class="nucleus"></div>
@@ -337,22 +422,27 @@ pub fn generate_challenge_html(token: &str, challenge: &str, difficulty: usize)
 <script>
   const challenge = "{challenge}";
   const token = "{token}";
-  const difficultyPrefix = "{difficulty_prefix}";
+  const algorithm = {algorithm_json};
+  const target = "{target}";
+  const returnTo = {return_to_json};
 </script>
 <script>{js}</script>
 </body>
 </html>"#,
 		challenge = sanitized_challenge,
 		token = sanitized_token,
-		difficulty_prefix = difficulty_prefix,
+		difficulty_bits = difficulty_bits,
+		algorithm_json = algorithm_json,
+		return_to_json = return_to_json,
+		target = target,
 		style = STYLE_CSS,
 		js = JS_SCRIPT,
 	)
 }
 
-pub fn render_challenge_page(challenge: &str, difficulty: &str) -> String {
+pub fn render_challenge_page(challenge: &str, target: &str) -> String {
 	let sanitized_challenge = encode_text(challenge);
-	let sanitized_difficulty = encode_text(difficulty);
+	let sanitized_target = encode_text(target);
 	format!(
 		r#"<!DOCTYPE html>
 <html lang="en">
@@ -383,55 +473,62 @@ pub fn render_challenge_page(challenge: &str, difficulty: &str) -> String {
 
 <script>
   const challenge = "{challenge}";
-  const difficultyPrefix = "{difficulty}";
+  const algorithm = {{ "name": "sha256" }};
+  const target = "{target}";
 </script>
 <script>{js}</script>
 </body>
 </html>"#,
 		challenge = sanitized_challenge,
-		difficulty = sanitized_difficulty,
+		target = sanitized_target,
 		style = STYLE_CSS,
 		js = JS_SCRIPT,
 	)
 }
 
-/// Debug helper
-pub fn demo_html() {
-	println!("html module demo called");
-}
-
 #[cfg(test)]
 mod tests {
 	use super::*;
-	#[test]
-	fn demo_function_exists_html() {
-		demo_html();
-	}
-
 	#[test]
 	fn test_render_challenge_page() {
 		let challenge = "test_challenge";
-		let difficulty = "00";
-		let rendered = render_challenge_page(challenge, difficulty);
+		let target = "00ff";
+		let rendered = render_challenge_page(challenge, target);
 
 		assert!(rendered.contains("test_challenge"));
-		assert!(rendered.contains("00"));
+		assert!(rendered.contains("00ff"));
 		assert!(rendered.contains("<!DOCTYPE html>"));
 		assert!(rendered.contains("<html lang=\"en\">"));
 		assert!(rendered.contains(STYLE_CSS));
 		assert!(rendered.contains(JS_SCRIPT));
 	}
 
+	#[test]
+	fn test_challenge_html_carries_scrypt_parameters() {
+		let algorithm = Algorithm::Scrypt { log_n: 14, r: 8, p: 1 };
+		let rendered = generate_challenge_html("t", "c", 4, algorithm, "/");
+
+		assert!(rendered.contains(r#"const algorithm = {"name":"scrypt","logN":14,"r":8,"p":1};"#));
+	}
+
 	#[test]
 	fn test_generate_challenge_html() {
 		let token = "test_token";
 		let challenge = "test_challenge";
-		let difficulty = 4;
-		let rendered = generate_challenge_html(token, challenge, difficulty);
+		let difficulty_bits = 13;
+		let rendered = generate_challenge_html(token, challenge, difficulty_bits, Algorithm::Sha256, "/");
 
 		assert!(rendered.contains("test_token"));
 		assert!(rendered.contains("test_challenge"));
-		assert!(rendered.contains("0000"));
+		assert!(rendered.contains(&format!("const target = \"{}\"", "0007".to_owned() + &"f".repeat(60))));
 		assert!(rendered.contains("<!DOCTYPE html>"));
 	}
+
+	#[test]
+	fn test_return_to_cannot_close_script() {
+		let rendered = generate_challenge_html("t", "c", 4, Algorithm::Sha256, "/a?x=\"</script><script>");
+
+		assert!(rendered.contains(r#"const returnTo = "/a?x=\"\u003c/script>\u003cscript>";"#));
+		assert_eq!(rendered.matches("</script>").count(), 2);
+	}
 }
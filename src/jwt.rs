@@ -1,15 +1,221 @@
-use jsonwebtoken::{decode, encode, errors::Error, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{
+	decode, decode_header, encode,
+	errors::{Error, ErrorKind},
+	Header, Validation,
+};
+use ipnet::IpNet;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use std::{
+	net::IpAddr,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::values::TOKEN_EXPIRY_SECS;
+use crate::keyring::KeyRing;
+use crate::values::{JWT_AUDIENCE, JWT_ISSUER};
 
-/// JWT Claims structure: subject and expiration
-#[derive(Debug, Serialize, Deserialize)]
+/// Size of generated secrets and the minimum accepted size of configured ones
+const KEY_SIZE_BYTES: usize = 32;
+
+/// JWT Claims structure: subject, expiration, identity, scope and the optional client binding
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
 	pub sub: String,
 	pub exp: usize,
+	/// Issue time; tokens from before the claim existed read as 0
+	#[serde(default)]
+	pub iat: usize,
+	/// Unique token id, used to revoke a single token; kept when the token is refreshed
+	#[serde(default)]
+	pub jti: String,
+	/// Time the challenge behind the session was solved; tokens from before the claim existed read as 0
+	#[serde(default)]
+	pub auth_time: usize,
+	/// Issuer; tokens from before the claim existed read as empty and are never refreshed
+	#[serde(default)]
+	pub iss: String,
+	/// Audience; tokens from before the claim existed read as empty
+	#[serde(default)]
+	pub aud: String,
+	/// Address of the client that solved the challenge, used to revoke by network
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub cip: Option<String>,
+	/// Network prefix of the client that solved the challenge, e.g. `203.0.113.0/24`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub ipp: Option<String>,
+	/// Hash of the `User-Agent` of the client that solved the challenge
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub uah: Option<String>,
+}
+
+impl Claims {
+	/// Claims for a new token with a fresh `jti`, issued now
+	///
+	/// # Arguments
+	/// * `subject` - identifier for the subject
+	/// * `scope` - issuer and audience of the token
+	/// * `expiry_secs` - token lifetime in seconds
+	pub fn new(subject: &str, scope: &TokenScope, expiry_secs: u64) -> Result<Self, String> {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_err(|_| String::from("JWT time calculation failed"))?;
+		let expiration = now
+			.checked_add(Duration::from_secs(expiry_secs))
+			.ok_or_else(|| String::from("JWT time calculation failed"))?;
+		Ok(Self {
+			sub: subject.to_owned(),
+			exp: expiration.as_secs() as usize,
+			iat: now.as_secs() as usize,
+			jti: uuid::Uuid::new_v4().to_string(),
+			auth_time: now.as_secs() as usize,
+			iss: scope.issuer.clone(),
+			aud: scope.audience.clone(),
+			cip: None,
+			ipp: None,
+			uah: None,
+		})
+	}
+
+	/// Start of the session, falling back to `iat` for tokens without `auth_time`
+	pub fn session_start(&self) -> u64 {
+		match self.auth_time {
+			0 => self.iat as u64,
+			auth_time => auth_time as u64,
+		}
+	}
+
+	/// Replacement for a token that is due for refresh
+	///
+	/// # Arguments
+	/// * `policy` - when and how far tokens are extended
+	/// * `now` - current unix time
+	///
+	/// # Returns
+	/// The same claims issued `now` with a later `exp`, or `None` if the token is not
+	/// due yet, its session has reached its maximum lifetime or it has no issuer
+	pub fn refreshed(&self, policy: &RefreshPolicy, now: u64) -> Option<Claims> {
+		if policy.refresh_after_secs == 0 || now < (self.iat as u64).saturating_add(policy.refresh_after_secs) {
+			return None;
+		}
+		// Tokens from before `iss`/`aud` existed run out instead of being re-signed without them
+		if self.iss.is_empty() {
+			return None;
+		}
+		let mut exp = now.saturating_add(policy.token_expiry_secs);
+		if policy.max_session_secs > 0 {
+			exp = exp.min(self.session_start().saturating_add(policy.max_session_secs));
+		}
+		if exp <= self.exp as u64 {
+			return None;
+		}
+		Some(Claims {
+			iat: now as usize,
+			exp: exp as usize,
+			auth_time: self.session_start() as usize,
+			..self.clone()
+		})
+	}
+}
+
+/// Issuer and audience every token carries and validation checks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenScope {
+	pub issuer: String,
+	pub audience: String,
+}
+
+impl Default for TokenScope {
+	fn default() -> Self {
+		Self {
+			issuer: JWT_ISSUER.to_owned(),
+			audience: JWT_AUDIENCE.to_owned(),
+		}
+	}
+}
+
+/// When `/validate` reissues a session token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefreshPolicy {
+	/// Age at which a token is replaced; 0 disables refreshing
+	pub refresh_after_secs: u64,
+	/// Lifetime of the replacement
+	pub token_expiry_secs: u64,
+	/// Time since the challenge was solved after which the session ends; 0 means never
+	pub max_session_secs: u64,
+}
+
+/// How strictly a session token must match the client presenting it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BindingPolicy {
+	/// Tokens are not bound and are accepted from anywhere
+	#[default]
+	Off,
+	/// Tokens are bound; mismatches are logged but accepted
+	Log,
+	/// The `User-Agent` must match; a changed address is only logged
+	UserAgent,
+	/// Both the address prefix and the `User-Agent` must match
+	Strict,
+}
+
+impl std::str::FromStr for BindingPolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"off" => Ok(BindingPolicy::Off),
+			"log" => Ok(BindingPolicy::Log),
+			"user_agent" => Ok(BindingPolicy::UserAgent),
+			"strict" => Ok(BindingPolicy::Strict),
+			_ => Err(String::from("expected one of off, log, user_agent, strict")),
+		}
+	}
+}
+
+impl BindingPolicy {
+	/// Whether a token is accepted given which parts of its binding differ
+	pub fn accepts(self, ip_mismatch: bool, user_agent_mismatch: bool) -> bool {
+		match self {
+			BindingPolicy::Off | BindingPolicy::Log => true,
+			BindingPolicy::UserAgent => !user_agent_mismatch,
+			BindingPolicy::Strict => !ip_mismatch && !user_agent_mismatch,
+		}
+	}
+}
+
+/// What a session token is bound to: the client's network prefix and `User-Agent` hash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionBinding {
+	pub ipp: Option<String>,
+	pub uah: Option<String>,
+}
+
+impl SessionBinding {
+	/// Binding of a client
+	///
+	/// # Arguments
+	/// * `ip` - client address, if known
+	/// * `user_agent` - client `User-Agent`, if sent
+	/// * `ipv4_prefix` / `ipv6_prefix` - prefix lengths the address is truncated to
+	pub fn new(ip: Option<IpAddr>, user_agent: Option<&str>, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+		let ipp = ip.and_then(|ip| {
+			let ip = ip.to_canonical();
+			let prefix = if ip.is_ipv4() { ipv4_prefix } else { ipv6_prefix };
+			IpNet::new(ip, prefix).ok().map(|net| net.trunc().to_string())
+		});
+		let uah = user_agent.map(|ua| hex::encode(&Sha256::digest(ua.as_bytes())[..8]));
+		Self { ipp, uah }
+	}
+
+	/// Which parts of the binding in `claims` differ from this one
+	///
+	/// # Returns
+	/// `(ip_mismatch, user_agent_mismatch)`; a token without a claim mismatches it
+	pub fn mismatches(&self, claims: &Claims) -> (bool, bool) {
+		(claims.ipp != self.ipp, claims.uah != self.uah)
+	}
 }
 
 /// Generates a 256-bit (32 bytes) secure random secret key
@@ -17,40 +223,54 @@ pub struct Claims {
 /// # Returns
 /// A `Vec<u8>` containing the secret key bytes
 pub fn generate_secret() -> Vec<u8> {
-	const KEY_SIZE_BYTES: usize = 32;
 	let mut secret_bytes: Vec<u8> = vec![0u8; KEY_SIZE_BYTES];
-	rand::thread_rng().fill_bytes(&mut secret_bytes);
-	return secret_bytes;
+	rand::rng().fill_bytes(&mut secret_bytes);
+	secret_bytes
+}
+
+/// Decodes a hex-encoded secret key
+///
+/// # Returns
+/// `Ok(Vec<u8>)` with the key bytes or `Err(String)` if it is malformed or shorter than 256 bits
+pub fn decode_secret(encoded: &str) -> Result<Vec<u8>, String> {
+	let secret = hex::decode(encoded.trim()).map_err(|e| format!("JWT secret is not valid hex: {}", e))?;
+	if secret.len() < KEY_SIZE_BYTES {
+		return Err(format!(
+			"JWT secret must be at least {} bytes, got {}",
+			KEY_SIZE_BYTES,
+			secret.len()
+		));
+	}
+	Ok(secret)
 }
 
-/// Issues a JWT containing a subject and expiration
+/// Issues a JWT for `subject` with the default issuer and audience
 ///
 /// # Arguments
 /// * `subject` - identifier for the subject
-/// * `secret_key` - HMAC secret key bytes
+/// * `keyring` - keys to sign with
+/// * `expiry_secs` - token lifetime in seconds
 ///
 /// # Returns
 /// `Ok(String)` containing the encoded JWT or `Err(String)` on failure
-pub fn issue_jwt(subject: &str, secret_key: &[u8]) -> Result<String, String> {
-	let now = SystemTime::now();
-	let expiration = match now
-		.checked_add(Duration::from_secs(TOKEN_EXPIRY_SECS))
-		.and_then(|ts| ts.duration_since(UNIX_EPOCH).ok())
-	{
-		Some(duration) => duration.as_secs() as usize,
-		None => return Err(String::from("JWT time calculation failed")),
-	};
+pub fn issue_jwt(subject: &str, keyring: &KeyRing, expiry_secs: u64) -> Result<String, String> {
+	sign_jwt(&Claims::new(subject, &TokenScope::default(), expiry_secs)?, keyring)
+}
 
-	let claims: Claims = Claims {
-		sub: subject.to_owned(),
-		exp: expiration,
+/// Encodes `claims` as a JWT
+///
+/// The token is signed with the keyring's current key and carries its `kid` in the header.
+///
+/// # Returns
+/// `Ok(String)` containing the encoded JWT or `Err(String)` on failure
+pub fn sign_jwt(claims: &Claims, keyring: &KeyRing) -> Result<String, String> {
+	let key = keyring.current();
+	let header = Header {
+		kid: Some(key.kid.clone()),
+		..Header::new(key.algorithm.jwt_algorithm())
 	};
 
-	match encode(
-		&Header::default(),
-		&claims,
-		&EncodingKey::from_secret(secret_key),
-	) {
+	match encode(&header, claims, &key.encoding_key()) {
 		Ok(token) => Ok(token),
 		Err(e) => Err(format!("JWT encoding failed: {}", e)),
 	}
@@ -58,45 +278,72 @@ pub fn issue_jwt(subject: &str, secret_key: &[u8]) -> Result<String, String> {
 
 /// Validates a JWT and extracts its claims
 ///
+/// The key is picked by the token's `kid`: the current key or a retired key whose
+/// deadline has not passed. Tokens without a `kid` are checked against the current key.
+/// The token's `alg` must match the algorithm of the selected key, and its
+/// `iss` and `aud` those of `scope`. Tokens issued before those claims existed
+/// carry neither and are accepted until they expire; lacking `jti` and `cip` as
+/// well, they are only matched by `issued_before` revocations.
+///
 /// # Arguments
 /// * `token` - JWT string
-/// * `secret_key` - HMAC secret key bytes
+/// * `keyring` - keys accepted for verification
+/// * `scope` - required issuer and audience
 ///
 /// # Returns
 /// `Ok(Claims)` if valid or `Err(Error)` from the jsonwebtoken crate
-pub fn validate_jwt(token: &str, secret_key: &[u8]) -> Result<Claims, Error> {
-	let validator: Validation = Validation::default();
-	let decoded = decode::<Claims>(token, &DecodingKey::from_secret(secret_key), &validator)?;
-	return Ok(decoded.claims);
+pub fn validate_jwt(token: &str, keyring: &KeyRing, scope: &TokenScope) -> Result<Claims, Error> {
+	let header = decode_header(token)?;
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	let key = match header.kid {
+		Some(kid) => keyring
+			.find(&kid, now)
+			.ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?,
+		None => keyring.current(),
+	};
+
+	// Only `exp` is required, so `iss` and `aud` are checked when present
+	let mut validator: Validation = Validation::new(key.algorithm.jwt_algorithm());
+	validator.set_issuer(&[&scope.issuer]);
+	validator.set_audience(&[&scope.audience]);
+	let decoded = decode::<Claims>(token, &key.decoding_key(), &validator)?;
+	Ok(decoded.claims)
 }
 
 /// Generates an expired token (used for testing)
-fn create_expired_token(subject: &str, secret: &[u8]) -> String {
+#[cfg(test)]
+fn create_expired_token(subject: &str, keyring: &KeyRing) -> String {
 	let claims: Claims = Claims {
-		sub: subject.to_owned(),
 		exp: 0, // Epoch start time (always expired)
+		..Claims::new(subject, &TokenScope::default(), 0).unwrap()
 	};
-	encode(
-		&Header::default(),
-		&claims,
-		&EncodingKey::from_secret(secret),
-	)
+	let key = keyring.current();
+	let header = Header {
+		kid: Some(key.kid.clone()),
+		..Header::new(key.algorithm.jwt_algorithm())
+	};
+	encode(&header, &claims, &key.encoding_key())
 	.expect("Failed to encode expired test token")
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use jsonwebtoken::errors::ErrorKind;
+	use crate::keyring::{KeyAlgorithm, SigningKey};
+	use crate::values::TOKEN_EXPIRY_SECS;
+	use jsonwebtoken::{DecodingKey, EncodingKey};
 
 	#[test]
 	fn test_issue_and_validate() {
-		let secret: Vec<u8> = generate_secret();
+		let keyring = KeyRing::new(generate_secret());
 		let subject: &str = "test_user";
 
-		let token: String = issue_jwt(subject, &secret).expect("JWT issuance should succeed");
+		let token: String = issue_jwt(subject, &keyring, TOKEN_EXPIRY_SECS).expect("JWT issuance should succeed");
 
-		let claims: Claims = validate_jwt(&token, &secret).expect("JWT validation should succeed");
+		let claims: Claims = validate_jwt(&token, &keyring, &TokenScope::default()).expect("JWT validation should succeed");
 
 		assert_eq!(claims.sub, subject);
 
@@ -109,9 +356,9 @@ mod tests {
 
 	#[test]
 	fn test_expired_token() {
-		let secret = generate_secret();
-		let token = create_expired_token("expired_user", &secret);
-		let err = validate_jwt(&token, &secret).expect_err("Should fail on expired token");
+		let keyring = KeyRing::new(generate_secret());
+		let token = create_expired_token("expired_user", &keyring);
+		let err = validate_jwt(&token, &keyring, &TokenScope::default()).expect_err("Should fail on expired token");
 
 		match *err.kind() {
 			ErrorKind::ExpiredSignature => (),
@@ -121,12 +368,12 @@ mod tests {
 
 	#[test]
 	fn test_invalid_signature() {
-		let secret1 = generate_secret();
-		let secret2 = generate_secret();
+		let keyring1 = KeyRing::new(generate_secret());
+		let keyring2 = KeyRing::new(generate_secret());
 		let subject = "user_signature";
 
-		let token = issue_jwt(subject, &secret1).unwrap();
-		let err = validate_jwt(&token, &secret2).expect_err("Should fail with wrong signature");
+		let token = issue_jwt(subject, &keyring1, TOKEN_EXPIRY_SECS).unwrap();
+		let err = validate_jwt(&token, &keyring2, &TokenScope::default()).expect_err("Should fail with wrong signature");
 
 		match *err.kind() {
 			ErrorKind::InvalidSignature => (),
@@ -134,12 +381,208 @@ mod tests {
 		}
 	}
 	#[test]
-	fn demo_function_exists_jwt() {
-		demo_jwt();
+	fn test_decode_secret() {
+		let secret = generate_secret();
+		assert_eq!(decode_secret(&hex::encode(&secret)).unwrap(), secret);
+		assert!(decode_secret("not hex").is_err());
+		assert!(decode_secret("abcd").is_err(), "short keys must be rejected");
 	}
-}
 
-/// Debug helper
-pub fn demo_jwt() {
-	println!("jwt module demo called");
+	#[test]
+	fn test_token_carries_kid() {
+		let keyring = KeyRing::new(generate_secret());
+		let token = issue_jwt("user", &keyring, TOKEN_EXPIRY_SECS).unwrap();
+		let header = decode_header(&token).unwrap();
+		assert_eq!(header.kid.as_deref(), Some(keyring.current().kid.as_str()));
+	}
+
+	#[test]
+	fn test_retired_key_accepted_until_deadline() {
+		let mut keyring = KeyRing::new(generate_secret());
+		let token = issue_jwt("user", &keyring, TOKEN_EXPIRY_SECS).unwrap();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+		keyring.rotate(SigningKey::new(generate_secret(), now), now, 3600);
+		assert!(validate_jwt(&token, &keyring, &TokenScope::default()).is_ok(), "token signed with retired key should validate");
+
+		keyring.rotate(SigningKey::new(generate_secret(), now), now + 7200, 3600);
+		let err = validate_jwt(&token, &keyring, &TokenScope::default()).expect_err("token past its key's deadline should fail");
+		assert_eq!(*err.kind(), ErrorKind::InvalidSignature);
+	}
+
+	#[test]
+	fn test_token_without_kid_uses_current_key() {
+		let keyring = KeyRing::new(generate_secret());
+		let claims = Claims::new("legacy", &TokenScope::default(), 60).unwrap();
+		let token = encode(
+			&Header::default(),
+			&claims,
+			&EncodingKey::from_secret(&keyring.current().secret),
+		)
+		.unwrap();
+
+		assert_eq!(validate_jwt(&token, &keyring, &TokenScope::default()).unwrap().sub, "legacy");
+	}
+
+	#[test]
+	fn test_asymmetric_algorithms() {
+		for algorithm in [KeyAlgorithm::EdDsa, KeyAlgorithm::Es256] {
+			let keyring = KeyRing::with_key(SigningKey::generate(algorithm, 0).unwrap());
+			let token = issue_jwt("user", &keyring, TOKEN_EXPIRY_SECS).unwrap();
+
+			let header = decode_header(&token).unwrap();
+			assert_eq!(header.alg, algorithm.jwt_algorithm());
+			assert_eq!(validate_jwt(&token, &keyring, &TokenScope::default()).unwrap().sub, "user");
+
+			let other = KeyRing::with_key(SigningKey::generate(algorithm, 0).unwrap());
+			assert!(validate_jwt(&token, &other, &TokenScope::default()).is_err());
+		}
+	}
+
+	#[test]
+	fn test_public_key_alone_verifies_token() {
+		let keyring = KeyRing::with_key(SigningKey::generate(KeyAlgorithm::EdDsa, 0).unwrap());
+		let token = issue_jwt("user", &keyring, TOKEN_EXPIRY_SECS).unwrap();
+
+		let jwk = keyring.current().jwk().unwrap();
+		let decoding = DecodingKey::from_ed_components(jwk["x"].as_str().unwrap()).unwrap();
+		let mut validation = Validation::new(jsonwebtoken::Algorithm::EdDSA);
+		validation.set_audience(&[JWT_AUDIENCE]);
+		let claims = decode::<Claims>(&token, &decoding, &validation).unwrap().claims;
+		assert_eq!(claims.sub, "user");
+	}
+
+	#[test]
+	fn test_algorithm_confusion_rejected() {
+		let keyring = KeyRing::with_key(SigningKey::generate(KeyAlgorithm::EdDsa, 0).unwrap());
+		let key = keyring.current();
+		let forged_claims = Claims::new("forged", &TokenScope::default(), 60).unwrap();
+		// HS256 token keyed with the published public key
+		let header = Header {
+			kid: Some(key.kid.clone()),
+			..Header::default()
+		};
+		let forged = encode(&header, &forged_claims, &EncodingKey::from_secret(&key.public)).unwrap();
+
+		let err = validate_jwt(&forged, &keyring, &TokenScope::default()).expect_err("alg mismatch must be rejected");
+		assert_eq!(*err.kind(), ErrorKind::InvalidAlgorithm);
+	}
+
+	#[test]
+	fn test_bound_token_claims() {
+		let keyring = KeyRing::new(generate_secret());
+		let ip = "203.0.113.77".parse().ok();
+		let binding = SessionBinding::new(ip, Some("Firefox"), 24, 64);
+		assert_eq!(binding.ipp.as_deref(), Some("203.0.113.0/24"));
+
+		let mut claims = Claims::new("user", &TokenScope::default(), TOKEN_EXPIRY_SECS).unwrap();
+		claims.ipp = binding.ipp.clone();
+		claims.uah = binding.uah.clone();
+		let claims = validate_jwt(&sign_jwt(&claims, &keyring).unwrap(), &keyring, &TokenScope::default()).unwrap();
+		assert_eq!(binding.mismatches(&claims), (false, false));
+
+		let same_net = SessionBinding::new("203.0.113.9".parse().ok(), Some("Firefox"), 24, 64);
+		assert_eq!(same_net.mismatches(&claims), (false, false));
+		let elsewhere = SessionBinding::new("198.51.100.1".parse().ok(), Some("curl/8"), 24, 64);
+		assert_eq!(elsewhere.mismatches(&claims), (true, true));
+
+		let v6 = SessionBinding::new("2001:db8:1:2:3::4".parse().ok(), None, 24, 48);
+		assert_eq!(v6.ipp.as_deref(), Some("2001:db8:1::/48"));
+
+		let unbound = Claims::new("user", &TokenScope::default(), 60).unwrap();
+		assert_eq!(binding.mismatches(&unbound), (true, true));
+	}
+
+	#[test]
+	fn test_binding_policy() {
+		assert!(BindingPolicy::Log.accepts(true, true));
+		assert!(BindingPolicy::UserAgent.accepts(true, false));
+		assert!(!BindingPolicy::UserAgent.accepts(false, true));
+		assert!(!BindingPolicy::Strict.accepts(true, false));
+		assert!(BindingPolicy::Strict.accepts(false, false));
+		assert_eq!("user_agent".parse::<BindingPolicy>(), Ok(BindingPolicy::UserAgent));
+	}
+
+	#[test]
+	fn test_identity_and_scope_claims() {
+		let keyring = KeyRing::new(generate_secret());
+		let scope = TokenScope::default();
+		let first = validate_jwt(&issue_jwt("user", &keyring, 60).unwrap(), &keyring, &scope).unwrap();
+		let second = validate_jwt(&issue_jwt("user", &keyring, 60).unwrap(), &keyring, &scope).unwrap();
+		assert_ne!(first.jti, second.jti);
+		assert!(first.iat > 0 && first.iat < first.exp);
+		assert_eq!(first.iss, JWT_ISSUER);
+
+		let other = TokenScope {
+			issuer: String::from("mpow"),
+			audience: String::from("other-site"),
+		};
+		let token = issue_jwt("user", &keyring, 60).unwrap();
+		let err = validate_jwt(&token, &keyring, &other).expect_err("foreign audience must be rejected");
+		assert_eq!(*err.kind(), ErrorKind::InvalidAudience);
+	}
+
+	#[test]
+	fn test_tokens_without_scope_accepted_until_expiry() {
+		#[derive(Serialize)]
+		struct LegacyClaims {
+			sub: &'static str,
+			exp: usize,
+			iat: usize,
+		}
+		let keyring = KeyRing::new(generate_secret());
+		let key = keyring.current();
+		let header = Header {
+			kid: Some(key.kid.clone()),
+			..Header::new(key.algorithm.jwt_algorithm())
+		};
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as usize;
+		let legacy = LegacyClaims {
+			sub: "user",
+			exp: now + 60,
+			iat: now - 600,
+		};
+		let token = encode(&header, &legacy, &key.encoding_key()).unwrap();
+
+		let claims = validate_jwt(&token, &keyring, &TokenScope::default()).unwrap();
+		assert_eq!((claims.iss.as_str(), claims.aud.as_str()), ("", ""));
+		let policy = RefreshPolicy {
+			refresh_after_secs: 100,
+			token_expiry_secs: 1_000,
+			max_session_secs: 0,
+		};
+		assert!(claims.refreshed(&policy, now as u64).is_none());
+
+		let expired = LegacyClaims { exp: now - 120, ..legacy };
+		let token = encode(&header, &expired, &key.encoding_key()).unwrap();
+		assert!(validate_jwt(&token, &keyring, &TokenScope::default()).is_err());
+	}
+
+	#[test]
+	fn test_refreshed_claims() {
+		let policy = RefreshPolicy {
+			refresh_after_secs: 100,
+			token_expiry_secs: 1_000,
+			max_session_secs: 2_500,
+		};
+		let claims = Claims {
+			iat: 10_000,
+			exp: 11_000,
+			auth_time: 10_000,
+			..Claims::new("user", &TokenScope::default(), 0).unwrap()
+		};
+		assert!(claims.refreshed(&policy, 10_099).is_none());
+
+		let refreshed = claims.refreshed(&policy, 10_100).unwrap();
+		assert_eq!((refreshed.iat, refreshed.exp, refreshed.auth_time), (10_100, 11_100, 10_000));
+		assert_eq!(refreshed.jti, claims.jti);
+
+		// Capped by the session's maximum lifetime, then no longer extended
+		let late = refreshed.refreshed(&policy, 12_000).unwrap();
+		assert_eq!(late.exp, 12_500);
+		assert!(late.refreshed(&policy, 12_200).is_none());
+
+		let disabled = RefreshPolicy { refresh_after_secs: 0, ..policy };
+		assert!(claims.refreshed(&disabled, 10_900).is_none());
+	}
 }
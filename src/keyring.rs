@@ -0,0 +1,795 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::{
+	rand::SystemRandom,
+	signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::SessionConfig;
+use crate::jwt::{decode_secret, generate_secret};
+
+/// Keyring shared between handlers and the rotation task
+pub type SharedKeyRing = Arc<RwLock<KeyRing>>;
+
+/// JWT signature algorithm of a key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+	/// HMAC-SHA256 with a shared secret
+	#[default]
+	#[serde(rename = "HS256")]
+	Hs256,
+	/// Ed25519 signatures
+	#[serde(rename = "EdDSA")]
+	EdDsa,
+	/// ECDSA on P-256 with SHA-256
+	#[serde(rename = "ES256")]
+	Es256,
+}
+
+/// A signing key and the `kid` it is advertised under
+///
+/// For HS256 `secret` is the HMAC key and `public` is empty; for the
+/// asymmetric algorithms `secret` is the PKCS#8 private key and `public`
+/// the raw public key (the 32-byte Ed25519 key or the uncompressed P-256 point).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKey {
+	pub kid: String,
+	pub algorithm: KeyAlgorithm,
+	pub secret: Vec<u8>,
+	pub public: Vec<u8>,
+	pub created_at: u64,
+}
+
+/// A previous signing key that is still accepted until `retire_at`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetiredKey {
+	pub key: SigningKey,
+	pub retire_at: u64,
+}
+
+/// The current signing key plus the retired keys still inside their grace window
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRing {
+	current: SigningKey,
+	retired: Vec<RetiredKey>,
+}
+
+/// On-disk form of a key: the secret is hex-encoded
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+	kid: String,
+	#[serde(default)]
+	alg: KeyAlgorithm,
+	secret: String,
+	created_at: u64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	retire_at: Option<u64>,
+}
+
+/// On-disk form of the keyring
+#[derive(Serialize, Deserialize)]
+struct StoredKeyRing {
+	current: StoredKey,
+	#[serde(default)]
+	retired: Vec<StoredKey>,
+}
+
+/// Derives a stable key identifier from the key bytes, so replicas sharing a
+/// secret advertise the same `kid` without coordinating
+pub fn key_id(secret: &[u8]) -> String {
+	hex::encode(&Sha256::digest(secret)[..8])
+}
+
+impl std::str::FromStr for KeyAlgorithm {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"HS256" => Ok(KeyAlgorithm::Hs256),
+			"EdDSA" => Ok(KeyAlgorithm::EdDsa),
+			"ES256" => Ok(KeyAlgorithm::Es256),
+			_ => Err(String::from("expected one of HS256, EdDSA, ES256")),
+		}
+	}
+}
+
+impl KeyAlgorithm {
+	/// The matching `jsonwebtoken` algorithm
+	pub fn jwt_algorithm(self) -> Algorithm {
+		match self {
+			KeyAlgorithm::Hs256 => Algorithm::HS256,
+			KeyAlgorithm::EdDsa => Algorithm::EdDSA,
+			KeyAlgorithm::Es256 => Algorithm::ES256,
+		}
+	}
+}
+
+impl SigningKey {
+	/// Creates an HMAC key
+	pub fn new(secret: Vec<u8>, created_at: u64) -> Self {
+		Self {
+			kid: key_id(&secret),
+			algorithm: KeyAlgorithm::Hs256,
+			secret,
+			public: Vec::new(),
+			created_at,
+		}
+	}
+
+	/// Creates a key of `algorithm` from its secret material
+	///
+	/// Asymmetric keys are identified by their public key, so the `kid` can be
+	/// published without revealing anything about the private key.
+	pub fn from_secret(algorithm: KeyAlgorithm, secret: Vec<u8>, created_at: u64) -> Result<Self, String> {
+		let public = match algorithm {
+			KeyAlgorithm::Hs256 => return Ok(Self::new(secret, created_at)),
+			// PKCS#8 v1, as written by `openssl genpkey`, lacks the public key
+			KeyAlgorithm::EdDsa => Ed25519KeyPair::from_pkcs8_maybe_unchecked(&secret)
+				.map_err(|e| format!("invalid Ed25519 PKCS#8 key: {}", e))?
+				.public_key()
+				.as_ref()
+				.to_vec(),
+			KeyAlgorithm::Es256 => {
+				EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &secret, &SystemRandom::new())
+					.map_err(|e| format!("invalid P-256 PKCS#8 key: {}", e))?
+					.public_key()
+					.as_ref()
+					.to_vec()
+			}
+		};
+		Ok(Self {
+			kid: key_id(&public),
+			algorithm,
+			secret,
+			public,
+			created_at,
+		})
+	}
+
+	/// Generates a fresh random key of `algorithm`
+	pub fn generate(algorithm: KeyAlgorithm, created_at: u64) -> Result<Self, String> {
+		let rng = SystemRandom::new();
+		let secret = match algorithm {
+			KeyAlgorithm::Hs256 => generate_secret(),
+			KeyAlgorithm::EdDsa => Ed25519KeyPair::generate_pkcs8(&rng)
+				.map_err(|_| String::from("Ed25519 key generation failed"))?
+				.as_ref()
+				.to_vec(),
+			KeyAlgorithm::Es256 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+				.map_err(|_| String::from("P-256 key generation failed"))?
+				.as_ref()
+				.to_vec(),
+		};
+		Self::from_secret(algorithm, secret, created_at)
+	}
+
+	/// Key used to sign tokens
+	pub fn encoding_key(&self) -> EncodingKey {
+		match self.algorithm {
+			KeyAlgorithm::Hs256 => EncodingKey::from_secret(&self.secret),
+			KeyAlgorithm::EdDsa => EncodingKey::from_ed_der(&self.secret),
+			KeyAlgorithm::Es256 => EncodingKey::from_ec_der(&self.secret),
+		}
+	}
+
+	/// Key used to verify tokens
+	pub fn decoding_key(&self) -> DecodingKey {
+		match self.algorithm {
+			KeyAlgorithm::Hs256 => DecodingKey::from_secret(&self.secret),
+			KeyAlgorithm::EdDsa => DecodingKey::from_ed_der(&self.public),
+			KeyAlgorithm::Es256 => DecodingKey::from_ec_der(&self.public),
+		}
+	}
+
+	/// Public key in JWK form, or `None` for HMAC keys which must never be published
+	pub fn jwk(&self) -> Option<Value> {
+		match self.algorithm {
+			KeyAlgorithm::Hs256 => None,
+			KeyAlgorithm::EdDsa => Some(json!({
+				"kty": "OKP",
+				"crv": "Ed25519",
+				"x": URL_SAFE_NO_PAD.encode(&self.public),
+				"kid": self.kid,
+				"alg": "EdDSA",
+				"use": "sig",
+			})),
+			KeyAlgorithm::Es256 => {
+				// Uncompressed SEC1 point: 0x04 || x || y
+				let (x, y) = self.public[1..].split_at(32);
+				Some(json!({
+					"kty": "EC",
+					"crv": "P-256",
+					"x": URL_SAFE_NO_PAD.encode(x),
+					"y": URL_SAFE_NO_PAD.encode(y),
+					"kid": self.kid,
+					"alg": "ES256",
+					"use": "sig",
+				}))
+			}
+		}
+	}
+}
+
+impl KeyRing {
+	/// Creates a keyring whose current key is the HMAC key `secret`
+	pub fn new(secret: Vec<u8>) -> Self {
+		Self::with_key(SigningKey::new(secret, current_timestamp()))
+	}
+
+	/// Creates a keyring with a single current key
+	pub fn with_key(current: SigningKey) -> Self {
+		Self {
+			current,
+			retired: Vec::new(),
+		}
+	}
+
+	/// Resolves the keyring from the session configuration
+	///
+	/// An inline `jwt_secret` (the HMAC key, or a PKCS#8 private key for the
+	/// asymmetric algorithms) wins over `jwt_secret_file`. With neither set, a
+	/// random key is generated that only lives as long as the process.
+	/// Configured `retired_keys` are added on top in every case.
+	///
+	/// If the stored current key does not use `jwt_algorithm`, a key of the
+	/// configured algorithm takes over and the stored one is retired after the
+	/// usual grace window.
+	pub fn from_config(session: &SessionConfig) -> Result<Self, String> {
+		let algorithm = session.jwt_algorithm;
+		let now = current_timestamp();
+		let mut ring = if let Some(encoded) = &session.jwt_secret {
+			Self::with_key(SigningKey::from_secret(algorithm, decode_secret(encoded)?, now)?)
+		} else if let Some(path) = &session.jwt_secret_file {
+			let mut ring = Self::load_or_create(path, algorithm)?;
+			if ring.current.algorithm != algorithm {
+				ring.rotate(SigningKey::generate(algorithm, now)?, now, session.rotation_grace());
+				ring.store(path)?;
+			}
+			ring
+		} else {
+			tracing::warn!("no JWT secret configured; sessions will not survive a restart");
+			Self::with_key(SigningKey::generate(algorithm, now)?)
+		};
+
+		for retired in &session.retired_keys {
+			let key = SigningKey::from_secret(retired.algorithm, decode_secret(&retired.secret)?, 0)?;
+			ring.add_retired(key, retired.retire_at);
+		}
+		Ok(ring)
+	}
+
+	/// The key new tokens are signed with
+	pub fn current(&self) -> &SigningKey {
+		&self.current
+	}
+
+	/// Retired keys still held by the ring, including ones past their deadline
+	/// that have not been pruned yet
+	pub fn retired(&self) -> &[RetiredKey] {
+		&self.retired
+	}
+
+	/// Looks up a key accepted for verification at `now`
+	pub fn find(&self, kid: &str, now: u64) -> Option<&SigningKey> {
+		if self.current.kid == kid {
+			return Some(&self.current);
+		}
+		self.retired
+			.iter()
+			.find(|r| r.key.kid == kid && r.retire_at > now)
+			.map(|r| &r.key)
+	}
+
+	/// Adds a key that is accepted until `retire_at`
+	pub fn add_retired(&mut self, key: SigningKey, retire_at: u64) {
+		if key.kid == self.current.kid || self.retired.iter().any(|r| r.key.kid == key.kid) {
+			return;
+		}
+		self.retired.push(RetiredKey { key, retire_at });
+	}
+
+	/// Public keys of every key still accepted at `now`, as a JWKS document
+	pub fn jwks(&self, now: u64) -> Value {
+		let keys: Vec<Value> = std::iter::once(&self.current)
+			.chain(self.retired.iter().filter(|r| r.retire_at > now).map(|r| &r.key))
+			.filter_map(SigningKey::jwk)
+			.collect();
+		json!({ "keys": keys })
+	}
+
+	/// Makes `key` the current key and keeps the old one for `grace_secs`
+	pub fn rotate(&mut self, key: SigningKey, now: u64, grace_secs: u64) {
+		let previous = std::mem::replace(&mut self.current, key);
+		self.retired.push(RetiredKey {
+			key: previous,
+			retire_at: now.saturating_add(grace_secs),
+		});
+		self.prune(now);
+	}
+
+	/// Drops retired keys whose deadline has passed
+	pub fn prune(&mut self, now: u64) {
+		self.retired.retain(|r| r.retire_at > now);
+	}
+
+	/// Reads the keyring stored at `path`, generating and writing a new one if the file does not exist
+	///
+	/// A file holding a single hex-encoded secret (the format written by earlier
+	/// versions) is accepted as the current HMAC key. New files get a key of
+	/// `algorithm` and are created with owner-only permissions; if several
+	/// replicas start at once, the first writer wins and the others read its keyring.
+	pub fn load_or_create(path: &Path, algorithm: KeyAlgorithm) -> Result<Self, String> {
+		match Self::load(path) {
+			Ok(ring) => return Ok(ring),
+			Err(LoadError::NotFound) => {}
+			Err(LoadError::Invalid(e)) => return Err(e),
+		}
+
+		let ring = Self::with_key(SigningKey::generate(algorithm, current_timestamp())?);
+		match write_new(path, &ring.to_json()?) {
+			Ok(()) => Ok(ring),
+			Err(e) if e.kind() == ErrorKind::AlreadyExists => Self::load_or_create(path, algorithm),
+			Err(e) => Err(format!("cannot create JWT key file {}: {}", path.display(), e)),
+		}
+	}
+
+	/// Writes the keyring to `path`, replacing the previous file atomically
+	pub fn store(&self, path: &Path) -> Result<(), String> {
+		let tmp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+		write_new(&tmp, &self.to_json()?)
+			.and_then(|_| fs::rename(&tmp, path))
+			.map_err(|e| {
+				let _ = fs::remove_file(&tmp);
+				format!("cannot write JWT key file {}: {}", path.display(), e)
+			})
+	}
+
+	fn load(path: &Path) -> Result<Self, LoadError> {
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Err(LoadError::NotFound),
+			Err(e) => {
+				return Err(LoadError::Invalid(format!(
+					"cannot read JWT key file {}: {}",
+					path.display(),
+					e
+				)))
+			}
+		};
+
+		let parsed = if contents.trim_start().starts_with('{') {
+			Self::from_json(&contents)
+		} else {
+			decode_secret(&contents).map(Self::new)
+		};
+		parsed.map_err(|e| LoadError::Invalid(format!("{}: {}", path.display(), e)))
+	}
+
+	fn to_json(&self) -> Result<String, String> {
+		let stored = StoredKeyRing {
+			current: StoredKey::from_key(&self.current, None),
+			retired: self
+				.retired
+				.iter()
+				.map(|r| StoredKey::from_key(&r.key, Some(r.retire_at)))
+				.collect(),
+		};
+		serde_json::to_string_pretty(&stored).map_err(|e| format!("cannot encode keyring: {}", e))
+	}
+
+	fn from_json(contents: &str) -> Result<Self, String> {
+		let stored: StoredKeyRing =
+			serde_json::from_str(contents).map_err(|e| format!("invalid keyring: {}", e))?;
+		let mut ring = Self {
+			current: stored.current.into_key()?,
+			retired: Vec::new(),
+		};
+		for key in stored.retired {
+			let retire_at = key.retire_at.unwrap_or(0);
+			ring.add_retired(key.into_key()?, retire_at);
+		}
+		Ok(ring)
+	}
+}
+
+impl StoredKey {
+	fn from_key(key: &SigningKey, retire_at: Option<u64>) -> Self {
+		Self {
+			kid: key.kid.clone(),
+			alg: key.algorithm,
+			secret: hex::encode(&key.secret),
+			created_at: key.created_at,
+			retire_at,
+		}
+	}
+
+	fn into_key(self) -> Result<SigningKey, String> {
+		let key = SigningKey::from_secret(self.alg, decode_secret(&self.secret)?, self.created_at)?;
+		if key.kid != self.kid {
+			return Err(format!("key {} does not match its kid", self.kid));
+		}
+		Ok(key)
+	}
+}
+
+enum LoadError {
+	NotFound,
+	Invalid(String),
+}
+
+fn write_new(path: &Path, contents: &str) -> std::io::Result<()> {
+	if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+		fs::create_dir_all(parent)?;
+	}
+
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+
+	let mut file = options.open(path)?;
+	writeln!(file, "{}", contents)?;
+	file.sync_all()
+}
+
+/// Rotates the current key once it is older than `interval_secs`
+///
+/// When the keyring is backed by a file, the file is re-read on every tick so
+/// that rotations made by another replica (or through the admin endpoint) are
+/// picked up, and each rotation is written back for the others to see.
+pub fn spawn_rotation(
+	ring: SharedKeyRing,
+	path: Option<PathBuf>,
+	interval_secs: u64,
+	grace_secs: u64,
+) -> tokio::task::JoinHandle<()> {
+	let tick = Duration::from_secs(interval_secs.clamp(1, 60));
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(tick);
+		loop {
+			ticker.tick().await;
+			let (ring, path) = (ring.clone(), path.clone());
+			let rotated = tokio::task::spawn_blocking(move || rotate_if_due(&ring, path.as_deref(), interval_secs, grace_secs))
+				.await
+				.unwrap_or_else(|e| Err(format!("rotation task failed: {}", e)));
+			if let Err(e) = rotated {
+				tracing::error!("JWT key rotation failed: {}", e);
+			}
+		}
+	})
+}
+
+fn rotate_if_due(
+	ring: &SharedKeyRing,
+	path: Option<&Path>,
+	interval_secs: u64,
+	grace_secs: u64,
+) -> Result<(), String> {
+	let now = current_timestamp();
+	let mut next = ring.read().map_err(|_| String::from("keyring lock poisoned"))?.clone();
+	let seen = next.clone();
+
+	if let Some(path) = path {
+		if let Ok(on_disk) = KeyRing::load(path) {
+			merge_from_disk(&mut next, on_disk, now, grace_secs);
+		}
+	}
+
+	if now.saturating_sub(next.current.created_at) < interval_secs {
+		next.prune(now);
+		// Nothing was stored; a keyring changed meanwhile is picked up on the next tick
+		let mut guard = ring.write().map_err(|_| String::from("keyring lock poisoned"))?;
+		if *guard == seen {
+			*guard = next;
+		}
+		return Ok(());
+	}
+	let key = SigningKey::generate(next.current.algorithm, now)?;
+	next.rotate(key, now, grace_secs);
+	if let Some(path) = path {
+		next.store(path)?;
+	}
+	tracing::info!("rotated JWT signing key, new kid {}", next.current.kid);
+	install(ring, &seen, next, now, grace_secs).map(|_| ())
+}
+
+/// Rotates to a fresh key, writing the keyring to `path` before it takes effect
+///
+/// Keys other replicas rotated to are merged from the file first, so they are
+/// not dropped from it. The file is written without holding the lock, so token
+/// checks are never blocked on disk IO, and a failed write leaves the keyring in
+/// use untouched.
+///
+/// # Returns
+/// The keyring now in use, or `Err(String)` if the key could not be generated or stored
+pub fn rotate_and_store(ring: &SharedKeyRing, path: Option<&Path>, grace_secs: u64) -> Result<KeyRing, String> {
+	let now = current_timestamp();
+	let seen = ring.read().map_err(|_| String::from("keyring lock poisoned"))?.clone();
+	let mut next = seen.clone();
+	if let Some(path) = path {
+		if let Ok(on_disk) = KeyRing::load(path) {
+			merge_from_disk(&mut next, on_disk, now, grace_secs);
+		}
+	}
+	next.rotate(SigningKey::generate(next.current.algorithm, now)?, now, grace_secs);
+	if let Some(path) = path {
+		next.store(path)?;
+	}
+	install(ring, &seen, next, now, grace_secs)
+}
+
+/// Puts `next`, already stored, in place of the shared keyring
+///
+/// If the keyring changed since `seen` was read, e.g. through a reload or another
+/// rotation, `next` is merged into it as if read from disk: the stored key is
+/// used from now on and the keys that landed meanwhile stay accepted.
+///
+/// # Returns
+/// The keyring now in use
+fn install(ring: &SharedKeyRing, seen: &KeyRing, next: KeyRing, now: u64, grace_secs: u64) -> Result<KeyRing, String> {
+	let mut guard = ring.write().map_err(|_| String::from("keyring lock poisoned"))?;
+	if *guard == *seen {
+		*guard = next;
+	} else {
+		merge_from_disk(&mut guard, next, now, grace_secs);
+	}
+	Ok(guard.clone())
+}
+
+/// Adopts the on-disk keyring while keeping any locally known keys it lacks
+fn merge_from_disk(ring: &mut KeyRing, on_disk: KeyRing, now: u64, grace_secs: u64) {
+	if on_disk == *ring {
+		return;
+	}
+	let local = std::mem::replace(ring, on_disk);
+	ring.add_retired(local.current, now.saturating_add(grace_secs));
+	for r in local.retired {
+		ring.add_retired(r.key, r.retire_at);
+	}
+}
+
+fn current_timestamp() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::RetiredKeyConfig;
+
+	fn temp_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("mpow-{}-{}", name, uuid::Uuid::new_v4()))
+	}
+
+	#[test]
+	fn test_key_id_is_stable() {
+		let secret = generate_secret();
+		assert_eq!(key_id(&secret), key_id(&secret));
+		assert_ne!(key_id(&secret), key_id(&generate_secret()));
+		assert_eq!(key_id(&secret).len(), 16);
+	}
+
+	#[test]
+	fn test_rotate_keeps_previous_key_until_deadline() {
+		let mut ring = KeyRing::new(generate_secret());
+		let old_kid = ring.current().kid.clone();
+
+		ring.rotate(SigningKey::new(generate_secret(), 1_000), 1_000, 100);
+
+		assert_ne!(ring.current().kid, old_kid);
+		assert!(ring.find(&old_kid, 1_099).is_some());
+		assert!(ring.find(&old_kid, 1_100).is_none());
+
+		ring.prune(1_100);
+		assert!(ring.retired().is_empty());
+	}
+
+	#[test]
+	fn test_key_file_created_and_reused() {
+		let path = temp_path("keyring").join("jwt.key");
+
+		let first = KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).expect("key file should be created");
+		let second = KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).expect("key file should be read back");
+		assert_eq!(first, second);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = fs::metadata(&path).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+
+		fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+
+	#[test]
+	fn test_store_round_trips_retired_keys() {
+		let path = temp_path("keyring-store");
+		let mut ring = KeyRing::new(generate_secret());
+		ring.rotate(SigningKey::new(generate_secret(), 0), current_timestamp(), 3600);
+
+		ring.store(&path).unwrap();
+		let loaded = KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap();
+		assert_eq!(loaded, ring);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_failed_store_keeps_keyring() {
+		let ring: SharedKeyRing = Arc::new(RwLock::new(KeyRing::new(generate_secret())));
+		let before = ring.read().unwrap().clone();
+		// A regular file where the key file's directory should be
+		let blocker = temp_path("keyring-blocker");
+		fs::write(&blocker, "").unwrap();
+
+		assert!(rotate_and_store(&ring, Some(&blocker.join("jwt.key")), 60).is_err());
+		assert_eq!(*ring.read().unwrap(), before);
+		fs::remove_file(&blocker).unwrap();
+
+		let rotated = rotate_and_store(&ring, None, 60).unwrap();
+		assert_ne!(rotated.current().kid, before.current().kid);
+		assert_eq!(*ring.read().unwrap(), rotated);
+	}
+
+	#[test]
+	fn test_admin_rotation_keeps_other_replicas_keys() {
+		let path = temp_path("keyring-admin-shared");
+		let ring_a: SharedKeyRing = Arc::new(RwLock::new(KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap()));
+		let ring_b: SharedKeyRing = Arc::new(RwLock::new(KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap()));
+
+		let from_a = rotate_and_store(&ring_a, Some(&path), 3600).unwrap();
+		let from_b = rotate_and_store(&ring_b, Some(&path), 3600).unwrap();
+		let on_disk = KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap();
+		assert_eq!(on_disk.current().kid, from_b.current().kid);
+		assert!(on_disk.find(&from_a.current().kid, current_timestamp()).is_some());
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_install_after_concurrent_change() {
+		let path = temp_path("keyring-conflict");
+		let ring: SharedKeyRing = Arc::new(RwLock::new(KeyRing::new(generate_secret())));
+		let now = current_timestamp();
+		let seen = ring.read().unwrap().clone();
+		let mut next = seen.clone();
+		next.rotate(SigningKey::generate(KeyAlgorithm::Hs256, now).unwrap(), now, 3600);
+		next.store(&path).unwrap();
+
+		// Another rotation lands between the store and the swap
+		let meanwhile = rotate_and_store(&ring, None, 3600).unwrap();
+		let installed = install(&ring, &seen, next.clone(), now, 3600).unwrap();
+		assert_eq!(installed.current().kid, next.current().kid);
+		assert_eq!(KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap().current().kid, installed.current().kid);
+		assert!(installed.find(&meanwhile.current().kid, now).is_some());
+		assert!(installed.find(&seen.current().kid, now).is_some());
+		assert_eq!(*ring.read().unwrap(), installed);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_legacy_hex_key_file() {
+		let path = temp_path("keyring-legacy");
+		let secret = generate_secret();
+		fs::write(&path, format!("{}\n", hex::encode(&secret))).unwrap();
+
+		let ring = KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap();
+		assert_eq!(ring.current().secret, secret);
+		assert_eq!(ring.current().kid, key_id(&secret));
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_from_config_adds_retired_keys() {
+		let current = generate_secret();
+		let retired = generate_secret();
+		let session = SessionConfig {
+			jwt_secret: Some(hex::encode(&current)),
+			jwt_secret_file: Some(temp_path("unused")),
+			retired_keys: vec![RetiredKeyConfig {
+				algorithm: KeyAlgorithm::Hs256,
+				secret: hex::encode(&retired),
+				retire_at: u64::MAX,
+			}],
+			..SessionConfig::default()
+		};
+
+		let ring = KeyRing::from_config(&session).unwrap();
+		assert_eq!(ring.current().secret, current);
+		assert!(ring.find(&key_id(&retired), current_timestamp()).is_some());
+		assert!(!session.jwt_secret_file.unwrap().exists());
+	}
+
+	#[test]
+	fn test_rotation_picks_up_other_replica() {
+		let path = temp_path("keyring-shared");
+		let ring_a: SharedKeyRing = Arc::new(RwLock::new(KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap()));
+		let ring_b: SharedKeyRing = Arc::new(RwLock::new(KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap()));
+		let original_kid = ring_a.read().unwrap().current().kid.clone();
+
+		rotate_if_due(&ring_a, Some(&path), 0, 3600).unwrap();
+		let rotated_kid = ring_a.read().unwrap().current().kid.clone();
+		assert_ne!(rotated_kid, original_kid);
+
+		rotate_if_due(&ring_b, Some(&path), 3600, 3600).unwrap();
+		let ring_b = ring_b.read().unwrap();
+		assert_eq!(ring_b.current().kid, rotated_kid);
+		assert!(ring_b.find(&original_kid, current_timestamp()).is_some());
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_jwks_lists_only_public_keys() {
+		let now = current_timestamp();
+		let mut ring = KeyRing::with_key(SigningKey::generate(KeyAlgorithm::EdDsa, now).unwrap());
+		let retired_kid = ring.current().kid.clone();
+		ring.rotate(SigningKey::generate(KeyAlgorithm::Es256, now).unwrap(), now, 3600);
+		ring.add_retired(SigningKey::new(generate_secret(), 0), u64::MAX);
+
+		let jwks = ring.jwks(now);
+		let keys = jwks["keys"].as_array().unwrap();
+		assert_eq!(keys.len(), 2, "HMAC keys must not be published");
+		assert_eq!(keys[0]["kty"], "EC");
+		assert_eq!(keys[0]["kid"], ring.current().kid.as_str());
+		assert_eq!(keys[1]["kty"], "OKP");
+		assert_eq!(keys[1]["kid"], retired_kid.as_str());
+		assert!(keys.iter().all(|k| k.get("d").is_none()));
+
+		assert_eq!(ring.jwks(now + 3600)["keys"].as_array().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn test_openssl_ed25519_key() {
+		use crate::jwt::{sign_jwt, validate_jwt, Claims, TokenScope};
+
+		// `openssl genpkey -algorithm ed25519 -outform DER`, PKCS#8 v1
+		let der = hex::decode("302e020100300506032b6570042204206a6b57bba8c5fa39831a82aefe6a9aed179ad9ab284b42171757b6de8923d130").unwrap();
+		let ring = KeyRing::with_key(SigningKey::from_secret(KeyAlgorithm::EdDsa, der, 0).unwrap());
+		let token = sign_jwt(&Claims::new("user", &TokenScope::default(), 60).unwrap(), &ring).unwrap();
+		assert!(validate_jwt(&token, &ring, &TokenScope::default()).is_ok());
+	}
+
+	#[test]
+	fn test_asymmetric_keyring_round_trips() {
+		let path = temp_path("keyring-eddsa");
+		let ring = KeyRing::load_or_create(&path, KeyAlgorithm::EdDsa).unwrap();
+		assert_eq!(ring.current().algorithm, KeyAlgorithm::EdDsa);
+		assert_eq!(KeyRing::load_or_create(&path, KeyAlgorithm::EdDsa).unwrap(), ring);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_from_config_switches_algorithm() {
+		let path = temp_path("keyring-switch");
+		let hs = KeyRing::load_or_create(&path, KeyAlgorithm::Hs256).unwrap();
+		let session = SessionConfig {
+			jwt_algorithm: KeyAlgorithm::Es256,
+			jwt_secret_file: Some(path.clone()),
+			..SessionConfig::default()
+		};
+
+		let ring = KeyRing::from_config(&session).unwrap();
+		assert_eq!(ring.current().algorithm, KeyAlgorithm::Es256);
+		assert!(ring.find(&hs.current().kid, current_timestamp()).is_some());
+		assert_eq!(KeyRing::load_or_create(&path, KeyAlgorithm::Es256).unwrap(), ring);
+		fs::remove_file(&path).unwrap();
+	}
+}
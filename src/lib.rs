@@ -0,0 +1,64 @@
+//! Proof-of-work gate
+//!
+//! Visitors solve a hash puzzle once and get a signed session cookie; requests
+//! without one are sent to the challenge. The gate runs as a service behind
+//! nginx `auth_request` (the `mpow` binary), or embedded in a Rust service.
+//!
+//! [`MpowBuilder`] is the entry point for embedding:
+//!
+//! ```no_run
+//! use mpow::{MpowBuilder, Store};
+//!
+//! # async fn run() -> Result<(), String> {
+//! let gate = MpowBuilder::new()
+//!     .difficulty(18)
+//!     .store(Store::Sharded(16))
+//!     .build()?;
+//!
+//! // Serve the gate on its own...
+//! gate.clone().serve().await?;
+//!
+//! // ...or put it in front of an existing app
+//! gate.spawn_background_tasks();
+//! let app: axum::Router = axum::Router::new()
+//!     .route("/", axum::routing::get(|| async { "hello" }))
+//!     .layer(gate.layer());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Settings without a builder method are reached through
+//! [`MpowBuilder::configure`] or by starting from a loaded [`Config`].
+
+pub mod admin;
+pub mod api;
+pub mod builder;
+pub mod challenges;
+pub mod client_ip;
+pub mod config;
+pub mod difficulty;
+pub mod ext_authz;
+pub mod forward_auth;
+pub mod gate;
+pub mod html;
+pub mod jwt;
+pub mod keyring;
+pub mod pow;
+pub mod proxy;
+pub mod quota;
+pub mod redirect;
+pub mod reputation;
+pub mod revocation;
+pub mod routing;
+pub mod stateless;
+pub mod store;
+pub mod values;
+
+pub use builder::{Mpow, MpowBuilder, Store};
+pub use challenges::Challenge;
+pub use config::Config;
+pub use gate::{PowGate, PowGateLayer};
+pub use jwt::Claims;
+pub use pow::PowAlgorithm;
+pub use routing::{create_router, gate_router, AppState};
+pub use stateless::ChallengeMode;
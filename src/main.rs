@@ -1,9 +1,18 @@
-mod html;
-mod jwt;
-mod routing;
-mod values;
+use mpow::{Config, MpowBuilder};
 
 #[tokio::main]
 async fn main() {
-	routing::start_server().await;
+	tracing_subscriber::fmt::init();
+
+	let gate = match Config::load().and_then(|config| MpowBuilder::from_config(config).build()) {
+		Ok(gate) => gate,
+		Err(e) => {
+			eprintln!("❌ Configuration error: {}", e);
+			std::process::exit(1);
+		}
+	};
+	if let Err(e) = gate.serve().await {
+		eprintln!("❌ {}", e);
+		std::process::exit(1);
+	}
 }
@@ -0,0 +1,293 @@
+//! Proof-of-work verification
+//!
+//! A solution is a nonce such that `SHA-256(challenge || nonce)`, read as a
+//! 256-bit big-endian number, is at most the target. A difficulty of `n` bits
+//! is the target with `n` leading zero bits, so each bit doubles the work.
+//!
+//! With the memory-hard `scrypt` algorithm the digest is instead
+//! `scrypt(password = challenge || nonce, salt = challenge)`, which costs
+//! `128 * r * 2^log_n` bytes of memory per attempt on both sides.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Hash function selected in the configuration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowAlgorithm {
+	#[default]
+	Sha256,
+	Scrypt,
+}
+
+impl std::str::FromStr for PowAlgorithm {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"sha256" => Ok(PowAlgorithm::Sha256),
+			"scrypt" => Ok(PowAlgorithm::Scrypt),
+			_ => Err(String::from("expected one of sha256, scrypt")),
+		}
+	}
+}
+
+/// Hash function with its cost parameters, as handed to the browser solver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum Algorithm {
+	Sha256,
+	Scrypt {
+		#[serde(rename = "logN")]
+		log_n: u8,
+		r: u32,
+		p: u32,
+	},
+}
+
+impl Algorithm {
+	/// Digest a solution is judged on
+	pub fn digest(&self, challenge: &str, nonce: &str) -> [u8; 32] {
+		match *self {
+			Algorithm::Sha256 => digest(challenge, nonce),
+			Algorithm::Scrypt { log_n, r, p } => scrypt_digest(challenge, nonce, log_n, r, p),
+		}
+	}
+
+	pub fn is_memory_hard(&self) -> bool {
+		!matches!(self, Algorithm::Sha256)
+	}
+}
+
+/// Outcome of checking a submitted nonce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+	Valid,
+	Invalid,
+	/// Every memory-hard verification slot is taken; the caller should retry later
+	Busy,
+}
+
+/// Checks solutions, running at most `max_concurrent` memory-hard checks at once
+/// so that submitting nonces cannot exhaust the server's memory or CPU
+pub struct Verifier {
+	algorithm: Algorithm,
+	permits: Arc<Semaphore>,
+}
+
+impl Verifier {
+	pub fn new(algorithm: Algorithm, max_concurrent: usize) -> Self {
+		Self {
+			algorithm,
+			permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+		}
+	}
+
+	/// Verifier whose memory-hard checks all report [`Verdict::Busy`]
+	#[cfg(test)]
+	pub fn saturated(algorithm: Algorithm) -> Self {
+		Self {
+			algorithm,
+			permits: Arc::new(Semaphore::new(0)),
+		}
+	}
+
+	pub fn algorithm(&self) -> Algorithm {
+		self.algorithm
+	}
+
+	/// Checks `nonce` against `challenge` at `bits` of difficulty
+	pub async fn check(&self, challenge: &str, nonce: &str, bits: u32) -> Verdict {
+		let target = Target::from_bits(bits);
+		if !self.algorithm.is_memory_hard() {
+			return verdict(target.is_met_by(&self.algorithm.digest(challenge, nonce)));
+		}
+
+		let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+			return Verdict::Busy;
+		};
+		let algorithm = self.algorithm;
+		let (challenge, nonce) = (challenge.to_owned(), nonce.to_owned());
+		let checked = tokio::task::spawn_blocking(move || {
+			let _permit = permit;
+			target.is_met_by(&algorithm.digest(&challenge, &nonce))
+		})
+		.await;
+		match checked {
+			Ok(valid) => verdict(valid),
+			Err(e) => {
+				tracing::error!("proof-of-work verification failed: {}", e);
+				Verdict::Busy
+			}
+		}
+	}
+}
+
+fn verdict(valid: bool) -> Verdict {
+	if valid {
+		Verdict::Valid
+	} else {
+		Verdict::Invalid
+	}
+}
+
+/// Largest digest accepted as a solution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target([u8; 32]);
+
+impl Target {
+	/// Target requiring at least `bits` leading zero bits
+	pub fn from_bits(bits: u32) -> Self {
+		let mut target = [0xffu8; 32];
+		for (i, byte) in target.iter_mut().enumerate() {
+			let zeros = bits.saturating_sub(8 * i as u32).min(8);
+			*byte = (0xffu16 >> zeros) as u8;
+		}
+		Self(target)
+	}
+
+	pub fn is_met_by(&self, digest: &[u8; 32]) -> bool {
+		digest <= &self.0
+	}
+
+	/// Big-endian hex form handed to the browser solver
+	pub fn to_hex(self) -> String {
+		hex::encode(self.0)
+	}
+}
+
+/// Digest a solution is judged on
+pub fn digest(challenge: &str, nonce: &str) -> [u8; 32] {
+	let mut hasher = Sha256::new();
+	hasher.update(challenge.as_bytes());
+	hasher.update(nonce.as_bytes());
+	hasher.finalize().into()
+}
+
+/// scrypt digest of `challenge || nonce` salted with `challenge`
+///
+/// Parameters must have been validated with [`scrypt_params_valid`].
+pub fn scrypt_digest(challenge: &str, nonce: &str, log_n: u8, r: u32, p: u32) -> [u8; 32] {
+	let params = scrypt::Params::new(log_n, r, p, 32).expect("scrypt parameters are validated");
+	let password = format!("{}{}", challenge, nonce);
+	let mut output = [0u8; 32];
+	scrypt::scrypt(password.as_bytes(), challenge.as_bytes(), &params, &mut output)
+		.expect("output length is valid");
+	output
+}
+
+/// Checks scrypt cost parameters
+pub fn scrypt_params_valid(log_n: u8, r: u32, p: u32) -> Result<(), String> {
+	scrypt::Params::new(log_n, r, p, 32)
+		.map(|_| ())
+		.map_err(|e| format!("invalid scrypt parameters: {}", e))
+}
+
+/// Number of leading zero bits in `digest`
+pub fn leading_zero_bits(digest: &[u8]) -> u32 {
+	let mut bits = 0;
+	for byte in digest {
+		bits += byte.leading_zeros();
+		if *byte != 0 {
+			break;
+		}
+	}
+	bits
+}
+
+/// Checks a nonce against a challenge at `bits` of difficulty with SHA-256
+pub fn meets_difficulty(challenge: &str, nonce: &str, bits: u32) -> bool {
+	Target::from_bits(bits).is_met_by(&digest(challenge, nonce))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_target_from_bits() {
+		assert_eq!(&Target::from_bits(0).to_hex()[..4], "ffff");
+		assert_eq!(&Target::from_bits(4).to_hex()[..4], "0fff");
+		assert_eq!(&Target::from_bits(13).to_hex()[..6], "0007ff");
+		assert_eq!(&Target::from_bits(16).to_hex()[..6], "0000ff");
+		assert_eq!(Target::from_bits(256).to_hex(), "0".repeat(64));
+	}
+
+	#[test]
+	fn test_leading_zero_bits() {
+		assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x80]), 16);
+		assert_eq!(leading_zero_bits(&[0x00, 0x1f]), 11);
+		assert_eq!(leading_zero_bits(&[0xff]), 0);
+		assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+	}
+
+	#[test]
+	fn test_target_matches_zero_bit_count() {
+		for nonce in 0..2000 {
+			let digest = digest("challenge", &nonce.to_string());
+			let zeros = leading_zero_bits(&digest);
+			for bits in 0..12 {
+				assert_eq!(Target::from_bits(bits).is_met_by(&digest), zeros >= bits);
+			}
+		}
+	}
+
+	#[test]
+	fn test_scrypt_rfc7914_vector() {
+		let params = scrypt::Params::new(4, 1, 1, 64).unwrap();
+		let mut output = [0u8; 64];
+		scrypt::scrypt(b"", b"", &params, &mut output).unwrap();
+		assert_eq!(&hex::encode(output)[..32], "77d6576238657b203b19ca42c18a0497");
+		// Same value as the browser solver in html.rs computes
+		assert_eq!(
+			hex::encode(scrypt_digest("challenge", "42", 4, 1, 1)),
+			"d02ef549dee314d0c7785da6028fdcdf51cd101ba5e906cf7ef207b508725864"
+		);
+		assert_eq!(
+			hex::encode(scrypt_digest("challenge", "42", 4, 1, 1)),
+			hex::encode(Algorithm::Scrypt { log_n: 4, r: 1, p: 1 }.digest("challenge", "42"))
+		);
+	}
+
+	#[test]
+	fn test_scrypt_params_validated() {
+		assert!(scrypt_params_valid(14, 8, 1).is_ok());
+		assert!(scrypt_params_valid(14, 0, 1).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_verifier() {
+		let algorithm = Algorithm::Scrypt { log_n: 4, r: 1, p: 1 };
+		let nonce = (0..)
+			.map(|n: u32| n.to_string())
+			.find(|n| leading_zero_bits(&algorithm.digest("abc", n)) >= 4)
+			.unwrap();
+
+		let verifier = Verifier::new(algorithm, 1);
+		assert_eq!(verifier.check("abc", &nonce, 4).await, Verdict::Valid);
+		assert_eq!(verifier.check("abd", "x", 32).await, Verdict::Invalid);
+
+		let sha = Verifier::new(Algorithm::Sha256, 1);
+		assert_eq!(sha.check("abc", &nonce, 200).await, Verdict::Invalid);
+	}
+
+	#[tokio::test]
+	async fn test_verifier_refuses_when_saturated() {
+		let verifier = Verifier::new(Algorithm::Scrypt { log_n: 4, r: 1, p: 1 }, 1);
+		let _held = verifier.permits.clone().try_acquire_owned().unwrap();
+		assert_eq!(verifier.check("abc", "1", 1).await, Verdict::Busy);
+	}
+
+	#[test]
+	fn test_meets_difficulty() {
+		let nonce = (0..)
+			.map(|n: u32| n.to_string())
+			.find(|n| leading_zero_bits(&digest("abc", n)) >= 10)
+			.unwrap();
+		assert!(meets_difficulty("abc", &nonce, 10));
+		assert!(meets_difficulty("abc", &nonce, 9));
+		assert!(!meets_difficulty("abc", &nonce, leading_zero_bits(&digest("abc", &nonce)) + 1));
+	}
+}
@@ -0,0 +1,454 @@
+//! Built-in reverse proxy
+//!
+//! With `proxy.upstream` set, mpow fronts the application itself: requests with
+//! a valid session are forwarded to the upstream with their bodies streamed in
+//! both directions, and websocket upgrades are spliced through once the
+//! upstream accepts them. Everything else goes through the challenge flow as in
+//! [`PowGateLayer`](crate::gate::PowGateLayer), which [`ReverseProxy`] is wrapped in.
+
+use axum::{
+	body::Body,
+	http::{
+		header::{self, HeaderName},
+		uri::PathAndQuery,
+		HeaderMap, HeaderValue, Request, StatusCode, Uri, Version,
+	},
+	response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use hyper_util::{
+	client::legacy::{connect::HttpConnector, Client},
+	rt::{TokioExecutor, TokioIo},
+};
+use std::{
+	convert::Infallible,
+	sync::Arc,
+	task::{Context, Poll},
+};
+use tower::Service;
+
+use crate::{
+	client_ip::{client_ip, TrustedProxies},
+	config::ProxyConfig,
+};
+
+/// Headers describing one connection, never forwarded to the next hop
+const HOP_BY_HOP: [&str; 9] = [
+	"connection",
+	"keep-alive",
+	"proxy-authenticate",
+	"proxy-authorization",
+	"proxy-connection",
+	"te",
+	"trailer",
+	"transfer-encoding",
+	"upgrade",
+];
+
+/// Service forwarding every request to the configured upstream
+#[derive(Clone)]
+pub struct ReverseProxy {
+	client: Client<HttpConnector, Body>,
+	upstream: Arc<Upstream>,
+	proxies: Arc<TrustedProxies>,
+}
+
+struct Upstream {
+	/// `scheme://host:port` of the upstream
+	origin: Uri,
+	/// Path prefix without its trailing slash, possibly empty
+	prefix: String,
+	preserve_host: bool,
+}
+
+impl ReverseProxy {
+	/// # Arguments
+	/// * `config` - proxy settings; `upstream` must be set
+	/// * `proxies` - trusted proxies, to name the client in `X-Forwarded-For`
+	pub fn new(config: &ProxyConfig, proxies: Arc<TrustedProxies>) -> Result<Self, String> {
+		let upstream = config
+			.upstream
+			.as_deref()
+			.ok_or_else(|| String::from("proxy.upstream is not set"))?;
+		let url = url::Url::parse(upstream).map_err(|e| format!("invalid proxy.upstream: {}", e))?;
+		let origin = url
+			.origin()
+			.ascii_serialization()
+			.parse()
+			.map_err(|e| format!("invalid proxy.upstream: {}", e))?;
+		let client = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+		Ok(Self {
+			client,
+			upstream: Arc::new(Upstream {
+				origin,
+				prefix: url.path().trim_end_matches('/').to_owned(),
+				preserve_host: config.preserve_host,
+			}),
+			proxies,
+		})
+	}
+}
+
+impl Service<Request<Body>> for ReverseProxy {
+	type Response = Response;
+	type Error = Infallible;
+	type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+	fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, request: Request<Body>) -> Self::Future {
+		let proxy = self.clone();
+		Box::pin(async move { Ok(proxy.forward(request).await) })
+	}
+}
+
+impl ReverseProxy {
+	async fn forward(self, mut request: Request<Body>) -> Response {
+		let ip = client_ip(&self.proxies, request.extensions(), request.headers());
+		let upgrade = upgrade_protocol(request.headers());
+		// Claimed before the request is handed to the client, which drops extensions
+		let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut request));
+
+		let (mut parts, body) = request.into_parts();
+		// HTTP/2 requests name the host in the URI rather than a `Host` header
+		let original_host = parts.headers.get(header::HOST).cloned().or_else(|| {
+			parts
+				.uri
+				.authority()
+				.and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+		});
+		parts.uri = match self.upstream.uri(&parts.uri) {
+			Ok(uri) => uri,
+			Err(e) => {
+				tracing::warn!("cannot build upstream URI: {}", e);
+				return StatusCode::BAD_GATEWAY.into_response();
+			}
+		};
+		// The upstream is always spoken to over HTTP/1.1, whatever the client used
+		parts.version = Version::HTTP_11;
+		strip_hop_by_hop(&mut parts.headers);
+		parts.headers.remove(header::HOST);
+		if let Some(host) = original_host {
+			if self.upstream.preserve_host {
+				parts.headers.insert(header::HOST, host.clone());
+			}
+			parts.headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+		}
+		if let Some(ip) = ip.and_then(|ip| HeaderValue::from_str(&ip.to_string()).ok()) {
+			parts.headers.insert(HeaderName::from_static("x-forwarded-for"), ip);
+		}
+		if let Some(protocol) = &upgrade {
+			parts.headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+			parts.headers.insert(header::UPGRADE, protocol.clone());
+		}
+
+		let mut response = match self.client.request(Request::from_parts(parts, body)).await {
+			Ok(response) => response,
+			Err(e) => {
+				tracing::warn!("upstream request failed: {}", e);
+				return (StatusCode::BAD_GATEWAY, "Upstream unavailable").into_response();
+			}
+		};
+
+		if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+			let Some(client_upgrade) = client_upgrade else {
+				return (StatusCode::BAD_GATEWAY, "Upstream switched protocols unasked").into_response();
+			};
+			let upstream_upgrade = hyper::upgrade::on(&mut response);
+			tokio::spawn(async move {
+				match tokio::try_join!(client_upgrade, upstream_upgrade) {
+					Ok((client, upstream)) => {
+						let (mut client, mut upstream) = (TokioIo::new(client), TokioIo::new(upstream));
+						let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+					}
+					Err(e) => tracing::warn!("protocol upgrade failed: {}", e),
+				}
+			});
+			let (parts, _) = response.into_parts();
+			return Response::from_parts(parts, Body::empty());
+		}
+
+		let (mut parts, body) = response.into_parts();
+		strip_hop_by_hop(&mut parts.headers);
+		Response::from_parts(parts, Body::new(body))
+	}
+}
+
+impl Upstream {
+	/// Upstream URI for a request to `uri`
+	fn uri(&self, uri: &Uri) -> Result<Uri, String> {
+		let path = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+		let path_and_query: PathAndQuery = format!("{}{}", self.prefix, path)
+			.parse()
+			.map_err(|e| format!("{}", e))?;
+		let mut parts = self.origin.clone().into_parts();
+		parts.path_and_query = Some(path_and_query);
+		Uri::from_parts(parts).map_err(|e| format!("{}", e))
+	}
+}
+
+/// Protocol a request asks to upgrade to, e.g. `websocket`
+fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+	let asks_upgrade = headers
+		.get_all(header::CONNECTION)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+	asks_upgrade.then(|| headers.get(header::UPGRADE).cloned()).flatten()
+}
+
+/// Removes the standard hop-by-hop headers and those named in `Connection`
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+	let named: Vec<String> = headers
+		.get_all(header::CONNECTION)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.map(|token| token.trim().to_ascii_lowercase())
+		.filter(|token| !token.is_empty())
+		.collect();
+	for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+		headers.remove(name);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		config::Config,
+		gate::PowGateLayer,
+		jwt::issue_jwt,
+		routing::AppState,
+		values::COOKIE_NAME,
+	};
+	use axum::{routing::any, Router};
+	use std::net::SocketAddr;
+	use tower::Layer;
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::{TcpListener, TcpStream},
+	};
+
+	/// Upstream echoing the request line, forwarding headers and body
+	async fn stub_upstream() -> SocketAddr {
+		let app = Router::new().fallback(any(|request: Request<Body>| async move {
+			let (parts, body) = request.into_parts();
+			let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+			let header = |name: &str| {
+				parts.headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("-").to_owned()
+			};
+			format!(
+				"{} {} host={} xff={} xfh={} te={} body={}",
+				parts.method,
+				parts.uri,
+				header("host"),
+				header("x-forwarded-for"),
+				header("x-forwarded-host"),
+				header("te"),
+				String::from_utf8_lossy(&body)
+			)
+		}));
+		serve_on_loopback(app).await
+	}
+
+	async fn serve_on_loopback(app: Router) -> SocketAddr {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+				.await
+				.unwrap();
+		});
+		addr
+	}
+
+	/// Gate in front of `upstream`, served on a loopback port
+	async fn proxied_gate(upstream: String) -> (SocketAddr, String) {
+		let mut config = Config::default();
+		config.proxy.upstream = Some(upstream);
+		let state = AppState::new(config).unwrap();
+		let token = issue_jwt("user", &state.keyring.read().unwrap(), 60).unwrap();
+		let proxy = ReverseProxy::new(&state.config.proxy, state.proxies.clone()).unwrap();
+		let gate = PowGateLayer::from_state(state).layer(proxy);
+		let addr = serve_on_loopback(Router::new().fallback_service(gate)).await;
+		(addr, format!("{}={}", COOKIE_NAME, token))
+	}
+
+	/// Sends a raw HTTP/1.1 request and returns the stream with the response head read
+	async fn raw_request(addr: SocketAddr, request: &str) -> (TcpStream, String) {
+		let mut stream = TcpStream::connect(addr).await.unwrap();
+		stream.write_all(request.as_bytes()).await.unwrap();
+		let mut head = Vec::new();
+		while !head.ends_with(b"\r\n\r\n") {
+			let mut byte = [0u8; 1];
+			if stream.read(&mut byte).await.unwrap() == 0 {
+				break;
+			}
+			head.push(byte[0]);
+		}
+		(stream, String::from_utf8(head).unwrap())
+	}
+
+	async fn read_to_end(mut stream: TcpStream) -> String {
+		let mut body = Vec::new();
+		stream.read_to_end(&mut body).await.unwrap();
+		String::from_utf8(body).unwrap()
+	}
+
+	#[test]
+	fn test_upstream_uri() {
+		let config = ProxyConfig {
+			upstream: Some(String::from("http://app:8080/base/")),
+			preserve_host: true,
+		};
+		let proxy = ReverseProxy::new(&config, Arc::new(TrustedProxies::new(Vec::new()))).unwrap();
+		let uri = proxy.upstream.uri(&"/articles/1?page=2".parse().unwrap()).unwrap();
+		assert_eq!(uri, "http://app:8080/base/articles/1?page=2");
+
+		assert!(ReverseProxy::new(&ProxyConfig::default(), Arc::new(TrustedProxies::new(Vec::new()))).is_err());
+	}
+
+	#[test]
+	fn test_strip_hop_by_hop() {
+		let mut headers = HeaderMap::new();
+		headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, x-internal"));
+		headers.insert("x-internal", HeaderValue::from_static("1"));
+		headers.insert(header::TE, HeaderValue::from_static("trailers"));
+		headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+		headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+		strip_hop_by_hop(&mut headers);
+		assert_eq!(headers.len(), 1);
+		assert!(headers.contains_key(header::ACCEPT));
+	}
+
+	#[tokio::test]
+	async fn test_forwards_authorised_requests() {
+		let upstream = stub_upstream().await;
+		let (gate, cookie) = proxied_gate(format!("http://{}/app", upstream)).await;
+
+		let (stream, head) = raw_request(
+			gate,
+			&format!(
+				"POST /articles/1?page=2 HTTP/1.1\r\nHost: example.com\r\nCookie: {}\r\nTE: trailers\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+				cookie
+			),
+		)
+		.await;
+		assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+		assert_eq!(
+			read_to_end(stream).await,
+			"POST /app/articles/1?page=2 host=example.com xff=127.0.0.1 xfh=example.com te=- body=hello"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_forwards_http2_requests() {
+		let upstream = stub_upstream().await;
+		let (gate, cookie) = proxied_gate(format!("http://{}", upstream)).await;
+		let client = Client::builder(TokioExecutor::new())
+			.http2_only(true)
+			.build_http::<Body>();
+
+		let request = Request::builder()
+			.uri(format!("http://{}/articles/1", gate))
+			.header(header::COOKIE, cookie)
+			.body(Body::empty())
+			.unwrap();
+		let response = client.request(request).await.unwrap();
+		assert_eq!((response.version(), response.status()), (Version::HTTP_2, StatusCode::OK));
+		let body = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX)
+			.await
+			.unwrap();
+		assert_eq!(
+			String::from_utf8(body.to_vec()).unwrap(),
+			format!("GET /articles/1 host={} xff=127.0.0.1 xfh={} te=- body=", gate, gate)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_challenges_unauthorised_requests() {
+		let upstream = stub_upstream().await;
+		let (gate, _) = proxied_gate(format!("http://{}", upstream)).await;
+
+		let (_, head) = raw_request(gate, "GET /articles/1 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+		assert!(head.starts_with("HTTP/1.1 303"), "{}", head);
+		assert!(head.contains("location: /get_challenge?return_to=%2Farticles%2F1"));
+
+		let (stream, head) = raw_request(gate, "GET /get_challenge HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+		assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+		assert!(read_to_end(stream).await.contains("const challenge = "));
+	}
+
+	#[tokio::test]
+	async fn test_forwards_paths_of_other_auth_endpoints() {
+		let upstream = stub_upstream().await;
+		let (gate, cookie) = proxied_gate(format!("http://{}", upstream)).await;
+
+		for path in ["/ext_authz/articles", "/admin/revoke", "/envoy.service.auth.v3.Authorization/Check"] {
+			let request = format!(
+				"POST {} HTTP/1.1\r\nHost: example.com\r\nCookie: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+				path, cookie
+			);
+			let (stream, head) = raw_request(gate, &request).await;
+			assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+			assert!(read_to_end(stream).await.starts_with(&format!("POST {} ", path)));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_unreachable_upstream() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let closed = listener.local_addr().unwrap();
+		drop(listener);
+		let (gate, cookie) = proxied_gate(format!("http://{}", closed)).await;
+
+		let request = format!("GET / HTTP/1.1\r\nHost: example.com\r\nCookie: {}\r\nConnection: close\r\n\r\n", cookie);
+		let (_, head) = raw_request(gate, &request).await;
+		assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+	}
+
+	#[tokio::test]
+	async fn test_splices_websocket_upgrades() {
+		// Upstream accepting any upgrade and echoing what follows
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let upstream = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut head = Vec::new();
+			while !head.ends_with(b"\r\n\r\n") {
+				let mut byte = [0u8; 1];
+				stream.read_exact(&mut byte).await.unwrap();
+				head.push(byte[0]);
+			}
+			assert!(String::from_utf8(head).unwrap().to_ascii_lowercase().contains("upgrade: websocket"));
+			stream
+				.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n")
+				.await
+				.unwrap();
+			let mut buf = [0u8; 64];
+			loop {
+				let n = stream.read(&mut buf).await.unwrap();
+				if n == 0 {
+					break;
+				}
+				stream.write_all(&buf[..n]).await.unwrap();
+			}
+		});
+		let (gate, cookie) = proxied_gate(format!("http://{}", upstream)).await;
+
+		let request = format!(
+			"GET /socket HTTP/1.1\r\nHost: example.com\r\nCookie: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+			cookie
+		);
+		let (mut stream, head) = raw_request(gate, &request).await;
+		assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+		stream.write_all(b"ping").await.unwrap();
+		let mut echo = [0u8; 4];
+		stream.read_exact(&mut echo).await.unwrap();
+		assert_eq!(&echo, b"ping");
+	}
+}
@@ -0,0 +1,214 @@
+//! Per-token request quotas
+//!
+//! Every request `/validate` accepts is counted against the token's `jti`: a
+//! lifetime budget (`max_requests`) and a rate (`rate_limit` per
+//! `rate_window_secs`, counted over a sliding window). Once either is used up
+//! the token stops working and the visitor has to solve a new challenge, so the
+//! proof-of-work cost grows with the amount of content fetched.
+//!
+//! Counts are kept per process; replicas behind a load balancer each enforce
+//! the full quota.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::config::QuotaConfig;
+
+/// Seconds between two prunes of a full table
+const PRUNE_INTERVAL_SECS: u64 = 1;
+
+/// Outcome of counting a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaVerdict {
+	Allowed,
+	/// The token's lifetime budget is used up
+	BudgetExhausted,
+	/// The token is being used faster than the configured rate
+	RateExceeded,
+	/// The token has no `jti` to count against, having been issued before the claim existed
+	Unidentified,
+}
+
+/// Requests made with one token
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+	total: u64,
+	/// Index of the current rate window (`now / rate_window_secs`)
+	window: u64,
+	current: u64,
+	previous: u64,
+	/// Token expiry, after which the entry can be dropped
+	expires_at: u64,
+}
+
+impl Usage {
+	fn roll(&mut self, window: u64) {
+		if window == self.window {
+			return;
+		}
+		self.previous = if window == self.window + 1 { self.current } else { 0 };
+		self.current = 0;
+		self.window = window;
+	}
+
+	/// Requests in the last `window_secs`, weighting the previous window by its overlap
+	fn rate(&self, now: u64, window_secs: u64) -> f64 {
+		let elapsed = (now % window_secs) as f64 / window_secs as f64;
+		self.previous as f64 * (1.0 - elapsed) + self.current as f64
+	}
+}
+
+#[derive(Default)]
+struct Table {
+	usage: HashMap<String, Usage>,
+	last_prune: u64,
+}
+
+/// Request counts of recently used tokens
+pub struct QuotaTracker {
+	max_requests: u64,
+	rate_limit: u64,
+	rate_window_secs: u64,
+	max_tracked_tokens: usize,
+	table: Mutex<Table>,
+}
+
+impl QuotaTracker {
+	pub fn new(config: &QuotaConfig) -> Self {
+		Self {
+			max_requests: config.max_requests,
+			rate_limit: config.rate_limit,
+			rate_window_secs: config.rate_window_secs.max(1),
+			max_tracked_tokens: config.max_tracked_tokens,
+			table: Mutex::new(Table::default()),
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.max_requests > 0 || self.rate_limit > 0
+	}
+
+	/// Counts one request made with the token `jti`
+	///
+	/// # Arguments
+	/// * `jti` - id of the token
+	/// * `expires_at` - the token's `exp`
+	/// * `now` - current unix time
+	///
+	/// # Returns
+	/// Whether the request is within the token's quota; refused requests are not counted
+	pub fn check(&self, jti: &str, expires_at: u64, now: u64) -> QuotaVerdict {
+		if !self.is_enabled() {
+			return QuotaVerdict::Allowed;
+		}
+		if jti.is_empty() {
+			return QuotaVerdict::Unidentified;
+		}
+		let Ok(mut table) = self.table.lock() else {
+			return QuotaVerdict::Allowed;
+		};
+
+		if !table.usage.contains_key(jti) && !self.make_room(&mut table, now) {
+			tracing::warn!("quota table full, {} not counted", jti);
+			return QuotaVerdict::Allowed;
+		}
+		let window = now / self.rate_window_secs;
+		let usage = table.usage.entry(jti.to_owned()).or_insert(Usage {
+			total: 0,
+			window,
+			current: 0,
+			previous: 0,
+			expires_at,
+		});
+		usage.roll(window);
+		// A refreshed token keeps its `jti` and counts on
+		usage.expires_at = usage.expires_at.max(expires_at);
+
+		if self.max_requests > 0 && usage.total >= self.max_requests {
+			return QuotaVerdict::BudgetExhausted;
+		}
+		if self.rate_limit > 0 && usage.rate(now, self.rate_window_secs) + 1.0 > self.rate_limit as f64 {
+			return QuotaVerdict::RateExceeded;
+		}
+		usage.total += 1;
+		usage.current += 1;
+		QuotaVerdict::Allowed
+	}
+
+	/// Drops expired tokens if the table is full
+	///
+	/// # Returns
+	/// Whether there is room for another token
+	fn make_room(&self, table: &mut Table, now: u64) -> bool {
+		if table.usage.len() < self.max_tracked_tokens {
+			return true;
+		}
+		if now.saturating_sub(table.last_prune) >= PRUNE_INTERVAL_SECS {
+			table.last_prune = now;
+			table.usage.retain(|_, usage| usage.expires_at > now);
+		}
+		table.usage.len() < self.max_tracked_tokens
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tracker(max_requests: u64, rate_limit: u64) -> QuotaTracker {
+		QuotaTracker::new(&QuotaConfig {
+			max_requests,
+			rate_limit,
+			rate_window_secs: 60,
+			max_tracked_tokens: 2,
+		})
+	}
+
+	#[test]
+	fn test_disabled_by_default() {
+		let tracker = QuotaTracker::new(&QuotaConfig::default());
+		assert!(!tracker.is_enabled());
+		for _ in 0..10_000 {
+			assert_eq!(tracker.check("a", 10_000, 1_000), QuotaVerdict::Allowed);
+		}
+	}
+
+	#[test]
+	fn test_budget() {
+		let tracker = tracker(3, 0);
+		for now in 0..3 {
+			assert_eq!(tracker.check("a", 10_000, 1_000 + now * 1_000), QuotaVerdict::Allowed);
+		}
+		assert_eq!(tracker.check("a", 10_000, 9_000), QuotaVerdict::BudgetExhausted);
+		assert_eq!(tracker.check("b", 10_000, 9_000), QuotaVerdict::Allowed);
+		assert_eq!(tracker.check("", 10_000, 9_000), QuotaVerdict::Unidentified);
+	}
+
+	#[test]
+	fn test_sliding_rate() {
+		let tracker = tracker(0, 10);
+		// Ten requests at the end of one window
+		for _ in 0..10 {
+			assert_eq!(tracker.check("a", 10_000, 1_019), QuotaVerdict::Allowed);
+		}
+		assert_eq!(tracker.check("a", 10_000, 1_019), QuotaVerdict::RateExceeded);
+		// Early in the next window most of them still count
+		assert_eq!(tracker.check("a", 10_000, 1_030), QuotaVerdict::Allowed);
+		assert_eq!(tracker.check("a", 10_000, 1_030), QuotaVerdict::RateExceeded);
+		// Two windows later they are forgotten
+		for _ in 0..10 {
+			assert_eq!(tracker.check("a", 10_000, 1_140), QuotaVerdict::Allowed);
+		}
+	}
+
+	#[test]
+	fn test_table_bounded() {
+		let tracker = tracker(1, 0);
+		tracker.check("a", 1_500, 1_000);
+		tracker.check("b", 5_000, 1_000);
+		// Full: "c" is let through uncounted until "a" expires
+		assert_eq!(tracker.check("c", 5_000, 1_000), QuotaVerdict::Allowed);
+		assert_eq!(tracker.check("c", 5_000, 1_000), QuotaVerdict::Allowed);
+		tracker.check("c", 5_000, 2_000);
+		assert_eq!(tracker.check("c", 5_000, 2_000), QuotaVerdict::BudgetExhausted);
+	}
+}
@@ -0,0 +1,96 @@
+//! Where to send a visitor after a solved challenge
+//!
+//! The page a visitor asked for arrives in the `return_to` query parameter or
+//! the `X-Original-URI` header nginx sets, both under the client's control. Only
+//! local paths are followed: anything that could name another host (`//host`,
+//! `/\host`, a scheme) or break out of a header falls back to `/`.
+
+use axum::http::HeaderMap;
+
+use crate::values::MAX_RETURN_TO_LENGTH;
+
+/// Header nginx passes the original request URI in
+pub const ORIGINAL_URI_HEADER: &str = "x-original-uri";
+
+/// Where visitors without a usable `return_to` end up
+pub const DEFAULT_RETURN_TO: &str = "/";
+
+/// Endpoints that would only lead back to the gate
+const GATE_PATHS: [&str; 4] = ["/get_challenge", "/post_nonce", "/validate", "/forward_auth"];
+
+/// Checks that `uri` is a path on this site
+///
+/// # Returns
+/// `Some(uri)` for a local path of visible ASCII that does not lead back to the gate
+pub fn sanitize_return_to(uri: &str) -> Option<String> {
+	if uri.is_empty() || uri.len() > MAX_RETURN_TO_LENGTH {
+		return None;
+	}
+	if !uri.bytes().all(|b| b.is_ascii_graphic()) || uri.contains('\\') {
+		return None;
+	}
+	let rest = uri.strip_prefix('/')?;
+	if rest.starts_with('/') {
+		return None;
+	}
+	let path = uri.split(['?', '#']).next().unwrap_or_default();
+	if GATE_PATHS.contains(&path) {
+		return None;
+	}
+	Some(uri.to_owned())
+}
+
+/// Page requested before the challenge, preferring an explicit `return_to`
+pub fn requested_return_to(query: Option<&str>, headers: &HeaderMap) -> Option<String> {
+	query
+		.or_else(|| headers.get(ORIGINAL_URI_HEADER).and_then(|v| v.to_str().ok()))
+		.and_then(sanitize_return_to)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_local_paths_accepted() {
+		for uri in ["/", "/articles/123", "/search?q=a%20b&page=2", "/a/b#section", "/%2F%2Fevil.example"] {
+			assert_eq!(sanitize_return_to(uri).as_deref(), Some(uri));
+		}
+	}
+
+	#[test]
+	fn test_open_redirects_rejected() {
+		for uri in [
+			"",
+			"https://evil.example/",
+			"//evil.example",
+			"/\\evil.example",
+			"\\\\evil.example",
+			"javascript:alert(1)",
+			"/a\r\nSet-Cookie: x=1",
+			"/with space",
+			"/caf\u{e9}",
+			"articles/123",
+		] {
+			assert_eq!(sanitize_return_to(uri), None, "{:?}", uri);
+		}
+		assert_eq!(sanitize_return_to(&format!("/{}", "a".repeat(MAX_RETURN_TO_LENGTH))), None);
+	}
+
+	#[test]
+	fn test_gate_paths_rejected() {
+		assert_eq!(sanitize_return_to("/get_challenge"), None);
+		assert_eq!(sanitize_return_to("/validate?x=1"), None);
+		assert!(sanitize_return_to("/get_challenge/help").is_some());
+	}
+
+	#[test]
+	fn test_query_preferred_over_header() {
+		let mut headers = HeaderMap::new();
+		headers.insert(ORIGINAL_URI_HEADER, "/from-nginx".parse().unwrap());
+		assert_eq!(requested_return_to(Some("/explicit"), &headers).as_deref(), Some("/explicit"));
+		assert_eq!(requested_return_to(None, &headers).as_deref(), Some("/from-nginx"));
+		assert_eq!(requested_return_to(Some("//evil.example"), &headers), None);
+		assert_eq!(requested_return_to(None, &HeaderMap::new()), None);
+	}
+}
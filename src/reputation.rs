@@ -0,0 +1,273 @@
+//! Per-client reputation
+//!
+//! Every challenge issued to a client adds a point against its address and its
+//! subnet (/24 for IPv4, /64 for IPv6), every rejected submission adds more and
+//! every solution refunds the point of the challenge it solved, so challenges
+//! that are requested and abandoned keep counting. Points halve every
+//! `half_life_secs`; each `points_per_bit` points make new challenges for the
+//! client one bit harder.
+
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	sync::Mutex,
+};
+
+use crate::config::ReputationConfig;
+
+/// Points for a challenge handed out, refunded when it is solved
+const ISSUED_POINTS: f64 = 1.0;
+/// Points for a wrong nonce or an unknown token
+const FAILED_POINTS: f64 = 2.0;
+/// Scores below this are forgotten when the table is pruned
+const FORGET_BELOW: f64 = 0.5;
+/// Seconds between two prunes of a full table
+const PRUNE_INTERVAL_SECS: u64 = 1;
+
+/// Something a client did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behaviour {
+	Issued,
+	Solved,
+	Failed,
+}
+
+impl Behaviour {
+	fn points(self) -> f64 {
+		match self {
+			Behaviour::Issued => ISSUED_POINTS,
+			Behaviour::Solved => -ISSUED_POINTS,
+			Behaviour::Failed => FAILED_POINTS,
+		}
+	}
+}
+
+/// An address, or the network it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ClientKey {
+	Addr(IpAddr),
+	Subnet(IpAddr),
+}
+
+/// The /24 or /64 containing `ip`
+fn subnet_of(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V4(v4) => {
+			let [a, b, c, _] = v4.octets();
+			IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+		}
+		IpAddr::V6(v6) => {
+			let mask = !0u128 << 64;
+			IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+		}
+	}
+}
+
+/// Score that decays over time
+#[derive(Debug, Clone, Copy)]
+struct Score {
+	points: f64,
+	updated_at: u64,
+}
+
+impl Score {
+	fn decayed(&self, now: u64, half_life_secs: u64) -> f64 {
+		let elapsed = now.saturating_sub(self.updated_at) as f64;
+		self.points * 0.5f64.powf(elapsed / half_life_secs as f64)
+	}
+}
+
+#[derive(Default)]
+struct Table {
+	scores: HashMap<ClientKey, Score>,
+	last_prune: u64,
+}
+
+/// Behaviour history of recent clients
+pub struct Reputation {
+	enabled: bool,
+	half_life_secs: u64,
+	points_per_bit: f64,
+	subnet_points_per_bit: f64,
+	max_extra_bits: u32,
+	max_clients: usize,
+	table: Mutex<Table>,
+}
+
+impl Reputation {
+	pub fn new(config: &ReputationConfig) -> Self {
+		Self {
+			enabled: config.enabled,
+			half_life_secs: config.half_life_secs.max(1),
+			points_per_bit: config.points_per_bit,
+			subnet_points_per_bit: config.subnet_points_per_bit,
+			max_extra_bits: config.max_extra_bits,
+			max_clients: config.max_clients,
+			table: Mutex::new(Table::default()),
+		}
+	}
+
+	/// Adds what `ip` did to its own and its subnet's score
+	pub fn record(&self, ip: Option<IpAddr>, behaviour: Behaviour, now: u64) {
+		let (true, Some(ip)) = (self.enabled, ip) else {
+			return;
+		};
+		let ip = ip.to_canonical();
+		let Ok(mut table) = self.table.lock() else {
+			return;
+		};
+		for key in [ClientKey::Addr(ip), ClientKey::Subnet(subnet_of(ip))] {
+			self.add(&mut table, key, behaviour.points(), now);
+		}
+	}
+
+	/// Extra difficulty bits for a challenge issued to `ip` now
+	pub fn extra_bits(&self, ip: Option<IpAddr>, now: u64) -> u32 {
+		let (true, Some(ip)) = (self.enabled, ip) else {
+			return 0;
+		};
+		let ip = ip.to_canonical();
+		let Ok(table) = self.table.lock() else {
+			return 0;
+		};
+		let score = |key| {
+			table
+				.scores
+				.get(&key)
+				.map_or(0.0, |s: &Score| s.decayed(now, self.half_life_secs))
+		};
+		let own = score(ClientKey::Addr(ip)) / self.points_per_bit;
+		let subnet = score(ClientKey::Subnet(subnet_of(ip))) / self.subnet_points_per_bit;
+		(own.max(subnet) as u32).min(self.max_extra_bits)
+	}
+
+	fn add(&self, table: &mut Table, key: ClientKey, points: f64, now: u64) {
+		if !table.scores.contains_key(&key) {
+			if points <= 0.0 {
+				return;
+			}
+			if table.scores.len() >= self.max_clients && !self.prune(table, now) {
+				return;
+			}
+		}
+		let score = table.scores.entry(key).or_insert(Score {
+			points: 0.0,
+			updated_at: now,
+		});
+		score.points = (score.decayed(now, self.half_life_secs) + points).max(0.0);
+		score.updated_at = now;
+	}
+
+	/// Forgets clients whose score has decayed away
+	///
+	/// # Returns
+	/// Whether there is room for a new client afterwards
+	fn prune(&self, table: &mut Table, now: u64) -> bool {
+		if now.saturating_sub(table.last_prune) >= PRUNE_INTERVAL_SECS {
+			table.last_prune = now;
+			let half_life_secs = self.half_life_secs;
+			table
+				.scores
+				.retain(|_, score| score.decayed(now, half_life_secs) >= FORGET_BELOW);
+		}
+		table.scores.len() < self.max_clients
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn reputation() -> Reputation {
+		Reputation::new(&ReputationConfig {
+			enabled: true,
+			half_life_secs: 100,
+			points_per_bit: 10.0,
+			subnet_points_per_bit: 40.0,
+			max_extra_bits: 3,
+			max_clients: 1_000,
+		})
+	}
+
+	fn ip(s: &str) -> Option<IpAddr> {
+		Some(s.parse().unwrap())
+	}
+
+	#[test]
+	fn test_subnet_of() {
+		assert_eq!(subnet_of("192.0.2.77".parse().unwrap()), "192.0.2.0".parse::<IpAddr>().unwrap());
+		assert_eq!(
+			subnet_of("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+			"2001:db8:1:2::".parse::<IpAddr>().unwrap()
+		);
+	}
+
+	#[test]
+	fn test_solving_clients_stay_clean() {
+		let reputation = reputation();
+		for _ in 0..50 {
+			reputation.record(ip("192.0.2.1"), Behaviour::Issued, 1_000);
+			reputation.record(ip("192.0.2.1"), Behaviour::Solved, 1_000);
+		}
+		assert_eq!(reputation.extra_bits(ip("192.0.2.1"), 1_000), 0);
+	}
+
+	#[test]
+	fn test_abandoning_and_failing_raise_difficulty() {
+		let reputation = reputation();
+		for _ in 0..10 {
+			reputation.record(ip("192.0.2.1"), Behaviour::Issued, 1_000);
+		}
+		assert_eq!(reputation.extra_bits(ip("192.0.2.1"), 1_000), 1);
+		for _ in 0..10 {
+			reputation.record(ip("192.0.2.1"), Behaviour::Failed, 1_000);
+		}
+		assert_eq!(reputation.extra_bits(ip("192.0.2.1"), 1_000), 3);
+		for _ in 0..100 {
+			reputation.record(ip("192.0.2.1"), Behaviour::Failed, 1_000);
+		}
+		assert_eq!(reputation.extra_bits(ip("192.0.2.1"), 1_000), 3);
+		assert_eq!(reputation.extra_bits(ip("198.51.100.1"), 1_000), 0);
+	}
+
+	#[test]
+	fn test_subnet_shares_score() {
+		let reputation = reputation();
+		for host in 0..80 {
+			reputation.record(ip(&format!("192.0.2.{}", host)), Behaviour::Issued, 1_000);
+		}
+		assert_eq!(reputation.extra_bits(ip("192.0.2.200"), 1_000), 2);
+		assert_eq!(reputation.extra_bits(ip("192.0.3.1"), 1_000), 0);
+		assert_eq!(reputation.extra_bits(ip("::ffff:192.0.2.200"), 1_000), 2);
+	}
+
+	#[test]
+	fn test_score_decays() {
+		let reputation = reputation();
+		for _ in 0..40 {
+			reputation.record(ip("2001:db8::1"), Behaviour::Issued, 1_000);
+		}
+		assert_eq!(reputation.extra_bits(ip("2001:db8::1"), 1_000), 3);
+		assert_eq!(reputation.extra_bits(ip("2001:db8::1"), 1_100), 2);
+		assert_eq!(reputation.extra_bits(ip("2001:db8::1"), 1_300), 0);
+	}
+
+	#[test]
+	fn test_bounded_and_disabled() {
+		let mut config = ReputationConfig {
+			enabled: true,
+			max_clients: 4,
+			..ReputationConfig::default()
+		};
+		let reputation = Reputation::new(&config);
+		for host in 0..10 {
+			reputation.record(ip(&format!("10.0.{}.1", host)), Behaviour::Issued, 1_000);
+		}
+		assert!(reputation.table.lock().unwrap().scores.len() <= 4);
+
+		config.enabled = false;
+		let reputation = Reputation::new(&config);
+		reputation.record(ip("10.0.0.1"), Behaviour::Failed, 1_000);
+		assert!(reputation.table.lock().unwrap().scores.is_empty());
+	}
+}
@@ -0,0 +1,381 @@
+//! Server-side revocation of session tokens
+//!
+//! Tokens can be revoked one by one (`jti`), by the network of the client they
+//! were issued to (`cip`), or all at once up to a time. Times are compared with
+//! the start of the session, which a refreshed token keeps, rather than its `iat`.
+//! Each entry only has to outlive the sessions it can match, so entries are
+//! dropped once every such session has ended.
+//!
+//! With `session.revocation_file` set the list is written there on every change
+//! and re-read periodically, so replicas sharing the file see each other's revocations.
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	fs,
+	io::ErrorKind,
+	net::IpAddr,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::jwt::Claims;
+
+/// How often a file-backed list is re-read
+const RELOAD_INTERVAL_SECS: u64 = 60;
+
+/// Times [`revoke_and_store`] redoes its update when the list changed meanwhile
+const REVOKE_ATTEMPTS: usize = 3;
+
+pub type SharedRevocations = Arc<RwLock<Revocations>>;
+
+/// Tokens issued to a network before `revoked_at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevokedNetwork {
+	pub network: IpNet,
+	pub revoked_at: u64,
+}
+
+/// What to revoke, as accepted by `POST /admin/revoke`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Revocation {
+	/// One token by its `jti`
+	Jti(String),
+	/// Every token issued to an address or CIDR network
+	Ip(String),
+	/// Every session started before a unix timestamp
+	IssuedBefore(u64),
+}
+
+impl Revocation {
+	/// Checks that the revocation can be applied
+	///
+	/// # Returns
+	/// `Err(String)` if the `jti` is empty or the address or network cannot be parsed
+	pub fn validate(&self) -> Result<(), String> {
+		match self {
+			Revocation::Jti(jti) if jti.is_empty() => Err(String::from("jti must not be empty")),
+			Revocation::Ip(ip) => parse_network(ip).map(|_| ()),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Parses an address or CIDR network, truncated to its network address
+fn parse_network(ip: &str) -> Result<IpNet, String> {
+	let network = match ip.parse::<IpNet>() {
+		Ok(network) => network,
+		Err(_) => ip
+			.parse::<IpAddr>()
+			.map(IpNet::from)
+			.map_err(|_| format!("invalid address or network {:?}", ip))?,
+	};
+	Ok(network.trunc())
+}
+
+/// Revoked tokens
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revocations {
+	/// `jti` to the time it was revoked
+	#[serde(default)]
+	tokens: HashMap<String, u64>,
+	#[serde(default)]
+	networks: Vec<RevokedNetwork>,
+	/// Sessions started before this time are revoked
+	#[serde(default)]
+	issued_before: u64,
+}
+
+impl Revocations {
+	/// Records a revocation made at `now`
+	///
+	/// # Returns
+	/// `Err(String)` if the revocation is invalid, see [`Revocation::validate`]
+	pub fn revoke(&mut self, revocation: Revocation, now: u64) -> Result<(), String> {
+		revocation.validate()?;
+		match revocation {
+			Revocation::Jti(jti) => {
+				self.tokens.insert(jti, now);
+			}
+			Revocation::Ip(ip) => {
+				self.networks.push(RevokedNetwork {
+					network: parse_network(&ip)?,
+					revoked_at: now,
+				});
+			}
+			Revocation::IssuedBefore(timestamp) => {
+				self.issued_before = self.issued_before.max(timestamp);
+			}
+		}
+		Ok(())
+	}
+
+	pub fn is_revoked(&self, claims: &Claims) -> bool {
+		let session_start = claims.session_start();
+		if session_start < self.issued_before {
+			return true;
+		}
+		if !claims.jti.is_empty() && self.tokens.contains_key(&claims.jti) {
+			return true;
+		}
+		let Some(ip) = claims.cip.as_deref().and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+			return false;
+		};
+		self.networks
+			.iter()
+			.any(|r| session_start <= r.revoked_at && r.network.contains(&ip))
+	}
+
+	/// Forgets entries older than any session that could still match them
+	///
+	/// # Arguments
+	/// * `lifetime_secs` - longest a session lasts, see [`SessionConfig::session_lifetime`](crate::config::SessionConfig::session_lifetime)
+	pub fn prune(&mut self, now: u64, lifetime_secs: u64) {
+		let live = |revoked_at: u64| now.saturating_sub(revoked_at) <= lifetime_secs;
+		self.tokens.retain(|_, revoked_at| live(*revoked_at));
+		self.networks.retain(|r| live(r.revoked_at));
+	}
+
+	/// Adds every revocation in `other`
+	pub fn merge(&mut self, other: Revocations) {
+		for (jti, revoked_at) in other.tokens {
+			let entry = self.tokens.entry(jti).or_insert(revoked_at);
+			*entry = (*entry).max(revoked_at);
+		}
+		for network in other.networks {
+			if !self.networks.contains(&network) {
+				self.networks.push(network);
+			}
+		}
+		self.issued_before = self.issued_before.max(other.issued_before);
+	}
+
+	/// Reads the list stored at `path`; a missing file is an empty list
+	pub fn load(path: &Path) -> Result<Self, String> {
+		match fs::read_to_string(path) {
+			Ok(contents) => serde_json::from_str(&contents)
+				.map_err(|e| format!("invalid revocation file {}: {}", path.display(), e)),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(format!("cannot read revocation file {}: {}", path.display(), e)),
+		}
+	}
+
+	/// Writes the list to `path`, replacing the previous file atomically
+	pub fn store(&self, path: &Path) -> Result<(), String> {
+		let contents =
+			serde_json::to_string(self).map_err(|e| format!("cannot encode revocations: {}", e))?;
+		let tmp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+		fs::write(&tmp, contents)
+			.and_then(|_| fs::rename(&tmp, path))
+			.map_err(|e| {
+				let _ = fs::remove_file(&tmp);
+				format!("cannot write revocation file {}: {}", path.display(), e)
+			})
+	}
+}
+
+/// Records a revocation made at `now`, through the shared file when there is one
+///
+/// The file is read and written without holding the lock, so token checks are
+/// never blocked on disk IO. If the list changes meanwhile, the update is redone
+/// on the new list so that neither change is lost.
+///
+/// # Arguments
+/// * `path` - `session.revocation_file`, if set
+/// * `lifetime_secs` - longest a session lasts, for pruning
+///
+/// # Returns
+/// `Err(String)` if the revocation is invalid, the file cannot be read or written,
+/// or the list kept changing
+pub fn revoke_and_store(
+	revocations: &SharedRevocations,
+	path: Option<&Path>,
+	revocation: &Revocation,
+	now: u64,
+	lifetime_secs: u64,
+) -> Result<(), String> {
+	for _ in 0..REVOKE_ATTEMPTS {
+		let seen = revocations
+			.read()
+			.map_err(|_| String::from("revocation lock poisoned"))?
+			.clone();
+		let mut next = seen.clone();
+		// Keep what other replicas revoked since the last reload
+		if let Some(path) = path {
+			next.merge(Revocations::load(path)?);
+		}
+		next.revoke(revocation.clone(), now)?;
+		next.prune(now, lifetime_secs);
+		if let Some(path) = path {
+			next.store(path)?;
+		}
+
+		let mut guard = revocations
+			.write()
+			.map_err(|_| String::from("revocation lock poisoned"))?;
+		if *guard == seen {
+			*guard = next;
+			return Ok(());
+		}
+	}
+	Err(String::from("revocation list changed during the update, try again"))
+}
+
+/// Periodically merges the revocation file into the in-memory list and prunes it
+pub fn spawn_reload(
+	revocations: SharedRevocations,
+	path: PathBuf,
+	lifetime_secs: u64,
+) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(Duration::from_secs(RELOAD_INTERVAL_SECS));
+		loop {
+			ticker.tick().await;
+			let on_disk = match Revocations::load(&path) {
+				Ok(on_disk) => on_disk,
+				Err(e) => {
+					tracing::error!("{}", e);
+					continue;
+				}
+			};
+			if let Ok(mut guard) = revocations.write() {
+				guard.merge(on_disk);
+				guard.prune(current_timestamp(), lifetime_secs);
+			}
+		}
+	})
+}
+
+fn current_timestamp() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn claims(jti: &str, iat: usize, cip: Option<&str>) -> Claims {
+		Claims {
+			sub: String::from("user"),
+			exp: usize::MAX / 2,
+			iat,
+			jti: jti.to_owned(),
+			auth_time: iat,
+			iss: String::new(),
+			aud: String::new(),
+			cip: cip.map(str::to_owned),
+			ipp: None,
+			uah: None,
+		}
+	}
+
+	#[test]
+	fn test_revoke_single_token() {
+		let mut list = Revocations::default();
+		list.revoke(Revocation::Jti(String::from("a")), 1_000).unwrap();
+		assert!(list.is_revoked(&claims("a", 900, None)));
+		assert!(!list.is_revoked(&claims("b", 900, None)));
+		assert!(list.revoke(Revocation::Jti(String::new()), 1_000).is_err());
+	}
+
+	#[test]
+	fn test_revoke_network() {
+		let mut list = Revocations::default();
+		list.revoke(Revocation::Ip(String::from("203.0.113.0/24")), 1_000).unwrap();
+		list.revoke(Revocation::Ip(String::from("2001:db8::7")), 1_000).unwrap();
+		assert!(list.is_revoked(&claims("a", 900, Some("203.0.113.9"))));
+		assert!(list.is_revoked(&claims("a", 900, Some("2001:db8::7"))));
+		assert!(!list.is_revoked(&claims("a", 900, Some("2001:db8::8"))));
+		assert!(!list.is_revoked(&claims("a", 900, None)));
+		// Solved again after the revocation
+		assert!(!list.is_revoked(&claims("a", 1_001, Some("203.0.113.9"))));
+		assert!(list.revoke(Revocation::Ip(String::from("nope")), 1_000).is_err());
+	}
+
+	#[test]
+	fn test_revoke_issued_before() {
+		let mut list = Revocations::default();
+		list.revoke(Revocation::IssuedBefore(1_000), 2_000).unwrap();
+		list.revoke(Revocation::IssuedBefore(500), 2_000).unwrap();
+		assert!(list.is_revoked(&claims("a", 999, None)));
+		assert!(!list.is_revoked(&claims("a", 1_000, None)));
+	}
+
+	#[test]
+	fn test_refreshed_token_stays_revoked() {
+		let refreshed = Claims {
+			iat: 1_500,
+			..claims("a", 900, Some("203.0.113.9"))
+		};
+		let mut list = Revocations::default();
+		list.revoke(Revocation::IssuedBefore(1_000), 2_000).unwrap();
+		assert!(list.is_revoked(&refreshed));
+
+		let mut list = Revocations::default();
+		list.revoke(Revocation::Ip(String::from("203.0.113.0/24")), 1_000).unwrap();
+		assert!(list.is_revoked(&refreshed));
+	}
+
+	#[test]
+	fn test_prune_and_merge() {
+		let mut list = Revocations::default();
+		list.revoke(Revocation::Jti(String::from("old")), 1_000).unwrap();
+		list.revoke(Revocation::Jti(String::from("new")), 5_000).unwrap();
+		list.prune(5_500, 1_000);
+		assert!(!list.is_revoked(&claims("old", 0, None)));
+		assert!(list.is_revoked(&claims("new", 0, None)));
+
+		let mut other = Revocations::default();
+		other.revoke(Revocation::Ip(String::from("198.51.100.1")), 5_000).unwrap();
+		other.revoke(Revocation::IssuedBefore(10), 5_000).unwrap();
+		list.merge(other.clone());
+		list.merge(other);
+		assert_eq!(list.networks.len(), 1);
+		assert!(list.is_revoked(&claims("x", 9, None)));
+	}
+
+	#[test]
+	fn test_revoke_and_store_merges_the_file() {
+		let path = std::env::temp_dir().join(format!("mpow-revoked-{}.json", uuid::Uuid::new_v4()));
+		let mut other_replica = Revocations::default();
+		other_replica.revoke(Revocation::Jti(String::from("theirs")), 1_000).unwrap();
+		other_replica.store(&path).unwrap();
+
+		let shared: SharedRevocations = Arc::new(RwLock::new(Revocations::default()));
+		let revocation = Revocation::Jti(String::from("ours"));
+		revoke_and_store(&shared, Some(&path), &revocation, 1_000, 3_600).unwrap();
+		assert!(shared.read().unwrap().is_revoked(&claims("theirs", 0, None)));
+		assert!(shared.read().unwrap().is_revoked(&claims("ours", 0, None)));
+		assert_eq!(Revocations::load(&path).unwrap(), *shared.read().unwrap());
+
+		let invalid = Revocation::Ip(String::from("nope"));
+		assert!(revoke_and_store(&shared, Some(&path), &invalid, 1_000, 3_600).is_err());
+		let _ = fs::remove_file(path);
+	}
+
+	#[test]
+	fn test_file_round_trip() {
+		let path = std::env::temp_dir().join(format!("mpow-revoked-{}.json", uuid::Uuid::new_v4()));
+		assert_eq!(Revocations::load(&path).unwrap(), Revocations::default());
+
+		let mut list = Revocations::default();
+		list.revoke(Revocation::Ip(String::from("10.0.0.0/8")), 1_000).unwrap();
+		list.store(&path).unwrap();
+		assert_eq!(Revocations::load(&path).unwrap(), list);
+		let _ = fs::remove_file(path);
+	}
+
+	#[test]
+	fn test_request_body() {
+		let parsed: Revocation = serde_json::from_str(r#"{"issued_before": 1700000000}"#).unwrap();
+		assert_eq!(parsed, Revocation::IssuedBefore(1_700_000_000));
+		let parsed: Revocation = serde_json::from_str(r#"{"ip": "203.0.113.7"}"#).unwrap();
+		assert_eq!(parsed, Revocation::Ip(String::from("203.0.113.7")));
+	}
+}
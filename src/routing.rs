@@ -1,162 +1,608 @@
 use axum::response::IntoResponse;
 use axum::{
-	extract::{Form, State},
-	http::{HeaderMap, StatusCode},
-	response::{Html, Redirect, Response},
+	extract::{Form, FromRef, Query, State},
+	http::{header, HeaderMap, HeaderValue, StatusCode},
+	response::{Html, Response},
 	routing::{get, post},
-	Router,
+	Json, Router,
 };
 use serde::Deserialize;
-use sha2::{Digest, Sha256};
 use std::{
-	collections::HashMap,
-	sync::{Arc, Mutex},
+	net::{IpAddr, SocketAddr},
+	sync::{Arc, RwLock},
 	time::{SystemTime, UNIX_EPOCH},
 };
-use tower::util::ServiceExt;
+use tower::Layer;
 use uuid::Uuid;
 
 use crate::{
+	admin::admin_router,
+	api::api_router,
+	challenges::{spawn_janitor, Challenge},
+	client_ip::{ClientIp, TrustedProxies},
+	config::Config,
+	difficulty::{spawn_controller, DifficultyController, Event},
+	ext_authz::ext_authz_router,
+	forward_auth::forward_auth_router,
+	gate::PowGateLayer,
 	html::generate_challenge_html,
-	jwt::{generate_secret, issue_jwt, validate_jwt},
-	values::{
-		CHALLENGE_EXPIRY_SECS, COOKIE_NAME, MAX_ATTEMPTS, POW_DIFFICULTY_PREFIX, TOKEN_EXPIRY_SECS,
+	jwt::{decode_secret, generate_secret, sign_jwt, validate_jwt, BindingPolicy, Claims, SessionBinding},
+	keyring::{spawn_rotation, KeyRing, SharedKeyRing},
+	pow::{Verdict, Verifier},
+	proxy::ReverseProxy,
+	quota::{QuotaTracker, QuotaVerdict},
+	redirect::{requested_return_to, sanitize_return_to, DEFAULT_RETURN_TO},
+	reputation::{Behaviour, Reputation},
+	revocation::{spawn_reload, Revocations, SharedRevocations},
+	stateless::{
+		client_binding, ChallengeMode, ChallengeSigner, SignedChallengeError, SpendResult, SpentIds,
 	},
+	store::{open_store, SharedStore},
+	values::REFRESH_COOKIE_HEADER,
 };
 
 #[derive(Clone)]
 pub struct AppState {
-	pub jwt_secret: Vec<u8>,
-	pub challenges: Arc<Mutex<HashMap<String, Challenge>>>,
+	pub config: Arc<Config>,
+	pub keyring: SharedKeyRing,
+	pub challenges: SharedStore,
+	/// Signs and verifies challenges in stateless mode
+	pub signer: Arc<ChallengeSigner>,
+	/// Ids of solved stateless challenges, kept to refuse replays
+	pub spent: Arc<SpentIds>,
+	/// Checks submitted nonces with the configured algorithm
+	pub verifier: Arc<Verifier>,
+	/// Picks the difficulty of new challenges from recent load
+	pub difficulty: Arc<DifficultyController>,
+	/// Recent behaviour of client addresses and subnets
+	pub reputation: Arc<Reputation>,
+	/// Proxies whose forwarding headers name the client
+	pub proxies: Arc<TrustedProxies>,
+	/// Session tokens revoked before their expiry
+	pub revocations: SharedRevocations,
+	/// Requests counted per session token
+	pub quotas: Arc<QuotaTracker>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Challenge {
-	pub token: String,
-	pub challenge: String,
-	pub created_at: u64,
-	pub attempts: u32,
+impl FromRef<AppState> for Arc<TrustedProxies> {
+	fn from_ref(state: &AppState) -> Self {
+		state.proxies.clone()
+	}
 }
 
 #[derive(Deserialize)]
 pub struct NonceSubmission {
-	nonce: String,
-	token: String,
+	pub nonce: String,
+	pub token: String,
+	/// Page to return to; only consulted for stateless challenges, which store nothing
+	#[serde(default)]
+	pub return_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeQuery {
+	return_to: Option<String>,
 }
 
 impl AppState {
-	pub fn new() -> Self {
-		Self {
-			jwt_secret: generate_secret(),
-			challenges: Arc::new(Mutex::new(HashMap::new())),
-		}
+	pub fn new(config: Config) -> Result<Self, String> {
+		let keyring = KeyRing::from_config(&config.session)?;
+		let challenge_secret = match &config.pow.challenge_secret {
+			Some(secret) => decode_secret(secret)?,
+			None => {
+				if config.pow.mode == ChallengeMode::Stateless {
+					tracing::warn!(
+						"pow.challenge_secret is not set; stateless challenges will only verify on this process"
+					);
+				}
+				generate_secret()
+			}
+		};
+		let challenges = open_store(&config.store, &config.pow)?;
+		let spent = SpentIds::new(
+			&challenges,
+			config.pow.challenge_expiry_secs,
+			config.pow.max_outstanding_challenges,
+		);
+		let verifier = Verifier::new(config.pow.algorithm(), config.pow.max_concurrent_verifications());
+		let difficulty = DifficultyController::new(&config.pow);
+		let reputation = Reputation::new(&config.pow.reputation);
+		let proxies = TrustedProxies::new(config.server.trusted_proxies.clone());
+		let revocations = match &config.session.revocation_file {
+			Some(path) => Revocations::load(path)?,
+			None => Revocations::default(),
+		};
+		let quotas = QuotaTracker::new(&config.session.quota);
+		Ok(Self {
+			config: Arc::new(config),
+			keyring: Arc::new(RwLock::new(keyring)),
+			challenges,
+			signer: Arc::new(ChallengeSigner::new(&challenge_secret)),
+			verifier: Arc::new(verifier),
+			spent: Arc::new(spent),
+			difficulty: Arc::new(difficulty),
+			reputation: Arc::new(reputation),
+			proxies: Arc::new(proxies),
+			revocations: Arc::new(RwLock::new(revocations)),
+			quotas: Arc::new(quotas),
+		})
 	}
 }
 
-pub fn create_router() -> Router {
-	let state = AppState::new();
+/// Router in front of a protected service, used by [`PowGateLayer`] and reverse proxy mode
+///
+/// Only the endpoints visitors need to get a session are mounted: the challenge
+/// and solve endpoints, the JWKS and, with an admin token configured, `/admin/*`.
+/// The auth endpoints for other proxies (`/validate`, `/forward_auth`,
+/// `/ext_authz/*` and the gRPC `Check`) are left out, so their paths reach the
+/// protected service and clients cannot ask the gate to vouch for made-up addresses.
+pub fn gate_router(state: AppState) -> Router {
+	let router = Router::new()
+		.route("/get_challenge", get(handle_get_challenge))
+		.route("/post_nonce", post(handle_post_nonce))
+		.route("/.well-known/jwks.json", get(handle_jwks))
+		.merge(api_router());
+	let router = match state.config.admin.token {
+		Some(_) => router.merge(admin_router()),
+		None => router,
+	};
+	router.with_state(state)
+}
 
+pub fn create_router(state: AppState) -> Router {
 	Router::new()
 		.route("/get_challenge", get(handle_get_challenge))
 		.route("/post_nonce", post(handle_post_nonce))
 		.route("/validate", get(handle_validate))
+		.route("/.well-known/jwks.json", get(handle_jwks))
+		.merge(api_router())
+		.merge(forward_auth_router())
+		.merge(ext_authz_router())
+		.merge(admin_router())
 		.with_state(state)
 }
 
-async fn handle_get_challenge(State(state): State<AppState>) -> Result<Response, StatusCode> {
+/// Challenge handed out to a client
+pub struct IssuedChallenge {
+	/// Names the challenge on submission; the challenge itself when stateless
+	pub token: String,
+	pub challenge: String,
+	pub difficulty_bits: u32,
+	pub issued_at: u64,
+	/// Sanitized page to send the visitor on to
+	pub return_to: Option<String>,
+}
+
+/// Session token granted for a solved challenge
+pub struct GrantedSession {
+	pub token: String,
+	/// `Set-Cookie` value carrying `token`
+	pub cookie: String,
+	pub expires_at: u64,
+	/// Sanitized page to send the visitor on to
+	pub return_to: Option<String>,
+}
+
+/// Why a challenge was not issued or a solution not accepted
+#[derive(Debug)]
+pub struct Refusal {
+	pub status: StatusCode,
+	/// Seconds the client should wait before trying again
+	pub retry_after: Option<u64>,
+	pub message: &'static str,
+}
+
+impl Refusal {
+	fn new(status: StatusCode, message: &'static str) -> Self {
+		Self {
+			status,
+			retry_after: None,
+			message,
+		}
+	}
+
+	fn busy(retry_after: u64, message: &'static str) -> Self {
+		Self {
+			status: StatusCode::SERVICE_UNAVAILABLE,
+			retry_after: Some(retry_after),
+			message,
+		}
+	}
+
+	/// Response with the refusal's status and `Retry-After` around `body`
+	pub fn with_body(&self, body: impl IntoResponse) -> Response {
+		let mut response = (self.status, body).into_response();
+		if let Some(secs) = self.retry_after {
+			response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+		}
+		response
+	}
+}
+
+impl From<StatusCode> for Refusal {
+	fn from(status: StatusCode) -> Self {
+		Self::new(status, status.canonical_reason().unwrap_or_default())
+	}
+}
+
+impl IntoResponse for Refusal {
+	fn into_response(self) -> Response {
+		self.with_body(self.message)
+	}
+}
+
+async fn handle_get_challenge(
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+	State(state): State<AppState>,
+	Query(query): Query<ChallengeQuery>,
+) -> Response {
+	let return_to = requested_return_to(query.return_to.as_deref(), &headers);
+	let issued = match issue_challenge(&state, ip, &headers, return_to).await {
+		Ok(issued) => issued,
+		Err(refusal) => return refusal.into_response(),
+	};
+	let html = generate_challenge_html(
+		&issued.token,
+		&issued.challenge,
+		issued.difficulty_bits,
+		state.verifier.algorithm(),
+		issued.return_to.as_deref().unwrap_or(DEFAULT_RETURN_TO),
+	);
+	Html(html).into_response()
+}
+
+/// Issues a challenge at the difficulty currently required of `ip`
+///
+/// # Arguments
+/// * `return_to` - sanitized page to send the visitor on to once solved
+///
+/// # Returns
+/// The challenge, or a refusal when too many are outstanding
+pub async fn issue_challenge(
+	state: &AppState,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+	return_to: Option<String>,
+) -> Result<IssuedChallenge, Refusal> {
+	let pow = &state.config.pow;
+	let now = current_timestamp();
+	let difficulty_bits = state
+		.difficulty
+		.current()
+		.saturating_add(state.reputation.extra_bits(ip, now))
+		.min(256);
+	state.difficulty.record(Event::Issued);
+	state.reputation.record(ip, Behaviour::Issued, now);
+
+	if pow.mode == ChallengeMode::Stateless {
+		let binding = request_binding(ip, headers, pow.bind_client);
+		let challenge = state.signer.issue(now, difficulty_bits, &binding);
+		return Ok(IssuedChallenge {
+			token: challenge.clone(),
+			challenge,
+			difficulty_bits,
+			issued_at: now,
+			return_to,
+		});
+	}
+
 	let token = Uuid::new_v4().to_string();
 	let challenge = Uuid::new_v4().to_string();
-	let now = current_timestamp();
 
 	let challenge_data = Challenge {
 		token: token.clone(),
 		challenge: challenge.clone(),
 		created_at: now,
 		attempts: 0,
+		difficulty_bits,
+		return_to: return_to.clone(),
 	};
 
-	if let Ok(mut map) = state.challenges.lock() {
-		map.insert(token.clone(), challenge_data);
+	let stored = state
+		.challenges
+		.insert(challenge_data)
+		.await
+		.map_err(store_error)?;
+	if !stored {
+		return Err(Refusal::busy(5, "Too many outstanding challenges, try again shortly"));
 	}
 
-	let html = generate_challenge_html(&token, &challenge, POW_DIFFICULTY_PREFIX.len());
-	Ok(Html(html).into_response())
+	Ok(IssuedChallenge {
+		token,
+		challenge,
+		difficulty_bits,
+		issued_at: now,
+		return_to,
+	})
 }
 
 async fn handle_post_nonce(
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
 	State(state): State<AppState>,
 	Form(submission): Form<NonceSubmission>,
 ) -> Result<Response, StatusCode> {
+	let session = match solve_challenge(&state, ip, &headers, &submission).await {
+		Ok(session) => session,
+		Err(refusal) => return Ok(refusal.into_response()),
+	};
+
+	let mut response = (
+		StatusCode::OK,
+		"PoW verified, access granted! Redirecting...",
+	)
+		.into_response();
+
+	response
+		.headers_mut()
+		.insert("set-cookie", session.cookie.parse().unwrap());
+
+	let refresh = format!("2; url={}", session.return_to.as_deref().unwrap_or(DEFAULT_RETURN_TO));
+	response
+		.headers_mut()
+		.insert("refresh", refresh.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+
+	Ok(response)
+}
+
+/// Checks a submitted nonce and grants a session for a correct one
+///
+/// # Returns
+/// The new session, or why the submission was refused
+pub async fn solve_challenge(
+	state: &AppState,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+	submission: &NonceSubmission,
+) -> Result<GrantedSession, Refusal> {
 	let now = current_timestamp();
+	let pow = &state.config.pow;
 
-	let mut challenges = state
+	if submission.nonce.len() > pow.max_nonce_length {
+		return Err(Refusal::new(StatusCode::BAD_REQUEST, "Nonce too long"));
+	}
+
+	if pow.mode == ChallengeMode::Stateless {
+		return verify_signed_submission(state, ip, headers, submission, now).await;
+	}
+
+	// Counting the attempt first keeps the limit exact under concurrent submissions
+	let challenge = match state
 		.challenges
-		.lock()
-		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-	let challenge = match challenges.get_mut(&submission.token) {
+		.increment_attempts(&submission.token)
+		.await
+		.map_err(store_error)?
+	{
 		Some(c) => c,
-		None => return Ok((StatusCode::FORBIDDEN, "No active challenge").into_response()),
+		None => {
+			record_failure(state, ip, now);
+			return Err(Refusal::new(StatusCode::FORBIDDEN, "No active challenge"));
+		}
 	};
 
-	if now - challenge.created_at > CHALLENGE_EXPIRY_SECS {
-		challenges.remove(&submission.token);
-		return Ok((StatusCode::FORBIDDEN, "Challenge expired").into_response());
+	if now.saturating_sub(challenge.created_at) > pow.challenge_expiry_secs {
+		state.challenges.take(&submission.token).await.map_err(store_error)?;
+		return Err(Refusal::new(StatusCode::FORBIDDEN, "Challenge expired"));
 	}
 
-	if challenge.attempts >= MAX_ATTEMPTS {
-		return Ok((StatusCode::TOO_MANY_REQUESTS, "Too many attempts").into_response());
+	if challenge.attempts > pow.max_attempts {
+		return Err(Refusal::new(StatusCode::TOO_MANY_REQUESTS, "Too many attempts"));
 	}
 
-	challenge.attempts += 1;
+	let verdict = state
+		.verifier
+		.check(&challenge.challenge, &submission.nonce, challenge.difficulty_bits)
+		.await;
+	if verdict != Verdict::Valid {
+		return Err(rejected_nonce(state, ip, verdict, now));
+	}
+
+	// Only the request that removes the challenge gets a session
+	let Some(solved) = state
+		.challenges
+		.take(&submission.token)
+		.await
+		.map_err(store_error)?
+	else {
+		return Err(Refusal::new(StatusCode::FORBIDDEN, "No active challenge"));
+	};
+
+	grant_session(state, ip, headers, solved.return_to, now)
+}
+
+/// Checks a solution to a signed challenge and refuses replays of solved ones
+async fn verify_signed_submission(
+	state: &AppState,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+	submission: &NonceSubmission,
+	now: u64,
+) -> Result<GrantedSession, Refusal> {
+	let pow = &state.config.pow;
+	let binding = request_binding(ip, headers, pow.bind_client);
+	let signed = match state
+		.signer
+		.verify(&submission.token, now, pow.challenge_expiry_secs, &binding)
+	{
+		Ok(signed) => signed,
+		Err(SignedChallengeError::Expired) => {
+			return Err(Refusal::new(StatusCode::FORBIDDEN, "Challenge expired"));
+		}
+		Err(_) => {
+			record_failure(state, ip, now);
+			return Err(Refusal::new(StatusCode::FORBIDDEN, "No active challenge"));
+		}
+	};
 
-	let hash_input = format!("{}{}", challenge.challenge, submission.nonce);
-	let hash = Sha256::digest(hash_input.as_bytes());
-	let hash_hex = format!("{:x}", hash);
+	// Replays and repeated guesses are turned away before paying for a hash
+	if state.spent.contains(signed.id, now).await.map_err(store_error)? {
+		return Err(Refusal::new(StatusCode::FORBIDDEN, "Challenge already solved"));
+	}
+	match state
+		.spent
+		.count_attempt(signed.id, signed.issued_at, now)
+		.await
+		.map_err(store_error)?
+	{
+		Some(attempts) if attempts > pow.max_attempts => {
+			return Err(Refusal::new(StatusCode::TOO_MANY_REQUESTS, "Too many attempts"));
+		}
+		Some(_) => {}
+		None => return Err(Refusal::busy(5, "Too many pending solutions, try again shortly")),
+	}
 
-	if !hash_hex.starts_with(POW_DIFFICULTY_PREFIX) {
-		return Ok((StatusCode::FORBIDDEN, "Invalid nonce").into_response());
+	let verdict = state
+		.verifier
+		.check(&submission.token, &submission.nonce, signed.difficulty)
+		.await;
+	if verdict != Verdict::Valid {
+		return Err(rejected_nonce(state, ip, verdict, now));
 	}
 
-	let jwt_token = issue_jwt("verified_user", &state.jwt_secret)
-		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	match state.spent.spend(signed.id, signed.issued_at, now).await.map_err(store_error)? {
+		SpendResult::Recorded => {
+			let return_to = submission.return_to.as_deref().and_then(sanitize_return_to);
+			grant_session(state, ip, headers, return_to, now)
+		}
+		SpendResult::AlreadySpent => {
+			Err(Refusal::new(StatusCode::FORBIDDEN, "Challenge already solved"))
+		}
+		SpendResult::Full => Err(Refusal::busy(5, "Too many recent solutions, try again shortly")),
+	}
+}
 
-	challenges.remove(&submission.token);
-	drop(challenges);
+/// Counts a wrong nonce or unknown token against the load and the client
+fn record_failure(state: &AppState, ip: Option<IpAddr>, now: u64) {
+	state.difficulty.record(Event::Failed);
+	state.reputation.record(ip, Behaviour::Failed, now);
+}
 
-	let cookie = format!(
-		"{name}={value}; HttpOnly; Secure; SameSite=Strict; Max-Age={max_age}",
-		name = COOKIE_NAME,
-		value = jwt_token,
-		max_age = TOKEN_EXPIRY_SECS
-	);
+fn rejected_nonce(state: &AppState, ip: Option<IpAddr>, verdict: Verdict, now: u64) -> Refusal {
+	if verdict == Verdict::Invalid {
+		record_failure(state, ip, now);
+	}
+	match verdict {
+		Verdict::Busy => Refusal::busy(1, "Server busy, try again shortly"),
+		_ => Refusal::new(StatusCode::FORBIDDEN, "Invalid nonce"),
+	}
+}
 
-	let mut response = (
-		StatusCode::OK,
-		"PoW verified, access granted! Redirecting...",
-	)
-		.into_response();
+fn store_error(error: String) -> StatusCode {
+	tracing::error!("challenge store error: {}", error);
+	StatusCode::INTERNAL_SERVER_ERROR
+}
 
-	response
-		.headers_mut()
-		.insert("set-cookie", cookie.parse().unwrap());
+/// Issues the session token for a solved challenge
+///
+/// # Arguments
+/// * `return_to` - sanitized page to send the visitor on to, `/` if `None`
+fn grant_session(
+	state: &AppState,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+	return_to: Option<String>,
+	now: u64,
+) -> Result<GrantedSession, Refusal> {
+	state.difficulty.record(Event::Solved);
+	state.reputation.record(ip, Behaviour::Solved, now);
+	let session = &state.config.session;
+	let mut claims = Claims::new("verified_user", &session.token_scope(), session.token_expiry_secs)
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	claims.cip = ip.map(|ip| ip.to_string());
+	if session.binding != BindingPolicy::Off {
+		let binding = session_binding(state, ip, headers);
+		claims.ipp = binding.ipp;
+		claims.uah = binding.uah;
+	}
+	let keyring = state
+		.keyring
+		.read()
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	let jwt_token = sign_jwt(&claims, &keyring).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	drop(keyring);
+
+	Ok(GrantedSession {
+		cookie: session_cookie(state, &jwt_token, session.token_expiry_secs),
+		token: jwt_token,
+		expires_at: claims.exp as u64,
+		return_to,
+	})
+}
 
-	response
-		.headers_mut()
-		.insert("refresh", "2; url=/validate".parse().unwrap());
+/// `Set-Cookie` value carrying a session token
+fn session_cookie(state: &AppState, token: &str, max_age: u64) -> String {
+	format!(
+		"{name}={value}; HttpOnly; Secure; SameSite=Strict; Max-Age={max_age}",
+		name = state.config.session.cookie_name,
+		value = token,
+		max_age = max_age
+	)
+}
 
-	Ok(response)
+/// Session presented with a request
+pub struct Authenticated {
+	pub claims: Claims,
+	/// `Set-Cookie` value of a replacement token, if the session was refreshed
+	pub refreshed_cookie: Option<String>,
+}
+
+/// Checks the session cookie of a request
+///
+/// # Arguments
+/// * `ip` - resolved client address
+/// * `headers` - request headers carrying the cookie
+///
+/// # Returns
+/// The session if the token is valid, bound to this client, not revoked and within
+/// its quota; `None` if the client has to solve a challenge
+pub fn authenticate(
+	state: &AppState,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+) -> Result<Option<Authenticated>, StatusCode> {
+	let Some(token) = headers
+		.get(header::COOKIE)
+		.and_then(|c| c.to_str().ok())
+		.and_then(|cookies| extract_token_from_cookie(cookies, &state.config.session.cookie_name))
+	else {
+		return Ok(None);
+	};
+	let keyring = state
+		.keyring
+		.read()
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	let claims = validate_jwt(&token, &keyring, &state.config.session.token_scope());
+	drop(keyring);
+	let Ok(claims) = claims else {
+		return Ok(None);
+	};
+	if !binding_accepted(state, &claims, ip, headers)
+		|| is_revoked(state, &claims)?
+		|| !within_quota(state, &claims)
+	{
+		return Ok(None);
+	}
+	let refreshed_cookie = refresh_session(state, &claims)?;
+	Ok(Some(Authenticated {
+		claims,
+		refreshed_cookie,
+	}))
 }
 
 async fn handle_validate(
+	ClientIp(ip): ClientIp,
 	headers: HeaderMap,
 	State(state): State<AppState>,
 ) -> Result<Response, StatusCode> {
-	if let Some(cookie_str) = headers.get("cookie").and_then(|c| c.to_str().ok()) {
-		if let Some(token) = extract_token_from_cookie(cookie_str) {
-			if let Ok(_) = validate_jwt(&token, &state.jwt_secret) {
-				return Ok((StatusCode::OK, "Access Granted - You are authenticated!").into_response());
-			}
+	if let Some(session) = authenticate(&state, ip, &headers)? {
+		let mut response = (StatusCode::OK, "Access Granted - You are authenticated!").into_response();
+		if let Some(cookie) = session.refreshed_cookie {
+			let cookie = HeaderValue::from_str(&cookie).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+			response.headers_mut().insert(REFRESH_COOKIE_HEADER, cookie.clone());
+			response.headers_mut().insert(header::SET_COOKIE, cookie);
 		}
+		return Ok(response);
 	}
 
 	Ok((
@@ -166,12 +612,132 @@ async fn handle_validate(
 	).into_response())
 }
 
-fn extract_token_from_cookie(cookie_str: &str) -> Option<String> {
-	cookie_str.split(';').map(str::trim).find_map(|cookie| {
-		cookie
-			.strip_prefix(&format!("{COOKIE_NAME}="))
-			.map(String::from)
-	})
+/// Client binding of the current request
+fn session_binding(state: &AppState, ip: Option<IpAddr>, headers: &HeaderMap) -> SessionBinding {
+	let session = &state.config.session;
+	let user_agent = headers.get(header::USER_AGENT).and_then(|v| v.to_str().ok());
+	SessionBinding::new(ip, user_agent, session.binding_ipv4_prefix, session.binding_ipv6_prefix)
+}
+
+/// Checks a valid token's binding against the presenting client under the configured policy
+fn binding_accepted(
+	state: &AppState,
+	claims: &Claims,
+	ip: Option<IpAddr>,
+	headers: &HeaderMap,
+) -> bool {
+	let policy = state.config.session.binding;
+	if policy == BindingPolicy::Off {
+		return true;
+	}
+	let (ip_mismatch, user_agent_mismatch) = session_binding(state, ip, headers).mismatches(claims);
+	if ip_mismatch || user_agent_mismatch {
+		tracing::warn!(
+			"session token presented by another client (address changed: {}, user agent changed: {})",
+			ip_mismatch,
+			user_agent_mismatch
+		);
+	}
+	policy.accepts(ip_mismatch, user_agent_mismatch)
+}
+
+/// Reissues a token that is due for refresh
+///
+/// # Returns
+/// The `Set-Cookie` value of the replacement, or `None` if the token is kept
+fn refresh_session(state: &AppState, claims: &Claims) -> Result<Option<String>, StatusCode> {
+	let now = current_timestamp();
+	let Some(refreshed) = claims.refreshed(&state.config.session.refresh_policy(), now) else {
+		return Ok(None);
+	};
+	let keyring = state
+		.keyring
+		.read()
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	let token = sign_jwt(&refreshed, &keyring).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	drop(keyring);
+	Ok(Some(session_cookie(state, &token, (refreshed.exp as u64).saturating_sub(now))))
+}
+
+/// Counts the request against the token's quota
+fn within_quota(state: &AppState, claims: &Claims) -> bool {
+	match state.quotas.check(&claims.jti, claims.exp as u64, current_timestamp()) {
+		QuotaVerdict::Allowed => true,
+		verdict => {
+			tracing::info!("token {} refused: {:?}", claims.jti, verdict);
+			false
+		}
+	}
+}
+
+fn is_revoked(state: &AppState, claims: &Claims) -> Result<bool, StatusCode> {
+	let revocations = state
+		.revocations
+		.read()
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	Ok(revocations.is_revoked(claims))
+}
+
+async fn handle_jwks(State(state): State<AppState>) -> Result<Response, StatusCode> {
+	let keyring = state
+		.keyring
+		.read()
+		.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+	let jwks = keyring.jwks(current_timestamp());
+	drop(keyring);
+
+	Ok((
+		[(header::CACHE_CONTROL, "public, max-age=300")],
+		Json(jwks),
+	)
+		.into_response())
+}
+
+fn extract_token_from_cookie(cookie_str: &str, cookie_name: &str) -> Option<String> {
+	let prefix = format!("{cookie_name}=");
+	cookie_str
+		.split(';')
+		.map(str::trim)
+		.find_map(|cookie| cookie.strip_prefix(&prefix).map(String::from))
+}
+
+/// Starts the janitor, difficulty controller, key rotation and revocation reload
+/// the configuration calls for; must be called within a Tokio runtime
+pub fn spawn_background_tasks(state: &AppState) {
+	let pow = &state.config.pow;
+	if pow.mode == ChallengeMode::Stateful {
+		spawn_janitor(state.challenges.clone(), pow.janitor_interval_secs);
+	}
+	if pow.adaptive.enabled {
+		spawn_controller(
+			state.difficulty.clone(),
+			state.challenges.clone(),
+			pow.adaptive.window_secs,
+		);
+	}
+
+	let session = &state.config.session;
+	if session.rotation_interval_secs > 0 {
+		spawn_rotation(
+			state.keyring.clone(),
+			session.keyring_file().map(Into::into),
+			session.rotation_interval_secs,
+			session.rotation_grace(),
+		);
+	}
+
+	if let Some(path) = &session.revocation_file {
+		spawn_reload(state.revocations.clone(), path.clone(), session.session_lifetime());
+	}
+}
+
+/// Binding for stateless challenges, from the client address and `User-Agent`
+fn request_binding(ip: Option<IpAddr>, headers: &HeaderMap, bind_client: bool) -> String {
+	if !bind_client {
+		return client_binding(None, None);
+	}
+	let user_agent = headers.get("user-agent").and_then(|v| v.to_str().ok());
+	client_binding(ip.map(|ip| ip.to_string()).as_deref(), user_agent)
 }
 
 fn current_timestamp() -> u64 {
@@ -181,17 +747,55 @@ fn current_timestamp() -> u64 {
 		.as_secs()
 }
 
-pub async fn start_server() {
-	let app = create_router();
-	let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+pub async fn start_server(config: Config) -> Result<(), String> {
+	let state = AppState::new(config)?;
+	spawn_background_tasks(&state);
+	serve(state).await
+}
+
+/// Serves the gate's endpoints on the configured address until the server fails
+///
+/// With `proxy.upstream` set, only the endpoints of [`gate_router`] are served
+/// and every other request is forwarded to the upstream when it carries a valid
+/// session and sent to the challenge otherwise.
+/// Background tasks are left to the caller; see [`spawn_background_tasks`].
+pub async fn serve(state: AppState) -> Result<(), String> {
+	let bind = state.config.server.bind;
+	let upstream = state.config.proxy.upstream.clone();
+	let admin_enabled = state.config.admin.token.is_some();
+	let app = match &upstream {
+		Some(_) => {
+			let proxy = ReverseProxy::new(&state.config.proxy, state.proxies.clone())?;
+			Router::new().fallback_service(PowGateLayer::from_state(state).layer(proxy))
+		}
+		None => create_router(state),
+	};
+	let listener = tokio::net::TcpListener::bind(bind)
 		.await
-		.expect("bind failed");
-	println!("🚀 Listening on http://0.0.0.0:3000");
+		.map_err(|e| format!("bind {} failed: {}", bind, e))?;
+	println!("🚀 Listening on http://{}", bind);
 	println!("📋 Endpoints:");
 	println!("   GET  /get_challenge - Get a new PoW challenge");
 	println!("   POST /post_nonce    - Submit nonce solution");
-	println!("   GET  /validate      - Check authentication status");
-	axum::serve(listener, app).await.expect("server error");
+	if upstream.is_none() {
+		println!("   GET  /validate      - Check authentication status");
+		println!("   GET  /forward_auth  - Traefik/Caddy forward auth, challenge page on denial");
+		println!("   *    /ext_authz/*   - Envoy ext_authz (HTTP), challenge page on denial");
+		println!("   gRPC envoy.service.auth.v3.Authorization/Check - Envoy ext_authz (gRPC, h2c)");
+	}
+	println!("   GET  /api/v1/challenge - Get a challenge as JSON");
+	println!("   POST /api/v1/solve     - Submit a JSON solution, get the session token");
+	println!("   GET  /.well-known/jwks.json - Public keys for offline token validation");
+	if upstream.is_none() || admin_enabled {
+		println!("   POST /admin/rotate_key - Rotate the JWT signing key (admin token)");
+		println!("   POST /admin/revoke     - Revoke tokens by jti, address or issue time (admin token)");
+	}
+	if let Some(upstream) = upstream {
+		println!("   *    everything else   - Proxied to {} with a valid session", upstream);
+	}
+	axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+		.await
+		.map_err(|e| format!("server error: {}", e))
 }
 
 #[cfg(test)]
@@ -199,13 +803,20 @@ mod tests {
 	use super::*;
 	use axum::{
 		body::Body,
+		extract::ConnectInfo,
 		http::{header, Method, Request},
 	};
+	use crate::jwt::issue_jwt;
+	use crate::values::{CHALLENGE_EXPIRY_SECS, COOKIE_NAME, MAX_ATTEMPTS, POW_DIFFICULTY_BITS};
 	use tower::ServiceExt;
 
+	fn test_state() -> AppState {
+		AppState::new(Config::default()).unwrap()
+	}
+
 	#[tokio::test]
 	async fn test_get_challenge() {
-		let app = create_router();
+		let app = create_router(test_state());
 
 		let request = Request::builder()
 			.method(Method::GET)
@@ -228,7 +839,7 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_post_nonce_with_invalid_token() {
-		let app = create_router();
+		let app = create_router(test_state());
 
 		let form_data = "nonce=123456&token=invalid_token";
 
@@ -253,7 +864,7 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_validate_without_cookie() {
-		let app = create_router();
+		let app = create_router(test_state());
 
 		let request = Request::builder()
 			.method(Method::GET)
@@ -269,8 +880,8 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_validate_with_valid_jwt() {
-		let state = AppState::new();
-		let jwt_token = issue_jwt("test_user", &state.jwt_secret).unwrap();
+		let state = test_state();
+		let jwt_token = issue_jwt("test_user", &state.keyring.read().unwrap(), 60).unwrap();
 		let cookie_value = format!("{}={}", COOKIE_NAME, jwt_token);
 
 		let app = Router::new()
@@ -296,15 +907,109 @@ mod tests {
 		assert_eq!(body_str, "Access Granted - You are authenticated!");
 	}
 
+	#[tokio::test]
+	async fn test_validate_uses_configured_cookie_name() {
+		let mut config = Config::default();
+		config.session.cookie_name = String::from("custom_gate");
+		let state = AppState::new(config).unwrap();
+		let jwt_token = issue_jwt("test_user", &state.keyring.read().unwrap(), 60).unwrap();
+
+		let app = create_router(state);
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/validate")
+			.header(header::COOKIE, format!("{}={}", COOKIE_NAME, jwt_token))
+			.body(Body::empty())
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/validate")
+			.header(header::COOKIE, format!("custom_gate={}", jwt_token))
+			.body(Body::empty())
+			.unwrap();
+		let response = app.oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_jwks_endpoint() {
+		let mut config = Config::default();
+		config.session.jwt_algorithm = crate::keyring::KeyAlgorithm::EdDsa;
+		let state = AppState::new(config).unwrap();
+		let kid = state.keyring.read().unwrap().current().kid.clone();
+		let app = create_router(state);
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/.well-known/jwks.json")
+			.body(Body::empty())
+			.unwrap();
+		let response = app.oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let jwks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+		assert_eq!(jwks["keys"][0]["kid"], kid.as_str());
+		assert_eq!(jwks["keys"][0]["alg"], "EdDSA");
+	}
+
+	#[tokio::test]
+	async fn test_get_challenge_rejected_when_full() {
+		let mut config = Config::default();
+		config.pow.max_outstanding_challenges = 1;
+		config.pow.eviction_policy = crate::challenges::EvictionPolicy::Reject;
+		let state = AppState::new(config).unwrap();
+		let app = create_router(state.clone());
+
+		let request = || {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/get_challenge")
+				.body(Body::empty())
+				.unwrap()
+		};
+
+		let response = app.clone().oneshot(request()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response = app.oneshot(request()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+		assert!(response.headers().contains_key(header::RETRY_AFTER));
+		assert_eq!(state.challenges.len().await.unwrap(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_get_challenge_evicts_oldest_when_full() {
+		let mut config = Config::default();
+		config.pow.max_outstanding_challenges = 2;
+		let state = AppState::new(config).unwrap();
+		let app = create_router(state.clone());
+
+		for _ in 0..5 {
+			let request = Request::builder()
+				.method(Method::GET)
+				.uri("/get_challenge")
+				.body(Body::empty())
+				.unwrap();
+			let response = app.clone().oneshot(request).await.unwrap();
+			assert_eq!(response.status(), StatusCode::OK);
+		}
+
+		assert_eq!(state.challenges.len().await.unwrap(), 2);
+	}
+
 	// Helper function to find a valid nonce for testing
-	fn find_valid_nonce(challenge: &str, difficulty_prefix: &str) -> String {
-		for nonce in 0..1000000 {
-			let hash_input = format!("{}{}", challenge, nonce);
-			let hash = Sha256::digest(hash_input.as_bytes());
-			let hash_hex = format!("{:x}", hash);
-
-			if hash_hex.starts_with(difficulty_prefix) {
-				return nonce.to_string();
+	fn find_valid_nonce(challenge: &str, difficulty_bits: u32) -> String {
+		for nonce in 0..10_000_000 {
+			let nonce = nonce.to_string();
+			if crate::pow::meets_difficulty(challenge, &nonce, difficulty_bits) {
+				return nonce;
 			}
 		}
 		panic!("Could not find valid nonce within reasonable attempts");
@@ -312,7 +1017,7 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_full_flow() {
-		let app = create_router();
+		let app = create_router(test_state());
 
 		// Step 1: Get challenge
 		let request = Request::builder()
@@ -335,9 +1040,44 @@ mod tests {
 		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 	}
 
+	#[tokio::test]
+	async fn test_native_solver_passes_page() {
+		let mut config = Config::default();
+		config.pow.algorithm = crate::pow::PowAlgorithm::Scrypt;
+		config.pow.difficulty_bits = 2;
+		config.pow.scrypt_log_n = 4;
+		let app = create_router(AppState::new(config).unwrap());
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/get_challenge")
+			.body(Body::empty())
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let page = mpow_solver::client::parse_challenge_page(std::str::from_utf8(&body).unwrap()).unwrap();
+		let solution = mpow_solver::Solver::new(page.algorithm)
+			.solve(&page.challenge, &page.target)
+			.unwrap()
+			.unwrap();
+
+		let request = Request::builder()
+			.method(Method::POST)
+			.uri("/post_nonce")
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.body(Body::from(format!("nonce={}&token={}", solution.nonce, page.token)))
+			.unwrap();
+		let response = app.oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+		assert_eq!(mpow_solver::client::SessionCookie::parse(set_cookie).unwrap().name, COOKIE_NAME);
+	}
+
 	#[tokio::test]
 	async fn test_post_nonce_with_valid_solution() {
-		let state = AppState::new();
+		let state = test_state();
 		let token = "test_token";
 		let challenge = "test_challenge";
 
@@ -346,20 +1086,20 @@ mod tests {
 			challenge: challenge.to_string(),
 			created_at: current_timestamp(),
 			attempts: 0,
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			return_to: Some(String::from("/articles/123")),
 		};
 
-		{
-			let mut challenges = state.challenges.lock().unwrap();
-			challenges.insert(token.to_string(), valid_challenge);
-		}
+		state.challenges.insert(valid_challenge).await.unwrap();
 
-		let valid_nonce = find_valid_nonce(challenge, POW_DIFFICULTY_PREFIX);
+		let valid_nonce = find_valid_nonce(challenge, POW_DIFFICULTY_BITS);
 
 		let app = Router::new()
 			.route("/post_nonce", post(handle_post_nonce))
 			.with_state(state);
 
-		let form_data = format!("nonce={}&token={}", valid_nonce, token);
+		// The stored page wins over whatever the form says
+		let form_data = format!("nonce={}&token={}&return_to=%2Felsewhere", valid_nonce, token);
 
 		let request = Request::builder()
 			.method(Method::POST)
@@ -378,15 +1118,324 @@ mod tests {
 		let cookie_str = set_cookie_header.unwrap().to_str().unwrap();
 		assert!(cookie_str.contains(COOKIE_NAME));
 
-		// Check that refresh header points to /validate
+		// Check that refresh header points to the page the visitor asked for
 		let refresh_header = response.headers().get("refresh");
 		assert!(refresh_header.is_some());
-		assert_eq!(refresh_header.unwrap().to_str().unwrap(), "2; url=/validate");
+		assert_eq!(refresh_header.unwrap().to_str().unwrap(), "2; url=/articles/123");
+	}
+
+	#[tokio::test]
+	async fn test_post_nonce_uses_recorded_difficulty() {
+		let state = test_state();
+		let challenge = "recorded_challenge";
+		// Solves 4 bits but not the configured 16
+		let nonce = (0..)
+			.map(|n: u32| n.to_string())
+			.find(|n| {
+				crate::pow::meets_difficulty(challenge, n, 4)
+					&& !crate::pow::meets_difficulty(challenge, n, POW_DIFFICULTY_BITS)
+			})
+			.unwrap();
+
+		for (token, difficulty_bits, expected) in [
+			("easy", 4, StatusCode::OK),
+			("hard", POW_DIFFICULTY_BITS, StatusCode::FORBIDDEN),
+		] {
+			state
+				.challenges
+				.insert(Challenge {
+					token: token.to_string(),
+					challenge: challenge.to_string(),
+					created_at: current_timestamp(),
+					attempts: 0,
+					difficulty_bits,
+					return_to: None,
+				})
+				.await
+				.unwrap();
+
+			let request = Request::builder()
+				.method(Method::POST)
+				.uri("/post_nonce")
+				.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+				.body(Body::from(format!("nonce={}&token={}", nonce, token)))
+				.unwrap();
+			let response = create_router(state.clone()).oneshot(request).await.unwrap();
+			assert_eq!(response.status(), expected);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_get_challenge_records_controller_difficulty() {
+		let mut config = Config::default();
+		config.pow.adaptive.enabled = true;
+		config.pow.adaptive.min_bits = Some(10);
+		config.pow.adaptive.max_bits = Some(11);
+		config.pow.adaptive.outstanding_high = 1;
+		let state = AppState::new(config).unwrap();
+		state.difficulty.adjust(10, 5);
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/get_challenge")
+			.body(Body::empty())
+			.unwrap();
+		let response = create_router(state.clone()).oneshot(request).await.unwrap();
+		let token = challenge_token(response).await;
+
+		let stored = state.challenges.get(&token).await.unwrap().unwrap();
+		assert_eq!(stored.difficulty_bits, 11);
+	}
+
+	#[tokio::test]
+	async fn test_get_challenge_records_return_to() {
+		let state = test_state();
+		let get_challenge = |uri: &str, original_uri: &str| {
+			Request::builder()
+				.method(Method::GET)
+				.uri(uri)
+				.header("x-original-uri", original_uri)
+				.body(Body::empty())
+				.unwrap()
+		};
+		let app = create_router(state.clone());
+		for (uri, original_uri, expected) in [
+			("/get_challenge", "/articles/123?page=2", Some("/articles/123?page=2")),
+			("/get_challenge?return_to=%2Fnews", "/articles/123", Some("/news")),
+			("/get_challenge", "//evil.example/", None),
+			("/get_challenge?return_to=https%3A%2F%2Fevil.example", "/articles/123", None),
+		] {
+			let response = app.clone().oneshot(get_challenge(uri, original_uri)).await.unwrap();
+			let token = challenge_token(response).await;
+			let stored = state.challenges.get(&token).await.unwrap().unwrap();
+			assert_eq!(stored.return_to.as_deref(), expected, "{} {}", uri, original_uri);
+		}
+	}
+
+	/// Connection from nginx on the same host, a trusted proxy by default
+	fn nginx_peer() -> ConnectInfo<SocketAddr> {
+		ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40_000)))
+	}
+
+	/// Token embedded in a challenge page
+	async fn challenge_token(response: Response) -> String {
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let body_str = String::from_utf8(body.to_vec()).unwrap();
+		body_str
+			.split("const token = \"")
+			.nth(1)
+			.and_then(|rest| rest.split('"').next())
+			.unwrap()
+			.to_owned()
+	}
+
+	#[tokio::test]
+	async fn test_reputation_raises_difficulty_for_failing_client() {
+		let mut config = Config::default();
+		config.pow.reputation.enabled = true;
+		config.pow.reputation.points_per_bit = 4.0;
+		config.pow.reputation.max_extra_bits = 2;
+		let state = AppState::new(config).unwrap();
+		let app = create_router(state.clone());
+
+		let get_challenge = |ip: &str| {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/get_challenge")
+				.header("x-real-ip", ip)
+				.extension(nginx_peer())
+				.body(Body::empty())
+				.unwrap()
+		};
+		for _ in 0..4 {
+			let request = Request::builder()
+				.method(Method::POST)
+				.uri("/post_nonce")
+				.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+				.header("x-forwarded-for", "203.0.113.9")
+				.extension(nginx_peer())
+				.body(Body::from("nonce=1&token=unknown"))
+				.unwrap();
+			app.clone().oneshot(request).await.unwrap();
+		}
+
+		let response = app.clone().oneshot(get_challenge("203.0.113.9")).await.unwrap();
+		let token = challenge_token(response).await;
+		let stored = state.challenges.get(&token).await.unwrap().unwrap();
+		assert_eq!(stored.difficulty_bits, POW_DIFFICULTY_BITS + 2);
+
+		let response = app.clone().oneshot(get_challenge("198.51.100.7")).await.unwrap();
+		let token = challenge_token(response).await;
+		let stored = state.challenges.get(&token).await.unwrap().unwrap();
+		assert_eq!(stored.difficulty_bits, POW_DIFFICULTY_BITS);
+
+		// Reached directly, the spoofed header is ignored and the socket address is scored
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/get_challenge")
+			.header("x-real-ip", "203.0.113.9")
+			.extension(ConnectInfo(SocketAddr::from(([198, 51, 100, 8], 50_000))))
+			.body(Body::empty())
+			.unwrap();
+		let token = challenge_token(app.oneshot(request).await.unwrap()).await;
+		let stored = state.challenges.get(&token).await.unwrap().unwrap();
+		assert_eq!(stored.difficulty_bits, POW_DIFFICULTY_BITS);
+	}
+
+	#[tokio::test]
+	async fn test_validate_enforces_request_budget() {
+		let mut config = Config::default();
+		config.session.quota.max_requests = 3;
+		let state = AppState::new(config).unwrap();
+		let jwt_token = issue_jwt("test_user", &state.keyring.read().unwrap(), 60).unwrap();
+		let app = create_router(state);
+
+		let validate = || {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/validate")
+				.header(header::COOKIE, format!("{}={}", COOKIE_NAME, jwt_token))
+				.body(Body::empty())
+				.unwrap()
+		};
+		for _ in 0..3 {
+			let response = app.clone().oneshot(validate()).await.unwrap();
+			assert_eq!(response.status(), StatusCode::OK);
+		}
+		let response = app.oneshot(validate()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		assert_eq!(response.headers()["refresh"], "0; url=/get_challenge");
+	}
+
+	#[tokio::test]
+	async fn test_validate_refuses_token_without_jti_under_quota() {
+		let mut config = Config::default();
+		config.session.jwt_secret = Some(hex::encode(generate_secret()));
+		let unlimited = AppState::new(config.clone()).unwrap();
+		config.session.quota.max_requests = 3;
+		let state = AppState::new(config).unwrap();
+		let claims = Claims {
+			jti: String::new(),
+			..Claims::new("test_user", &state.config.session.token_scope(), 60).unwrap()
+		};
+		let jwt_token = sign_jwt(&claims, &state.keyring.read().unwrap()).unwrap();
+		let validate = || {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/validate")
+				.header(header::COOKIE, format!("{}={}", COOKIE_NAME, jwt_token))
+				.body(Body::empty())
+				.unwrap()
+		};
+
+		let response = create_router(state).oneshot(validate()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let response = create_router(unlimited).oneshot(validate()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_validate_refreshes_aging_token() {
+		let mut config = Config::default();
+		config.session.token_expiry_secs = 3_600;
+		config.session.refresh_after_secs = 600;
+		let state = AppState::new(config).unwrap();
+		let now = current_timestamp() as usize;
+		let scope = state.config.session.token_scope();
+		let aging = Claims {
+			iat: now - 900,
+			exp: now + 2_700,
+			auth_time: now - 900,
+			..Claims::new("test_user", &scope, 0).unwrap()
+		};
+		let fresh = Claims::new("test_user", &scope, 3_600).unwrap();
+		let sign = |claims: &Claims| sign_jwt(claims, &state.keyring.read().unwrap()).unwrap();
+		let (aging_token, fresh_token) = (sign(&aging), sign(&fresh));
+		let app = create_router(state.clone());
+
+		let validate = |token: &str| {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/validate")
+				.header(header::COOKIE, format!("{}={}", COOKIE_NAME, token))
+				.body(Body::empty())
+				.unwrap()
+		};
+		let response = app.clone().oneshot(validate(&fresh_token)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(response.headers().get(REFRESH_COOKIE_HEADER).is_none());
+
+		let response = app.oneshot(validate(&aging_token)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let cookie = response.headers()[REFRESH_COOKIE_HEADER].to_str().unwrap();
+		assert_eq!(response.headers()[header::SET_COOKIE], cookie);
+		assert!(cookie.contains("Max-Age=3600"));
+		let token = cookie.split(';').next().unwrap().trim_start_matches("mpow_token=");
+		let refreshed = validate_jwt(token, &state.keyring.read().unwrap(), &scope).unwrap();
+		assert_eq!(refreshed.jti, aging.jti);
+		assert_eq!(refreshed.auth_time, aging.auth_time);
+		assert!(refreshed.exp > aging.exp);
+	}
+
+	#[tokio::test]
+	async fn test_bound_session_rejected_elsewhere() {
+		let mut config = Config::default();
+		config.session.binding = BindingPolicy::Strict;
+		let state = AppState::new(config).unwrap();
+		state
+			.challenges
+			.insert(Challenge {
+				token: String::from("bound"),
+				challenge: String::from("bound_challenge"),
+				created_at: current_timestamp(),
+				attempts: 0,
+				difficulty_bits: 4,
+				return_to: None,
+			})
+			.await
+			.unwrap();
+		let app = create_router(state);
+
+		let request = Request::builder()
+			.method(Method::POST)
+			.uri("/post_nonce")
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.header("x-real-ip", "203.0.113.7")
+			.header(header::USER_AGENT, "solver")
+			.extension(nginx_peer())
+			.body(Body::from(format!("nonce={}&token=bound", find_valid_nonce("bound_challenge", 4))))
+			.unwrap();
+		let response = app.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+		let cookie = cookie.split(';').next().unwrap().to_owned();
+
+		let validate = |ip: &str, user_agent: &str| {
+			Request::builder()
+				.method(Method::GET)
+				.uri("/validate")
+				.header(header::COOKIE, cookie.as_str())
+				.header("x-real-ip", ip)
+				.header(header::USER_AGENT, user_agent)
+				.extension(nginx_peer())
+				.body(Body::empty())
+				.unwrap()
+		};
+		for (ip, user_agent, expected) in [
+			("203.0.113.99", "solver", StatusCode::OK),
+			("203.0.113.7", "scraper", StatusCode::UNAUTHORIZED),
+			("198.51.100.7", "solver", StatusCode::UNAUTHORIZED),
+		] {
+			let response = app.clone().oneshot(validate(ip, user_agent)).await.unwrap();
+			assert_eq!(response.status(), expected, "{} {}", ip, user_agent);
+		}
 	}
 
 	#[tokio::test]
 	async fn test_challenge_expiry() {
-		let state = AppState::new();
+		let state = test_state();
 		let token = "expired_token";
 		let challenge = "expired_challenge";
 
@@ -396,12 +1445,11 @@ mod tests {
 			challenge: challenge.to_string(),
 			created_at: current_timestamp() - CHALLENGE_EXPIRY_SECS - 1,
 			attempts: 0,
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			return_to: None,
 		};
 
-		{
-			let mut challenges = state.challenges.lock().unwrap();
-			challenges.insert(token.to_string(), expired_challenge);
-		}
+		state.challenges.insert(expired_challenge).await.unwrap();
 
 		let app = Router::new()
 			.route("/post_nonce", post(handle_post_nonce))
@@ -430,7 +1478,7 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_too_many_attempts() {
-		let state = AppState::new();
+		let state = test_state();
 		let token = "max_attempts_token";
 		let challenge = "max_attempts_challenge";
 
@@ -440,12 +1488,11 @@ mod tests {
 			challenge: challenge.to_string(),
 			created_at: current_timestamp(),
 			attempts: MAX_ATTEMPTS,
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			return_to: None,
 		};
 
-		{
-			let mut challenges = state.challenges.lock().unwrap();
-			challenges.insert(token.to_string(), max_attempts_challenge);
-		}
+		state.challenges.insert(max_attempts_challenge).await.unwrap();
 
 		let app = Router::new()
 			.route("/post_nonce", post(handle_post_nonce))
@@ -474,7 +1521,7 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_invalid_nonce() {
-		let state = AppState::new();
+		let state = test_state();
 		let token = "invalid_nonce_token";
 		let challenge = "invalid_nonce_challenge";
 
@@ -483,12 +1530,11 @@ mod tests {
 			challenge: challenge.to_string(),
 			created_at: current_timestamp(),
 			attempts: 0,
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			return_to: None,
 		};
 
-		{
-			let mut challenges = state.challenges.lock().unwrap();
-			challenges.insert(token.to_string(), valid_challenge);
-		}
+		state.challenges.insert(valid_challenge).await.unwrap();
 
 		let app = Router::new()
 			.route("/post_nonce", post(handle_post_nonce))
@@ -520,27 +1566,27 @@ mod tests {
 	async fn test_extract_token_from_cookie() {
 		// Test with valid cookie
 		let cookie_str = format!("{}=test_token_value; other=value", COOKIE_NAME);
-		let token = extract_token_from_cookie(&cookie_str);
+		let token = extract_token_from_cookie(&cookie_str, COOKIE_NAME);
 		assert_eq!(token, Some("test_token_value".to_string()));
 
 		// Test with multiple cookies
 		let cookie_str = format!("first=value1; {}=test_token; last=value2", COOKIE_NAME);
-		let token = extract_token_from_cookie(&cookie_str);
+		let token = extract_token_from_cookie(&cookie_str, COOKIE_NAME);
 		assert_eq!(token, Some("test_token".to_string()));
 
 		// Test with no matching cookie
 		let cookie_str = "other=value; another=value2";
-		let token = extract_token_from_cookie(&cookie_str);
+		let token = extract_token_from_cookie(cookie_str, COOKIE_NAME);
 		assert_eq!(token, None);
 
 		// Test with empty string
-		let token = extract_token_from_cookie("");
+		let token = extract_token_from_cookie("", COOKIE_NAME);
 		assert_eq!(token, None);
 	}
 
 	#[tokio::test]
 	async fn test_validate_with_invalid_jwt() {
-		let state = AppState::new();
+		let state = test_state();
 		let invalid_jwt = "invalid.jwt.token";
 		let cookie_value = format!("{}={}", COOKIE_NAME, invalid_jwt);
 
@@ -569,7 +1615,7 @@ mod tests {
 
 	#[tokio::test]
 	async fn test_challenge_cleanup_after_success() {
-		let state = AppState::new();
+		let state = test_state();
 		let token = "cleanup_token";
 		let challenge = "cleanup_challenge";
 
@@ -578,14 +1624,13 @@ mod tests {
 			challenge: challenge.to_string(),
 			created_at: current_timestamp(),
 			attempts: 0,
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			return_to: None,
 		};
 
-		{
-			let mut challenges = state.challenges.lock().unwrap();
-			challenges.insert(token.to_string(), valid_challenge);
-		}
+		state.challenges.insert(valid_challenge).await.unwrap();
 
-		let valid_nonce = find_valid_nonce(challenge, POW_DIFFICULTY_PREFIX);
+		let valid_nonce = find_valid_nonce(challenge, POW_DIFFICULTY_BITS);
 
 		let app = Router::new()
 			.route("/post_nonce", post(handle_post_nonce))
@@ -605,15 +1650,12 @@ mod tests {
 		assert_eq!(response.status(), StatusCode::OK);
 
 		// Verify that the challenge was removed from the map
-		{
-			let challenges = state.challenges.lock().unwrap();
-			assert!(!challenges.contains_key(token));
-		}
+		assert!(state.challenges.get(token).await.unwrap().is_none());
 	}
 
 	#[tokio::test]
 	async fn test_attempt_increment() {
-		let state = AppState::new();
+		let state = test_state();
 		let token = "attempt_token";
 		let challenge = "attempt_challenge";
 
@@ -622,12 +1664,11 @@ mod tests {
 			challenge: challenge.to_string(),
 			created_at: current_timestamp(),
 			attempts: 0,
+			difficulty_bits: POW_DIFFICULTY_BITS,
+			return_to: None,
 		};
 
-		{
-			let mut challenges = state.challenges.lock().unwrap();
-			challenges.insert(token.to_string(), valid_challenge);
-		}
+		state.challenges.insert(valid_challenge).await.unwrap();
 
 		let app = Router::new()
 			.route("/post_nonce", post(handle_post_nonce))
@@ -648,10 +1689,144 @@ mod tests {
 		assert_eq!(response.status(), StatusCode::FORBIDDEN);
 
 		// Verify that attempts were incremented
-		{
-			let challenges = state.challenges.lock().unwrap();
-			let challenge = challenges.get(token).unwrap();
-			assert_eq!(challenge.attempts, 1);
+		let challenge = state.challenges.get(token).await.unwrap().unwrap();
+		assert_eq!(challenge.attempts, 1);
+	}
+
+	fn stateless_state() -> AppState {
+		let mut config = Config::default();
+		config.pow.mode = ChallengeMode::Stateless;
+		AppState::new(config).unwrap()
+	}
+
+	fn nonce_request(challenge: &str, nonce: &str, user_agent: &str) -> Request<Body> {
+		Request::builder()
+			.method(Method::POST)
+			.uri("/post_nonce")
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.header("x-real-ip", "203.0.113.7")
+			.header(header::USER_AGENT, user_agent)
+			.extension(nginx_peer())
+			.body(Body::from(format!("nonce={}&token={}", nonce, challenge)))
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_stateless_challenge_is_not_stored() {
+		let state = stateless_state();
+		let app = create_router(state.clone());
+
+		let request = Request::builder()
+			.method(Method::GET)
+			.uri("/get_challenge")
+			.body(Body::empty())
+			.unwrap();
+		let response = app.oneshot(request).await.unwrap();
+
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(state.challenges.is_empty().await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_stateless_solution_accepted_once() {
+		let state = stateless_state();
+		let binding = client_binding(Some("203.0.113.7"), Some("test-agent"));
+		let challenge = state.signer.issue(current_timestamp(), POW_DIFFICULTY_BITS, &binding);
+		let nonce = find_valid_nonce(&challenge, POW_DIFFICULTY_BITS);
+		let app = create_router(state);
+
+		let response = app
+			.clone()
+			.oneshot(nonce_request(&challenge, &nonce, "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(response.headers().get("set-cookie").is_some());
+
+		let response = app
+			.oneshot(nonce_request(&challenge, &nonce, "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		assert_eq!(&body[..], b"Challenge already solved");
+	}
+
+	#[tokio::test]
+	async fn test_stateless_replay_refused_before_verifying() {
+		let mut state = stateless_state();
+		let binding = client_binding(Some("203.0.113.7"), Some("test-agent"));
+		let challenge = state.signer.issue(current_timestamp(), POW_DIFFICULTY_BITS, &binding);
+		let nonce = find_valid_nonce(&challenge, POW_DIFFICULTY_BITS);
+		let response = create_router(state.clone())
+			.oneshot(nonce_request(&challenge, &nonce, "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+
+		// Any check reaching this verifier would answer 503
+		state.verifier = Arc::new(Verifier::saturated(crate::pow::Algorithm::Scrypt { log_n: 10, r: 8, p: 1 }));
+		let app = create_router(state);
+		let response = app
+			.clone()
+			.oneshot(nonce_request(&challenge, &nonce, "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let response = app
+			.oneshot(nonce_request(&challenge, "wrong", "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test]
+	async fn test_stateless_attempts_are_capped() {
+		let state = stateless_state();
+		let binding = client_binding(Some("203.0.113.7"), Some("test-agent"));
+		let challenge = state.signer.issue(current_timestamp(), POW_DIFFICULTY_BITS, &binding);
+		let nonce = find_valid_nonce(&challenge, POW_DIFFICULTY_BITS);
+		let max_attempts = state.config.pow.max_attempts;
+		let app = create_router(state);
+
+		for _ in 0..max_attempts {
+			let response = app
+				.clone()
+				.oneshot(nonce_request(&challenge, "wrong", "test-agent"))
+				.await
+				.unwrap();
+			assert_eq!(response.status(), StatusCode::FORBIDDEN);
 		}
+		let response = app
+			.oneshot(nonce_request(&challenge, &nonce, "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+	}
+
+	#[tokio::test]
+	async fn test_stateless_rejects_other_client_and_forgery() {
+		let state = stateless_state();
+		let binding = client_binding(Some("203.0.113.7"), Some("test-agent"));
+		let challenge = state.signer.issue(current_timestamp(), POW_DIFFICULTY_BITS, &binding);
+		let nonce = find_valid_nonce(&challenge, POW_DIFFICULTY_BITS);
+		let forged = ChallengeSigner::new(b"attacker-key").issue(current_timestamp(), 1, &binding);
+		let forged_nonce = find_valid_nonce(&forged, 1);
+		let app = create_router(state.clone());
+
+		let response = app
+			.clone()
+			.oneshot(nonce_request(&challenge, &nonce, "other-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+		let response = app
+			.oneshot(nonce_request(&forged, &forged_nonce, "test-agent"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
 	}
 }
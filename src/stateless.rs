@@ -0,0 +1,477 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use ring::hmac;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Mutex,
+};
+
+use crate::store::SharedStore;
+
+/// Seconds a challenge's issue time may lie ahead of the verifying replica's clock
+pub const CLOCK_SKEW_SECS: u64 = 30;
+
+/// How challenges are tracked between issue and solution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMode {
+	/// Challenges are kept in server memory until solved or expired
+	#[default]
+	Stateful,
+	/// Challenges are self-describing and authenticated with an HMAC
+	Stateless,
+}
+
+impl std::str::FromStr for ChallengeMode {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"stateful" => Ok(ChallengeMode::Stateful),
+			"stateless" => Ok(ChallengeMode::Stateless),
+			_ => Err(String::from("expected one of stateful, stateless")),
+		}
+	}
+}
+
+/// Fields authenticated by a signed challenge
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedChallenge {
+	pub issued_at: u64,
+	/// Leading zero bits required
+	pub difficulty: u32,
+	pub binding: String,
+	/// Unique per challenge; recorded in the spent-set once solved
+	pub id: [u8; 16],
+}
+
+/// Why a signed challenge was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignedChallengeError {
+	Malformed,
+	BadSignature,
+	Expired,
+	WrongClient,
+}
+
+/// Issues and verifies challenges of the form
+/// `<issued_at>.<difficulty>.<binding>.<salt>.<mac>`
+///
+/// The MAC covers every preceding field, so a client can neither extend the
+/// lifetime, lower the difficulty, nor move a challenge to another client.
+pub struct ChallengeSigner {
+	key: hmac::Key,
+}
+
+impl ChallengeSigner {
+	pub fn new(secret: &[u8]) -> Self {
+		Self {
+			key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+		}
+	}
+
+	/// Creates a signed challenge
+	pub fn issue(&self, issued_at: u64, difficulty: u32, binding: &str) -> String {
+		let mut salt = [0u8; 16];
+		rand::rng().fill_bytes(&mut salt);
+		let payload = format!("{}.{}.{}.{}", issued_at, difficulty, binding, hex::encode(salt));
+		let tag = hmac::sign(&self.key, payload.as_bytes());
+		format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(tag.as_ref()))
+	}
+
+	/// Checks a signed challenge's MAC, age and client binding
+	///
+	/// # Arguments
+	/// * `challenge` - the challenge string as issued
+	/// * `now` - current unix time
+	/// * `expiry_secs` - maximum challenge age
+	/// * `binding` - binding of the client presenting the challenge
+	pub fn verify(
+		&self,
+		challenge: &str,
+		now: u64,
+		expiry_secs: u64,
+		binding: &str,
+	) -> Result<SignedChallenge, SignedChallengeError> {
+		let (payload, mac) = challenge
+			.rsplit_once('.')
+			.ok_or(SignedChallengeError::Malformed)?;
+		let mac = URL_SAFE_NO_PAD
+			.decode(mac)
+			.map_err(|_| SignedChallengeError::Malformed)?;
+		hmac::verify(&self.key, payload.as_bytes(), &mac)
+			.map_err(|_| SignedChallengeError::BadSignature)?;
+
+		let fields: Vec<&str> = payload.split('.').collect();
+		let [issued_at, difficulty, signed_binding, _salt] = fields[..] else {
+			return Err(SignedChallengeError::Malformed);
+		};
+		let issued_at: u64 = issued_at.parse().map_err(|_| SignedChallengeError::Malformed)?;
+		let difficulty: u32 = difficulty.parse().map_err(|_| SignedChallengeError::Malformed)?;
+
+		// Allow a little clock skew between replicas
+		if issued_at > now.saturating_add(CLOCK_SKEW_SECS) || now.saturating_sub(issued_at) > expiry_secs {
+			return Err(SignedChallengeError::Expired);
+		}
+		if signed_binding != binding {
+			return Err(SignedChallengeError::WrongClient);
+		}
+
+		let mut id = [0u8; 16];
+		id.copy_from_slice(&mac[..16]);
+		Ok(SignedChallenge {
+			issued_at,
+			difficulty,
+			binding: signed_binding.to_owned(),
+			id,
+		})
+	}
+}
+
+/// Derives the short client binding embedded in signed challenges
+///
+/// # Arguments
+/// * `ip` - client address as reported by the proxy, if known
+/// * `user_agent` - `User-Agent` header, if present
+pub fn client_binding(ip: Option<&str>, user_agent: Option<&str>) -> String {
+	let mut hasher = Sha256::new();
+	hasher.update(ip.unwrap_or_default().as_bytes());
+	hasher.update([0u8]);
+	hasher.update(user_agent.unwrap_or_default().as_bytes());
+	hex::encode(&hasher.finalize()[..8])
+}
+
+/// Remembers solved challenge ids long enough to refuse replays
+///
+/// Ids live in two generations, each `window_secs` long, so an id is kept for
+/// at least one full window (the challenge lifetime plus clock skew) after being recorded.
+/// Memory is bounded by `capacity`; when full, new solutions are refused rather
+/// than forgetting ids that could then be replayed. Attempts on unsolved ids are
+/// counted until their challenge expires, under the same bound.
+pub struct SpentSet {
+	current: HashSet<[u8; 16]>,
+	previous: HashSet<[u8; 16]>,
+	/// Attempts per unsolved id, with the issue time of its challenge
+	attempts: HashMap<[u8; 16], (u32, u64)>,
+	window_start: u64,
+	window_secs: u64,
+	capacity: usize,
+}
+
+/// Outcome of recording a solved challenge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpendResult {
+	Recorded,
+	AlreadySpent,
+	Full,
+}
+
+impl SpentSet {
+	pub fn new(window_secs: u64, capacity: usize) -> Self {
+		Self {
+			current: HashSet::new(),
+			previous: HashSet::new(),
+			attempts: HashMap::new(),
+			window_start: 0,
+			window_secs,
+			capacity,
+		}
+	}
+
+	/// Records `id` as spent at `now` unless it already was
+	pub fn spend(&mut self, id: [u8; 16], now: u64) -> SpendResult {
+		self.advance(now);
+		if self.current.contains(&id) || self.previous.contains(&id) {
+			return SpendResult::AlreadySpent;
+		}
+		if self.current.len() + self.previous.len() >= self.capacity {
+			return SpendResult::Full;
+		}
+		self.current.insert(id);
+		self.attempts.remove(&id);
+		SpendResult::Recorded
+	}
+
+	/// Whether `id` was recorded as spent
+	pub fn contains(&mut self, id: &[u8; 16], now: u64) -> bool {
+		self.advance(now);
+		self.current.contains(id) || self.previous.contains(id)
+	}
+
+	/// Counts one more solution attempt on `id`, from a challenge issued at `issued_at`
+	///
+	/// # Returns
+	/// The number of attempts so far, this one included, or `None` if `capacity`
+	/// ids are already being counted
+	pub fn count_attempt(&mut self, id: [u8; 16], issued_at: u64, now: u64) -> Option<u32> {
+		self.advance(now);
+		if !self.attempts.contains_key(&id) && self.attempts.len() >= self.capacity {
+			self.forget_expired_attempts(now);
+			if self.attempts.len() >= self.capacity {
+				return None;
+			}
+		}
+		let (attempts, _) = self.attempts.entry(id).or_insert((0, issued_at));
+		*attempts += 1;
+		Some(*attempts)
+	}
+
+	fn advance(&mut self, now: u64) {
+		let elapsed = now.saturating_sub(self.window_start);
+		if elapsed < self.window_secs {
+			return;
+		}
+		if elapsed < 2 * self.window_secs {
+			self.previous = std::mem::take(&mut self.current);
+			self.window_start += self.window_secs;
+		} else {
+			self.previous.clear();
+			self.current.clear();
+			self.window_start = now;
+		}
+		self.forget_expired_attempts(now);
+	}
+
+	fn forget_expired_attempts(&mut self, now: u64) {
+		let window_secs = self.window_secs;
+		self.attempts
+			.retain(|_, (_, issued_at)| issued_at.saturating_add(window_secs) >= now);
+	}
+}
+
+/// Where solved stateless challenges are remembered
+///
+/// A store shared by every replica keeps them itself, so a solution is accepted
+/// once across the deployment. Otherwise each process has its own [`SpentSet`]
+/// and a solution can be redeemed once per replica.
+pub enum SpentIds {
+	Local(Mutex<SpentSet>),
+	Shared { store: SharedStore, expiry_secs: u64 },
+}
+
+impl SpentIds {
+	/// Shares spent ids through `store` when it supports that
+	///
+	/// Ids are kept as long as their challenge verifies: `expiry_secs` from an
+	/// issue time up to [`CLOCK_SKEW_SECS`] ahead of this replica's clock.
+	///
+	/// # Arguments
+	/// * `expiry_secs` - challenge lifetime
+	/// * `capacity` - bound on the ids kept by a [`SpentSet`]
+	pub fn new(store: &SharedStore, expiry_secs: u64, capacity: usize) -> Self {
+		if store.shares_spent_ids() {
+			SpentIds::Shared {
+				store: store.clone(),
+				expiry_secs,
+			}
+		} else {
+			let window_secs = expiry_secs.saturating_add(CLOCK_SKEW_SECS);
+			SpentIds::Local(Mutex::new(SpentSet::new(window_secs, capacity)))
+		}
+	}
+
+	/// Records `id`, from a challenge issued at `issued_at`, as spent at `now`
+	/// unless it already was
+	pub async fn spend(&self, id: [u8; 16], issued_at: u64, now: u64) -> Result<SpendResult, String> {
+		match self {
+			SpentIds::Local(spent) => Ok(spent
+				.lock()
+				.map_err(|_| String::from("spent-set lock poisoned"))?
+				.spend(id, now)),
+			SpentIds::Shared { store, expiry_secs } => {
+				let ttl_secs = verifiable_for(issued_at, *expiry_secs, now);
+				if store.spend(&hex::encode(id), ttl_secs).await? {
+					Ok(SpendResult::Recorded)
+				} else {
+					Ok(SpendResult::AlreadySpent)
+				}
+			}
+		}
+	}
+
+	/// Whether `id` was recorded as spent
+	pub async fn contains(&self, id: [u8; 16], now: u64) -> Result<bool, String> {
+		match self {
+			SpentIds::Local(spent) => Ok(spent
+				.lock()
+				.map_err(|_| String::from("spent-set lock poisoned"))?
+				.contains(&id, now)),
+			SpentIds::Shared { store, .. } => store.is_spent(&hex::encode(id)).await,
+		}
+	}
+
+	/// Counts one more solution attempt on `id`, from a challenge issued at `issued_at`
+	///
+	/// # Returns
+	/// The number of attempts so far, this one included, or `None` if too many
+	/// challenges are already being counted
+	pub async fn count_attempt(&self, id: [u8; 16], issued_at: u64, now: u64) -> Result<Option<u32>, String> {
+		match self {
+			SpentIds::Local(spent) => Ok(spent
+				.lock()
+				.map_err(|_| String::from("spent-set lock poisoned"))?
+				.count_attempt(id, issued_at, now)),
+			SpentIds::Shared { store, expiry_secs } => {
+				let ttl_secs = verifiable_for(issued_at, *expiry_secs, now);
+				store.count_attempt(&hex::encode(id), ttl_secs).await.map(Some)
+			}
+		}
+	}
+}
+
+/// Seconds a challenge issued at `issued_at` may still verify on some replica
+fn verifiable_for(issued_at: u64, expiry_secs: u64, now: u64) -> u64 {
+	issued_at
+		.saturating_add(expiry_secs)
+		.saturating_add(CLOCK_SKEW_SECS)
+		.saturating_sub(now)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BINDING: &str = "0011223344556677";
+
+	#[test]
+	fn test_issue_and_verify() {
+		let signer = ChallengeSigner::new(b"test-secret");
+		let challenge = signer.issue(1_000, 16, BINDING);
+
+		let verified = signer.verify(&challenge, 1_010, 300, BINDING).unwrap();
+		assert_eq!(verified.issued_at, 1_000);
+		assert_eq!(verified.difficulty, 16);
+		assert_eq!(verified.binding, BINDING);
+	}
+
+	#[test]
+	fn test_tampered_difficulty_rejected() {
+		let signer = ChallengeSigner::new(b"test-secret");
+		let challenge = signer.issue(1_000, 4, BINDING);
+		let tampered = challenge.replacen(".4.", ".1.", 1);
+
+		assert_eq!(
+			signer.verify(&tampered, 1_010, 300, BINDING),
+			Err(SignedChallengeError::BadSignature)
+		);
+	}
+
+	#[test]
+	fn test_other_key_rejected() {
+		let challenge = ChallengeSigner::new(b"key-a").issue(1_000, 4, BINDING);
+		assert_eq!(
+			ChallengeSigner::new(b"key-b").verify(&challenge, 1_010, 300, BINDING),
+			Err(SignedChallengeError::BadSignature)
+		);
+	}
+
+	#[test]
+	fn test_expired_and_wrong_client() {
+		let signer = ChallengeSigner::new(b"test-secret");
+		let challenge = signer.issue(1_000, 4, BINDING);
+
+		assert_eq!(
+			signer.verify(&challenge, 1_301, 300, BINDING),
+			Err(SignedChallengeError::Expired)
+		);
+		assert_eq!(
+			signer.verify(&challenge, 1_010, 300, "ffffffffffffffff"),
+			Err(SignedChallengeError::WrongClient)
+		);
+		assert_eq!(
+			signer.verify("garbage", 1_010, 300, BINDING),
+			Err(SignedChallengeError::Malformed)
+		);
+	}
+
+	#[test]
+	fn test_client_binding() {
+		let a = client_binding(Some("203.0.113.7"), Some("Firefox"));
+		assert_eq!(a, client_binding(Some("203.0.113.7"), Some("Firefox")));
+		assert_ne!(a, client_binding(Some("203.0.113.8"), Some("Firefox")));
+		assert_ne!(a, client_binding(Some("203.0.113.7"), Some("Chrome")));
+		assert_eq!(a.len(), 16);
+	}
+
+	#[test]
+	fn test_spent_set_refuses_replay() {
+		let mut spent = SpentSet::new(300, 10);
+		assert_eq!(spent.spend([1; 16], 1_000), SpendResult::Recorded);
+		assert_eq!(spent.spend([1; 16], 1_100), SpendResult::AlreadySpent);
+	}
+
+	#[test]
+	fn test_spent_set_keeps_ids_for_a_full_window() {
+		let mut spent = SpentSet::new(300, 10);
+		spent.spend([0; 16], 1_000);
+		spent.spend([1; 16], 1_299);
+
+		// One rotation later the id is still remembered
+		assert_eq!(spent.spend([1; 16], 1_599), SpendResult::AlreadySpent);
+		// After two windows everything is forgotten
+		assert_eq!(spent.spend([1; 16], 2_500), SpendResult::Recorded);
+		assert!(spent.previous.is_empty());
+	}
+
+	#[test]
+	fn test_spent_set_counts_attempts_until_expiry() {
+		let mut spent = SpentSet::new(300, 1);
+		assert_eq!(spent.count_attempt([1; 16], 1_000, 1_000), Some(1));
+		assert_eq!(spent.count_attempt([1; 16], 1_000, 1_010), Some(2));
+		assert_eq!(spent.count_attempt([2; 16], 1_000, 1_010), None);
+
+		// Spending forgets the count, the id itself refuses further attempts
+		assert_eq!(spent.spend([1; 16], 1_020), SpendResult::Recorded);
+		assert!(spent.contains(&[1; 16], 1_020));
+		assert_eq!(spent.count_attempt([2; 16], 1_000, 1_020), Some(1));
+
+		// Counts of expired challenges make room for new ones
+		assert_eq!(spent.count_attempt([3; 16], 1_400, 1_400), Some(1));
+	}
+
+	#[tokio::test]
+	async fn test_local_store_keeps_spent_ids_in_process() {
+		use crate::{
+			challenges::EvictionPolicy,
+			store::{MemoryStore, StoreLimits},
+		};
+		let limits = StoreLimits {
+			capacity: 10,
+			policy: EvictionPolicy::Oldest,
+			expiry_secs: 300,
+		};
+		let store: SharedStore = std::sync::Arc::new(MemoryStore::new(limits, 1));
+		let spent = SpentIds::new(&store, 300, 10);
+		assert!(matches!(spent, SpentIds::Local(_)));
+		assert_eq!(spent.spend([1; 16], 1_000, 1_000).await.unwrap(), SpendResult::Recorded);
+		assert_eq!(spent.spend([1; 16], 1_000, 1_001).await.unwrap(), SpendResult::AlreadySpent);
+
+		// Solved late in a window, a challenge stamped ahead of this clock is
+		// remembered for as long as it verifies
+		assert_eq!(spent.spend([2; 16], 2_000, 2_000).await.unwrap(), SpendResult::Recorded);
+		let signer = ChallengeSigner::new(b"test-secret");
+		let ahead = signer.issue(2_299 + CLOCK_SKEW_SECS, 4, BINDING);
+		let signed = signer.verify(&ahead, 2_299, 300, BINDING).unwrap();
+		assert_eq!(spent.spend(signed.id, signed.issued_at, 2_299).await.unwrap(), SpendResult::Recorded);
+		let last = signed.issued_at + 300;
+		assert!(signer.verify(&ahead, last, 300, BINDING).is_ok());
+		assert!(spent.contains(signed.id, last).await.unwrap());
+	}
+
+	#[test]
+	fn test_verifiable_for_covers_clock_skew() {
+		assert_eq!(verifiable_for(1_000, 300, 1_000), 300 + CLOCK_SKEW_SECS);
+		assert_eq!(verifiable_for(1_030, 300, 1_000), 330 + CLOCK_SKEW_SECS);
+		assert_eq!(verifiable_for(1_000, 300, 2_000), 0);
+	}
+
+	#[test]
+	fn test_spent_set_is_bounded() {
+		let mut spent = SpentSet::new(300, 2);
+		assert_eq!(spent.spend([1; 16], 1_000), SpendResult::Recorded);
+		assert_eq!(spent.spend([2; 16], 1_000), SpendResult::Recorded);
+		assert_eq!(spent.spend([3; 16], 1_000), SpendResult::Full);
+	}
+}
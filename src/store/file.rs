@@ -0,0 +1,229 @@
+use async_trait::async_trait;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use std::{path::Path, sync::Arc};
+
+use super::{ChallengeStore, StoreLimits};
+use crate::challenges::{Challenge, EvictionPolicy};
+
+type DbError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Challenges as JSON, keyed by token
+const CHALLENGES: TableDefinition<&str, &[u8]> = TableDefinition::new("challenges");
+/// Index of `(created_at, token)` for expiry and eviction in issue order
+const BY_AGE: TableDefinition<(u64, &str), ()> = TableDefinition::new("challenges_by_age");
+
+/// Challenge store kept in an embedded key-value file
+///
+/// Every operation is a single transaction, so pending challenges and their
+/// attempt counters survive a restart of the server.
+pub struct FileStore {
+	db: Arc<Database>,
+	limits: StoreLimits,
+}
+
+impl FileStore {
+	/// Opens the store at `path`, creating the file if needed
+	pub fn open(path: &Path, limits: StoreLimits) -> Result<Self, String> {
+		let db = Database::create(path)
+			.map_err(|e| format!("cannot open challenge store {}: {}", path.display(), e))?;
+		let txn = db.begin_write().map_err(|e| e.to_string())?;
+		txn.open_table(CHALLENGES).map_err(|e| e.to_string())?;
+		txn.open_table(BY_AGE).map_err(|e| e.to_string())?;
+		txn.commit().map_err(|e| e.to_string())?;
+		Ok(Self {
+			db: Arc::new(db),
+			limits,
+		})
+	}
+
+	/// Runs `f` inside a write transaction on the blocking thread pool
+	async fn write<T, F>(&self, f: F) -> Result<T, String>
+	where
+		T: Send + 'static,
+		F: FnOnce(&WriteTransaction, StoreLimits) -> Result<T, DbError> + Send + 'static,
+	{
+		let db = self.db.clone();
+		let limits = self.limits;
+		tokio::task::spawn_blocking(move || {
+			let txn = db.begin_write()?;
+			let result = f(&txn, limits)?;
+			txn.commit()?;
+			Ok(result)
+		})
+		.await
+		.map_err(|e| e.to_string())?
+		.map_err(|e: DbError| format!("challenge store: {}", e))
+	}
+}
+
+fn encode(challenge: &Challenge) -> Vec<u8> {
+	serde_json::to_vec(challenge).expect("challenge serializes")
+}
+
+fn decode(bytes: &[u8]) -> Result<Challenge, DbError> {
+	serde_json::from_slice(bytes).map_err(|e| format!("invalid challenge record: {}", e).into())
+}
+
+fn remove(txn: &WriteTransaction, token: &str) -> Result<Option<Challenge>, DbError> {
+	let removed = match txn.open_table(CHALLENGES)?.remove(token)? {
+		Some(bytes) => decode(bytes.value())?,
+		None => return Ok(None),
+	};
+	txn.open_table(BY_AGE)?.remove((removed.created_at, token))?;
+	Ok(Some(removed))
+}
+
+/// Removes challenges in issue order while `done` says more should go
+fn remove_oldest<F>(txn: &WriteTransaction, mut done: F) -> Result<usize, DbError>
+where
+	F: FnMut(u64, usize) -> bool,
+{
+	let mut removed = 0;
+	loop {
+		let oldest = txn
+			.open_table(BY_AGE)?
+			.first()?
+			.map(|(key, _)| {
+				let (created_at, token) = key.value();
+				(created_at, token.to_owned())
+			});
+		let Some((created_at, token)) = oldest else {
+			return Ok(removed);
+		};
+		if done(created_at, removed) {
+			return Ok(removed);
+		}
+		txn.open_table(CHALLENGES)?.remove(token.as_str())?;
+		txn.open_table(BY_AGE)?.remove((created_at, token.as_str()))?;
+		removed += 1;
+	}
+}
+
+fn sweep(txn: &WriteTransaction, now: u64, expiry_secs: u64) -> Result<usize, DbError> {
+	remove_oldest(txn, |created_at, _| now.saturating_sub(created_at) <= expiry_secs)
+}
+
+#[async_trait]
+impl ChallengeStore for FileStore {
+	async fn insert(&self, challenge: Challenge) -> Result<bool, String> {
+		self.write(move |txn, limits| {
+			let len = txn.open_table(CHALLENGES)?.len()? as usize;
+			if len >= limits.capacity {
+				let mut len = len - sweep(txn, challenge.created_at, limits.expiry_secs)?;
+				if len >= limits.capacity {
+					if limits.policy == EvictionPolicy::Reject {
+						return Ok(false);
+					}
+					let excess = len + 1 - limits.capacity;
+					len -= remove_oldest(txn, |_, removed| removed == excess)?;
+				}
+				if len >= limits.capacity {
+					return Ok(false);
+				}
+			}
+			txn.open_table(CHALLENGES)?
+				.insert(challenge.token.as_str(), encode(&challenge).as_slice())?;
+			txn.open_table(BY_AGE)?
+				.insert((challenge.created_at, challenge.token.as_str()), ())?;
+			Ok(true)
+		})
+		.await
+	}
+
+	async fn get(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let db = self.db.clone();
+		let token = token.to_owned();
+		tokio::task::spawn_blocking(move || -> Result<Option<Challenge>, DbError> {
+			let txn = db.begin_read()?;
+			let table = txn.open_table(CHALLENGES)?;
+			let stored = table.get(token.as_str())?;
+			stored.map(|bytes| decode(bytes.value())).transpose()
+		})
+		.await
+		.map_err(|e| e.to_string())?
+		.map_err(|e| format!("challenge store: {}", e))
+	}
+
+	async fn take(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let token = token.to_owned();
+		self.write(move |txn, _| remove(txn, &token)).await
+	}
+
+	async fn increment_attempts(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let token = token.to_owned();
+		self.write(move |txn, _| {
+			let mut table = txn.open_table(CHALLENGES)?;
+			let mut challenge = match table.get(token.as_str())? {
+				Some(bytes) => decode(bytes.value())?,
+				None => return Ok(None),
+			};
+			challenge.attempts += 1;
+			table.insert(token.as_str(), encode(&challenge).as_slice())?;
+			Ok(Some(challenge))
+		})
+		.await
+	}
+
+	async fn sweep(&self, now: u64) -> Result<usize, String> {
+		self.write(move |txn, limits| sweep(txn, now, limits.expiry_secs))
+			.await
+	}
+
+	async fn len(&self) -> Result<usize, String> {
+		let db = self.db.clone();
+		tokio::task::spawn_blocking(move || -> Result<usize, DbError> {
+			let txn = db.begin_read()?;
+			Ok(txn.open_table(CHALLENGES)?.len()? as usize)
+		})
+		.await
+		.map_err(|e| e.to_string())?
+		.map_err(|e| format!("challenge store: {}", e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::tests::{challenge, exercise_store, limits};
+
+	fn temp_path(name: &str) -> std::path::PathBuf {
+		std::env::temp_dir().join(format!("mpow-{}-{}.redb", name, uuid::Uuid::new_v4()))
+	}
+
+	#[tokio::test]
+	async fn test_file_store() {
+		let path = temp_path("store");
+		exercise_store(&FileStore::open(&path, limits(10, EvictionPolicy::Oldest)).unwrap()).await;
+		let _ = std::fs::remove_file(path);
+	}
+
+	#[tokio::test]
+	async fn test_challenges_survive_reopen() {
+		let path = temp_path("store-reopen");
+		{
+			let store = FileStore::open(&path, limits(10, EvictionPolicy::Oldest)).unwrap();
+			store.insert(challenge("pending", 1_000)).await.unwrap();
+			store.increment_attempts("pending").await.unwrap();
+		}
+
+		let store = FileStore::open(&path, limits(10, EvictionPolicy::Oldest)).unwrap();
+		assert_eq!(store.get("pending").await.unwrap().unwrap().attempts, 1);
+		let _ = std::fs::remove_file(path);
+	}
+
+	#[tokio::test]
+	async fn test_bounded_insert() {
+		let path = temp_path("store-bounded");
+		let store = FileStore::open(&path, limits(2, EvictionPolicy::Oldest)).unwrap();
+		store.insert(challenge("a", 1_000)).await.unwrap();
+		store.insert(challenge("b", 1_001)).await.unwrap();
+		assert!(store.insert(challenge("c", 1_002)).await.unwrap());
+		assert!(store.get("a").await.unwrap().is_none());
+		assert_eq!(store.len().await.unwrap(), 2);
+		drop(store);
+
+		let store = FileStore::open(&path, limits(2, EvictionPolicy::Reject)).unwrap();
+		assert!(!store.insert(challenge("d", 1_003)).await.unwrap());
+		let _ = std::fs::remove_file(path);
+	}
+}
@@ -0,0 +1,117 @@
+use async_trait::async_trait;
+use std::{
+	hash::{BuildHasher, RandomState},
+	sync::{Mutex, MutexGuard},
+};
+
+use super::{ChallengeStore, StoreLimits};
+use crate::challenges::{Challenge, ChallengeMap};
+
+/// In-memory store split across independently locked shards
+///
+/// Tokens are spread over the shards by hash and each shard holds an equal
+/// share of the capacity, so eviction picks the oldest challenge of the shard
+/// being inserted into rather than the globally oldest one.
+pub struct MemoryStore {
+	shards: Vec<Mutex<ChallengeMap>>,
+	hasher: RandomState,
+	limits: StoreLimits,
+	shard_capacity: usize,
+}
+
+impl MemoryStore {
+	/// # Arguments
+	/// * `limits` - capacity, eviction policy and expiry
+	/// * `shards` - number of locks; `1` gives a single exact map
+	pub fn new(limits: StoreLimits, shards: usize) -> Self {
+		let shards = shards.max(1);
+		Self {
+			shards: (0..shards).map(|_| Mutex::new(ChallengeMap::new())).collect(),
+			hasher: RandomState::new(),
+			limits,
+			shard_capacity: limits.capacity.div_ceil(shards),
+		}
+	}
+
+	fn shard(&self, token: &str) -> Result<MutexGuard<'_, ChallengeMap>, String> {
+		let index = self.hasher.hash_one(token) as usize % self.shards.len();
+		lock(&self.shards[index])
+	}
+}
+
+fn lock(shard: &Mutex<ChallengeMap>) -> Result<MutexGuard<'_, ChallengeMap>, String> {
+	shard
+		.lock()
+		.map_err(|_| String::from("challenge store lock poisoned"))
+}
+
+#[async_trait]
+impl ChallengeStore for MemoryStore {
+	async fn insert(&self, challenge: Challenge) -> Result<bool, String> {
+		let mut shard = self.shard(&challenge.token)?;
+		Ok(shard.insert_bounded(
+			challenge,
+			self.shard_capacity,
+			self.limits.policy,
+			self.limits.expiry_secs,
+		))
+	}
+
+	async fn get(&self, token: &str) -> Result<Option<Challenge>, String> {
+		Ok(self.shard(token)?.get(token).cloned())
+	}
+
+	async fn take(&self, token: &str) -> Result<Option<Challenge>, String> {
+		Ok(self.shard(token)?.remove(token))
+	}
+
+	async fn increment_attempts(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let mut shard = self.shard(token)?;
+		Ok(shard.get_mut(token).map(|challenge| {
+			challenge.attempts += 1;
+			challenge.clone()
+		}))
+	}
+
+	async fn sweep(&self, now: u64) -> Result<usize, String> {
+		let mut removed = 0;
+		for shard in &self.shards {
+			removed += lock(shard)?.sweep(now, self.limits.expiry_secs);
+		}
+		Ok(removed)
+	}
+
+	async fn len(&self) -> Result<usize, String> {
+		let mut len = 0;
+		for shard in &self.shards {
+			len += lock(shard)?.len();
+		}
+		Ok(len)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::challenges::EvictionPolicy;
+	use crate::store::tests::{challenge, exercise_store, limits};
+
+	#[tokio::test]
+	async fn test_single_shard() {
+		exercise_store(&MemoryStore::new(limits(10, EvictionPolicy::Oldest), 1)).await;
+	}
+
+	#[tokio::test]
+	async fn test_sharded() {
+		exercise_store(&MemoryStore::new(limits(64, EvictionPolicy::Oldest), 8)).await;
+	}
+
+	#[tokio::test]
+	async fn test_sharded_capacity_is_bounded() {
+		let store = MemoryStore::new(limits(16, EvictionPolicy::Reject), 4);
+		for i in 0..100 {
+			store.insert(challenge(&i.to_string(), 1_000)).await.unwrap();
+		}
+		assert!(store.len().await.unwrap() <= 16);
+	}
+}
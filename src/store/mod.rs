@@ -0,0 +1,225 @@
+//! Storage for outstanding challenges
+//!
+//! Handlers only see the [`ChallengeStore`] trait; the backend is chosen by the
+//! `[store]` section of the configuration.
+
+mod file;
+mod memory;
+mod redis;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::challenges::{Challenge, EvictionPolicy};
+use crate::config::{PowConfig, StoreConfig};
+
+pub use file::FileStore;
+pub use memory::MemoryStore;
+pub use self::redis::RedisStore;
+
+/// Store shared between handlers and the janitor
+pub type SharedStore = Arc<dyn ChallengeStore>;
+
+/// Which [`ChallengeStore`] implementation to use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+	/// One in-memory map behind a single lock
+	#[default]
+	Memory,
+	/// In-memory maps split across `shards` locks
+	Sharded,
+	/// Embedded key-value file that survives restarts
+	File,
+	/// Redis-protocol server shared by every replica
+	Redis,
+}
+
+impl std::str::FromStr for StoreBackend {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"memory" => Ok(StoreBackend::Memory),
+			"sharded" => Ok(StoreBackend::Sharded),
+			"file" => Ok(StoreBackend::File),
+			"redis" => Ok(StoreBackend::Redis),
+			_ => Err(String::from("expected one of memory, sharded, file, redis")),
+		}
+	}
+}
+
+/// Bounds every store enforces
+#[derive(Debug, Clone, Copy)]
+pub struct StoreLimits {
+	/// Maximum number of outstanding challenges
+	pub capacity: usize,
+	/// What happens to new challenges once `capacity` is reached
+	pub policy: EvictionPolicy,
+	/// Age after which a challenge is expired
+	pub expiry_secs: u64,
+}
+
+impl StoreLimits {
+	pub fn from_config(pow: &PowConfig) -> Self {
+		Self {
+			capacity: pow.max_outstanding_challenges,
+			policy: pow.eviction_policy,
+			expiry_secs: pow.challenge_expiry_secs,
+		}
+	}
+}
+
+/// Outstanding challenges, keyed by token
+///
+/// Every operation is atomic with respect to the others, so two requests racing
+/// on the same token can never both `take` it.
+#[async_trait]
+pub trait ChallengeStore: Send + Sync {
+	/// Stores a new challenge, expiring or evicting old ones if the store is full
+	///
+	/// # Returns
+	/// `Ok(true)` if stored, `Ok(false)` if refused under [`EvictionPolicy::Reject`]
+	async fn insert(&self, challenge: Challenge) -> Result<bool, String>;
+
+	/// Returns a copy of the challenge without modifying it
+	async fn get(&self, token: &str) -> Result<Option<Challenge>, String>;
+
+	/// Removes the challenge and returns it if it was still present
+	async fn take(&self, token: &str) -> Result<Option<Challenge>, String>;
+
+	/// Counts one more solution attempt
+	///
+	/// # Returns
+	/// The challenge with its updated attempt count, or `None` if unknown
+	async fn increment_attempts(&self, token: &str) -> Result<Option<Challenge>, String>;
+
+	/// Removes every expired challenge
+	///
+	/// # Returns
+	/// The number of challenges removed
+	async fn sweep(&self, now: u64) -> Result<usize, String>;
+
+	/// Number of challenges currently stored
+	async fn len(&self) -> Result<usize, String>;
+
+	/// Whether no challenge is stored
+	async fn is_empty(&self) -> Result<bool, String> {
+		Ok(self.len().await? == 0)
+	}
+
+	/// Whether every replica sees the same store, so spent stateless challenge
+	/// ids are kept here rather than in each process
+	fn shares_spent_ids(&self) -> bool {
+		false
+	}
+
+	/// Records a solved stateless challenge id for `ttl_secs`; only called when
+	/// [`ChallengeStore::shares_spent_ids`]
+	///
+	/// # Returns
+	/// `Ok(true)` if recorded, `Ok(false)` if the id was already spent
+	async fn spend(&self, _id: &str, _ttl_secs: u64) -> Result<bool, String> {
+		Err(String::from("this store does not keep spent ids"))
+	}
+
+	/// Whether a stateless challenge id was recorded with [`ChallengeStore::spend`]
+	async fn is_spent(&self, _id: &str) -> Result<bool, String> {
+		Err(String::from("this store does not keep spent ids"))
+	}
+
+	/// Counts one more solution attempt on a stateless challenge id, forgetting
+	/// the count after `ttl_secs`
+	///
+	/// # Returns
+	/// The number of attempts so far, this one included
+	async fn count_attempt(&self, _id: &str, _ttl_secs: u64) -> Result<u32, String> {
+		Err(String::from("this store does not keep spent ids"))
+	}
+}
+
+/// Builds the store selected by the configuration
+pub fn open_store(store: &StoreConfig, pow: &PowConfig) -> Result<SharedStore, String> {
+	let limits = StoreLimits::from_config(pow);
+	Ok(match store.backend {
+		StoreBackend::Memory => Arc::new(MemoryStore::new(limits, 1)),
+		StoreBackend::Sharded => Arc::new(MemoryStore::new(limits, store.shards)),
+		StoreBackend::File => {
+			let path = store
+				.path
+				.as_deref()
+				.ok_or_else(|| String::from("store.path is required for the file backend"))?;
+			Arc::new(FileStore::open(path, limits)?)
+		}
+		StoreBackend::Redis => {
+			let url = store
+				.redis_url
+				.as_deref()
+				.ok_or_else(|| String::from("store.redis_url is required for the redis backend"))?;
+			Arc::new(RedisStore::open(url, &store.redis_prefix, limits)?)
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	pub(super) fn challenge(token: &str, created_at: u64) -> Challenge {
+		Challenge {
+			token: token.to_owned(),
+			challenge: format!("challenge-{}", token),
+			created_at,
+			attempts: 0,
+			difficulty_bits: 12,
+			return_to: Some(String::from("/articles/1?page=2")),
+		}
+	}
+
+	pub(super) fn limits(capacity: usize, policy: EvictionPolicy) -> StoreLimits {
+		StoreLimits {
+			capacity,
+			policy,
+			expiry_secs: 300,
+		}
+	}
+
+	/// Behaviour every backend must share
+	pub(super) async fn exercise_store(store: &dyn ChallengeStore) {
+		assert!(store.insert(challenge("a", 1_000)).await.unwrap());
+		assert_eq!(store.len().await.unwrap(), 1);
+
+		let counted = store.increment_attempts("a").await.unwrap().unwrap();
+		assert_eq!(counted.attempts, 1);
+		assert_eq!(counted.difficulty_bits, 12);
+		assert_eq!(counted.return_to.as_deref(), Some("/articles/1?page=2"));
+		assert_eq!(store.get("a").await.unwrap().unwrap().attempts, 1);
+		assert!(store.increment_attempts("missing").await.unwrap().is_none());
+
+		assert!(store.take("a").await.unwrap().is_some());
+		assert!(store.take("a").await.unwrap().is_none());
+		assert!(store.is_empty().await.unwrap());
+
+		store.insert(challenge("old", 1_000)).await.unwrap();
+		store.insert(challenge("new", 1_250)).await.unwrap();
+		assert_eq!(store.sweep(1_400).await.unwrap(), 1);
+		assert!(store.get("old").await.unwrap().is_none());
+		assert!(store.get("new").await.unwrap().is_some());
+	}
+
+	#[test]
+	fn test_backend_from_str() {
+		assert_eq!("sharded".parse::<StoreBackend>(), Ok(StoreBackend::Sharded));
+		assert!("disk".parse::<StoreBackend>().is_err());
+	}
+
+	#[test]
+	fn test_file_backend_requires_path() {
+		let store = StoreConfig {
+			backend: StoreBackend::File,
+			..StoreConfig::default()
+		};
+		assert!(open_store(&store, &PowConfig::default()).is_err());
+	}
+}
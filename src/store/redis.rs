@@ -0,0 +1,611 @@
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands, Client};
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+use super::{ChallengeStore, StoreLimits};
+use crate::challenges::{Challenge, EvictionPolicy};
+
+/// Extra lifetime given to challenge keys past `expiry_secs`, so a late
+/// submission is told "Challenge expired" rather than "No active challenge"
+const EXPIRED_GRACE_SECS: u64 = 60;
+
+/// Challenge store on a Redis-protocol server, shared by every replica
+///
+/// Each challenge is a hash at `<prefix>challenge:<token>` with a native TTL,
+/// and attempts are counted with `HINCRBY`. A sorted set at `<prefix>challenges`
+/// indexes tokens by issue time for capacity checks, eviction and sweeping.
+/// Solved stateless challenges are recorded at `<prefix>spent:<id>` with
+/// `SET NX EX`, so a solution is accepted by one replica only, and their
+/// attempts are counted at `<prefix>attempts:<id>`.
+/// The capacity bound is checked and enforced in separate round trips, so
+/// replicas issuing at the same moment may overshoot it slightly.
+pub struct RedisStore {
+	client: Client,
+	connection: OnceCell<ConnectionManager>,
+	prefix: String,
+	limits: StoreLimits,
+}
+
+impl RedisStore {
+	/// Creates a store for `url`; the connection is made on first use
+	///
+	/// # Arguments
+	/// * `url` - e.g. `redis://redis:6379/0`
+	/// * `prefix` - prepended to every key, so several gates can share a server
+	/// * `limits` - capacity, eviction policy and expiry
+	pub fn open(url: &str, prefix: &str, limits: StoreLimits) -> Result<Self, String> {
+		let client = Client::open(url).map_err(|e| format!("invalid redis url: {}", e))?;
+		Ok(Self {
+			client,
+			connection: OnceCell::new(),
+			prefix: prefix.to_owned(),
+			limits,
+		})
+	}
+
+	async fn connection(&self) -> Result<ConnectionManager, String> {
+		self.connection
+			.get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+			.await
+			.cloned()
+			.map_err(redis_error)
+	}
+
+	fn key(&self, token: &str) -> String {
+		format!("{}challenge:{}", self.prefix, token)
+	}
+
+	fn spent_key(&self, id: &str) -> String {
+		format!("{}spent:{}", self.prefix, id)
+	}
+
+	fn attempts_key(&self, id: &str) -> String {
+		format!("{}attempts:{}", self.prefix, id)
+	}
+
+	fn index(&self) -> String {
+		format!("{}challenges", self.prefix)
+	}
+
+	/// Deletes every challenge issued before `now - expiry_secs`
+	async fn sweep_expired(&self, conn: &mut ConnectionManager, now: u64) -> Result<usize, String> {
+		let cutoff = now.saturating_sub(self.limits.expiry_secs);
+		let expired: Vec<String> = conn
+			.zrangebyscore(self.index(), "-inf", format!("({}", cutoff))
+			.await
+			.map_err(redis_error)?;
+		self.remove_tokens(conn, &expired).await
+	}
+
+	async fn remove_tokens(&self, conn: &mut ConnectionManager, tokens: &[String]) -> Result<usize, String> {
+		if tokens.is_empty() {
+			return Ok(0);
+		}
+		let keys: Vec<String> = tokens.iter().map(|t| self.key(t)).collect();
+		let (_, removed): (usize, usize) = redis::pipe()
+			.atomic()
+			.del(keys)
+			.zrem(self.index(), tokens)
+			.query_async(conn)
+			.await
+			.map_err(redis_error)?;
+		Ok(removed)
+	}
+}
+
+fn redis_error(error: redis::RedisError) -> String {
+	format!("redis: {}", error)
+}
+
+/// Rebuilds a challenge from its hash; `None` if the hash does not exist
+fn from_fields(fields: HashMap<String, String>) -> Option<Challenge> {
+	Some(Challenge {
+		token: fields.get("token")?.clone(),
+		challenge: fields.get("challenge")?.clone(),
+		created_at: fields.get("created_at")?.parse().ok()?,
+		attempts: fields.get("attempts")?.parse().ok()?,
+		difficulty_bits: fields.get("difficulty_bits")?.parse().ok()?,
+		return_to: fields.get("return_to").filter(|uri| !uri.is_empty()).cloned(),
+	})
+}
+
+#[async_trait]
+impl ChallengeStore for RedisStore {
+	async fn insert(&self, challenge: Challenge) -> Result<bool, String> {
+		let mut conn = self.connection().await?;
+		let index = self.index();
+
+		let mut outstanding: usize = conn.zcard(&index).await.map_err(redis_error)?;
+		if outstanding >= self.limits.capacity {
+			outstanding -= self.sweep_expired(&mut conn, challenge.created_at).await?;
+		}
+		if outstanding >= self.limits.capacity {
+			if self.limits.policy == EvictionPolicy::Reject {
+				return Ok(false);
+			}
+			let excess = outstanding + 1 - self.limits.capacity;
+			let oldest: Vec<(String, f64)> = conn
+				.zpopmin(&index, excess as isize)
+				.await
+				.map_err(redis_error)?;
+			let tokens: Vec<String> = oldest.into_iter().map(|(token, _)| token).collect();
+			let keys: Vec<String> = tokens.iter().map(|t| self.key(t)).collect();
+			if !keys.is_empty() {
+				let _: () = conn.del(keys).await.map_err(redis_error)?;
+			}
+		}
+
+		let key = self.key(&challenge.token);
+		let _: () = redis::pipe()
+			.atomic()
+			.hset_multiple(
+				&key,
+				&[
+					("token", challenge.token.clone()),
+					("challenge", challenge.challenge.clone()),
+					("created_at", challenge.created_at.to_string()),
+					("attempts", challenge.attempts.to_string()),
+					("difficulty_bits", challenge.difficulty_bits.to_string()),
+					("return_to", challenge.return_to.clone().unwrap_or_default()),
+				],
+			)
+			.ignore()
+			.expire(&key, (self.limits.expiry_secs + EXPIRED_GRACE_SECS) as i64)
+			.ignore()
+			.zadd(&index, &challenge.token, challenge.created_at)
+			.ignore()
+			.query_async(&mut conn)
+			.await
+			.map_err(redis_error)?;
+		Ok(true)
+	}
+
+	async fn get(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let mut conn = self.connection().await?;
+		let fields: HashMap<String, String> = conn.hgetall(self.key(token)).await.map_err(redis_error)?;
+		Ok(from_fields(fields))
+	}
+
+	async fn take(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let mut conn = self.connection().await?;
+		let key = self.key(token);
+		// Only the caller whose DEL removed the key gets the challenge
+		let (fields, deleted): (HashMap<String, String>, usize) = redis::pipe()
+			.atomic()
+			.hgetall(&key)
+			.del(&key)
+			.zrem(self.index(), token)
+			.ignore()
+			.query_async(&mut conn)
+			.await
+			.map_err(redis_error)?;
+		Ok(if deleted == 0 { None } else { from_fields(fields) })
+	}
+
+	async fn increment_attempts(&self, token: &str) -> Result<Option<Challenge>, String> {
+		let mut conn = self.connection().await?;
+		let key = self.key(token);
+		let (_, fields): (u32, HashMap<String, String>) = redis::pipe()
+			.atomic()
+			.hincr(&key, "attempts", 1)
+			.hgetall(&key)
+			.query_async(&mut conn)
+			.await
+			.map_err(redis_error)?;
+		let challenge = from_fields(fields);
+		if challenge.is_none() {
+			// HINCRBY created a bare counter for an unknown token
+			let _: () = conn.del(&key).await.map_err(redis_error)?;
+		}
+		Ok(challenge)
+	}
+
+	async fn sweep(&self, now: u64) -> Result<usize, String> {
+		let mut conn = self.connection().await?;
+		self.sweep_expired(&mut conn, now).await
+	}
+
+	async fn len(&self) -> Result<usize, String> {
+		let mut conn = self.connection().await?;
+		conn.zcard(self.index()).await.map_err(redis_error)
+	}
+
+	fn shares_spent_ids(&self) -> bool {
+		true
+	}
+
+	async fn spend(&self, id: &str, ttl_secs: u64) -> Result<bool, String> {
+		let mut conn = self.connection().await?;
+		let recorded: Option<String> = redis::cmd("SET")
+			.arg(self.spent_key(id))
+			.arg(1)
+			.arg("NX")
+			.arg("EX")
+			.arg(ttl_secs.max(1))
+			.query_async(&mut conn)
+			.await
+			.map_err(redis_error)?;
+		Ok(recorded.is_some())
+	}
+
+	async fn is_spent(&self, id: &str) -> Result<bool, String> {
+		let mut conn = self.connection().await?;
+		conn.exists(self.spent_key(id)).await.map_err(redis_error)
+	}
+
+	async fn count_attempt(&self, id: &str, ttl_secs: u64) -> Result<u32, String> {
+		let mut conn = self.connection().await?;
+		let key = self.attempts_key(id);
+		let (attempts, _): (u32, bool) = redis::pipe()
+			.atomic()
+			.incr(&key, 1)
+			.expire(&key, ttl_secs.max(1) as i64)
+			.query_async(&mut conn)
+			.await
+			.map_err(redis_error)?;
+		Ok(attempts)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::tests::{challenge, exercise_store, limits};
+	use std::{
+		collections::BTreeMap,
+		sync::{Arc, Mutex},
+		time::{Duration, Instant},
+	};
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+		net::{TcpListener, TcpStream},
+	};
+
+	#[derive(Debug)]
+	enum Value {
+		Hash(HashMap<String, String>),
+		ZSet(BTreeMap<String, f64>),
+		String(String),
+	}
+
+	#[derive(Debug, Default)]
+	struct Db {
+		values: HashMap<String, Value>,
+		expires: HashMap<String, Instant>,
+	}
+
+	enum Reply {
+		Ok,
+		Queued,
+		Int(i64),
+		Bulk(Option<String>),
+		Array(Vec<Reply>),
+		Error(String),
+	}
+
+	impl Reply {
+		fn encode(&self, out: &mut Vec<u8>) {
+			match self {
+				Reply::Ok => out.extend_from_slice(b"+OK\r\n"),
+				Reply::Queued => out.extend_from_slice(b"+QUEUED\r\n"),
+				Reply::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+				Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+				Reply::Bulk(Some(s)) => {
+					out.extend_from_slice(format!("${}\r\n{}\r\n", s.len(), s).as_bytes())
+				}
+				Reply::Array(items) => {
+					out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+					items.iter().for_each(|item| item.encode(out));
+				}
+				Reply::Error(e) => out.extend_from_slice(format!("-ERR {}\r\n", e).as_bytes()),
+			}
+		}
+	}
+
+	fn bulk_array<I: IntoIterator<Item = String>>(items: I) -> Reply {
+		Reply::Array(items.into_iter().map(|s| Reply::Bulk(Some(s))).collect())
+	}
+
+	fn parse_bound(bound: &str) -> (f64, bool) {
+		let (value, exclusive) = match bound.strip_prefix('(') {
+			Some(rest) => (rest, true),
+			None => (bound, false),
+		};
+		let value = match value {
+			"-inf" => f64::NEG_INFINITY,
+			"+inf" => f64::INFINITY,
+			v => v.parse().unwrap(),
+		};
+		(value, exclusive)
+	}
+
+	impl Db {
+		fn purge(&mut self, key: &str) {
+			if self.expires.get(key).is_some_and(|at| *at <= Instant::now()) {
+				self.expires.remove(key);
+				self.values.remove(key);
+			}
+		}
+
+		fn hash(&mut self, key: &str) -> &mut HashMap<String, String> {
+			match self.values.entry(key.to_owned()).or_insert_with(|| Value::Hash(HashMap::new())) {
+				Value::Hash(h) => h,
+				_ => panic!("WRONGTYPE"),
+			}
+		}
+
+		fn zset(&mut self, key: &str) -> &mut BTreeMap<String, f64> {
+			match self.values.entry(key.to_owned()).or_insert_with(|| Value::ZSet(BTreeMap::new())) {
+				Value::ZSet(z) => z,
+				_ => panic!("WRONGTYPE"),
+			}
+		}
+
+		fn zrange(&mut self, key: &str, min: &str, max: &str) -> Vec<String> {
+			let (min, min_ex) = parse_bound(min);
+			let (max, max_ex) = parse_bound(max);
+			let mut members: Vec<(String, f64)> = self
+				.zset(key)
+				.iter()
+				.filter(|(_, &s)| (if min_ex { s > min } else { s >= min }) && (if max_ex { s < max } else { s <= max }))
+				.map(|(m, &s)| (m.clone(), s))
+				.collect();
+			members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+			members.into_iter().map(|(m, _)| m).collect()
+		}
+
+		fn execute(&mut self, args: &[String]) -> Reply {
+			let name = args[0].to_ascii_uppercase();
+			for key in args.iter().skip(1) {
+				self.purge(key);
+			}
+			match name.as_str() {
+				"PING" => Reply::Bulk(Some(String::from("PONG"))),
+				"CLIENT" | "SELECT" => Reply::Ok,
+				"HSET" | "HMSET" => {
+					let hash = self.hash(&args[1]);
+					let mut added = 0;
+					for pair in args[2..].chunks(2) {
+						if hash.insert(pair[0].clone(), pair[1].clone()).is_none() {
+							added += 1;
+						}
+					}
+					if name == "HMSET" {
+						Reply::Ok
+					} else {
+						Reply::Int(added)
+					}
+				}
+				"SET" => {
+					let options: Vec<String> = args[3..].iter().map(|a| a.to_ascii_uppercase()).collect();
+					if options.contains(&String::from("NX")) && self.values.contains_key(&args[1]) {
+						return Reply::Bulk(None);
+					}
+					self.values.insert(args[1].clone(), Value::String(args[2].clone()));
+					match options.iter().position(|o| o == "EX") {
+						Some(i) => {
+							let secs: u64 = args[4 + i].parse().unwrap();
+							self.expires.insert(args[1].clone(), Instant::now() + Duration::from_secs(secs));
+						}
+						None => {
+							self.expires.remove(&args[1]);
+						}
+					}
+					Reply::Ok
+				}
+				"EXISTS" => Reply::Int(args[1..].iter().filter(|k| self.values.contains_key(*k)).count() as i64),
+				"INCRBY" => {
+					let value = args[2].parse::<i64>().unwrap()
+						+ match self.values.get(&args[1]) {
+							Some(Value::String(v)) => v.parse::<i64>().unwrap(),
+							Some(_) => panic!("WRONGTYPE"),
+							None => 0,
+						};
+					self.values.insert(args[1].clone(), Value::String(value.to_string()));
+					Reply::Int(value)
+				}
+				"HGETALL" => match self.values.get(&args[1]) {
+					Some(Value::Hash(h)) => bulk_array(h.iter().flat_map(|(k, v)| [k.clone(), v.clone()])),
+					_ => Reply::Array(Vec::new()),
+				},
+				"HINCRBY" => {
+					let hash = self.hash(&args[1]);
+					let value = hash.get(&args[2]).map_or(0, |v| v.parse::<i64>().unwrap())
+						+ args[3].parse::<i64>().unwrap();
+					hash.insert(args[2].clone(), value.to_string());
+					Reply::Int(value)
+				}
+				"EXPIRE" => {
+					let secs: u64 = args[2].parse().unwrap();
+					if !self.values.contains_key(&args[1]) {
+						return Reply::Int(0);
+					}
+					self.expires.insert(args[1].clone(), Instant::now() + Duration::from_secs(secs));
+					Reply::Int(1)
+				}
+				"DEL" => {
+					let removed = args[1..].iter().filter(|k| self.values.remove(*k).is_some()).count();
+					args[1..].iter().for_each(|k| {
+						self.expires.remove(k);
+					});
+					Reply::Int(removed as i64)
+				}
+				"ZADD" => {
+					let zset = self.zset(&args[1]);
+					let mut added = 0;
+					for pair in args[2..].chunks(2) {
+						if zset.insert(pair[1].clone(), pair[0].parse().unwrap()).is_none() {
+							added += 1;
+						}
+					}
+					Reply::Int(added)
+				}
+				"ZREM" => {
+					let zset = self.zset(&args[1]);
+					Reply::Int(args[2..].iter().filter(|m| zset.remove(*m).is_some()).count() as i64)
+				}
+				"ZCARD" => match self.values.get(&args[1]) {
+					Some(Value::ZSet(z)) => Reply::Int(z.len() as i64),
+					_ => Reply::Int(0),
+				},
+				"ZRANGEBYSCORE" => bulk_array(self.zrange(&args[1], &args[2], &args[3])),
+				"ZPOPMIN" => {
+					let count = args.get(2).map_or(1, |c| c.parse().unwrap());
+					let popped: Vec<String> = self.zrange(&args[1], "-inf", "+inf").into_iter().take(count).collect();
+					let zset = self.zset(&args[1]);
+					let mut reply = Vec::new();
+					for member in popped {
+						let score = zset.remove(&member).unwrap();
+						reply.push(member);
+						reply.push(score.to_string());
+					}
+					bulk_array(reply)
+				}
+				_ => Reply::Error(format!("unknown command '{}'", name)),
+			}
+		}
+	}
+
+	async fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+		let mut line = String::new();
+		if reader.read_line(&mut line).await.ok()? == 0 {
+			return None;
+		}
+		let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+		let mut args = Vec::with_capacity(count);
+		for _ in 0..count {
+			line.clear();
+			reader.read_line(&mut line).await.ok()?;
+			let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+			let mut buf = vec![0u8; len + 2];
+			reader.read_exact(&mut buf).await.ok()?;
+			buf.truncate(len);
+			args.push(String::from_utf8(buf).ok()?);
+		}
+		Some(args)
+	}
+
+	/// Minimal in-process server speaking enough RESP for [`RedisStore`]
+	async fn spawn_standin() -> (String, Arc<Mutex<Db>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("redis://{}/", listener.local_addr().unwrap());
+		let db = Arc::new(Mutex::new(Db::default()));
+		let shared = db.clone();
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				let db = shared.clone();
+				tokio::spawn(async move {
+					let mut reader = BufReader::new(stream);
+					let mut queued: Option<Vec<Vec<String>>> = None;
+					while let Some(args) = read_command(&mut reader).await {
+						let reply = match (args[0].to_ascii_uppercase().as_str(), queued.as_mut()) {
+							("MULTI", _) => {
+								queued = Some(Vec::new());
+								Reply::Ok
+							}
+							("EXEC", _) => {
+								let mut db = db.lock().unwrap();
+								let commands = queued.take().unwrap_or_default();
+								Reply::Array(commands.iter().map(|c| db.execute(c)).collect())
+							}
+							(_, Some(queue)) => {
+								queue.push(args);
+								Reply::Queued
+							}
+							(_, None) => db.lock().unwrap().execute(&args),
+						};
+						let mut out = Vec::new();
+						reply.encode(&mut out);
+						if reader.get_mut().write_all(&out).await.is_err() {
+							return;
+						}
+					}
+				});
+			}
+		});
+		(url, db)
+	}
+
+	#[tokio::test]
+	async fn test_redis_store() {
+		let (url, _) = spawn_standin().await;
+		let store = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+		exercise_store(&store).await;
+	}
+
+	#[tokio::test]
+	async fn test_challenges_have_native_ttl() {
+		let (url, db) = spawn_standin().await;
+		let store = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+		store.insert(challenge("a", 1_000)).await.unwrap();
+
+		assert!(db.lock().unwrap().expires.contains_key("test:challenge:a"));
+	}
+
+	#[tokio::test]
+	async fn test_replicas_share_challenges() {
+		let (url, _) = spawn_standin().await;
+		let first = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+		let second = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+		first.insert(challenge("shared", 1_000)).await.unwrap();
+
+		assert_eq!(second.increment_attempts("shared").await.unwrap().unwrap().attempts, 1);
+		assert!(second.take("shared").await.unwrap().is_some());
+		assert!(first.take("shared").await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn test_unknown_token_leaves_no_counter() {
+		let (url, db) = spawn_standin().await;
+		let store = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+
+		assert!(store.increment_attempts("missing").await.unwrap().is_none());
+		assert!(db.lock().unwrap().values.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_bounded_insert() {
+		let (url, _) = spawn_standin().await;
+		let store = RedisStore::open(&url, "test:", limits(2, EvictionPolicy::Oldest)).unwrap();
+		store.insert(challenge("a", 1_000)).await.unwrap();
+		store.insert(challenge("b", 1_001)).await.unwrap();
+		assert!(store.insert(challenge("c", 1_002)).await.unwrap());
+		assert!(store.get("a").await.unwrap().is_none());
+		assert_eq!(store.len().await.unwrap(), 2);
+
+		let store = RedisStore::open(&url, "test:", limits(2, EvictionPolicy::Reject)).unwrap();
+		assert!(!store.insert(challenge("d", 1_003)).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_replicas_share_spent_ids() {
+		let (url, db) = spawn_standin().await;
+		let first = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+		let second = RedisStore::open(&url, "test:", limits(10, EvictionPolicy::Oldest)).unwrap();
+
+		assert!(first.shares_spent_ids());
+		assert!(first.spend("00ff", 300).await.unwrap());
+		assert!(!second.spend("00ff", 300).await.unwrap());
+		assert!(second.spend("0100", 300).await.unwrap());
+		assert!(second.is_spent("00ff").await.unwrap());
+		assert!(!first.is_spent("0200").await.unwrap());
+		assert_eq!(first.count_attempt("0200", 300).await.unwrap(), 1);
+		assert_eq!(second.count_attempt("0200", 300).await.unwrap(), 2);
+		let db = db.lock().unwrap();
+		assert!(matches!(db.values.get("test:spent:00ff"), Some(Value::String(v)) if v == "1"));
+		assert!(db.expires.contains_key("test:spent:00ff"));
+		assert!(db.expires.contains_key("test:attempts:0200"));
+	}
+
+	/// Runs the shared checks against a real server when `MPOW_TEST_REDIS_URL` is set
+	#[tokio::test]
+	async fn test_real_server() {
+		let Ok(url) = std::env::var("MPOW_TEST_REDIS_URL") else {
+			return;
+		};
+		let prefix = format!("mpow-test-{}:", uuid::Uuid::new_v4());
+		let store = RedisStore::open(&url, &prefix, limits(10, EvictionPolicy::Oldest)).unwrap();
+		exercise_store(&store).await;
+	}
+}
@@ -1,20 +1,48 @@
+//! Built-in defaults. Every value here can be overridden at runtime through
+//! the configuration file or `MPOW_*` environment variables (see `config.rs`).
+
+pub const BIND_ADDR: &str = "0.0.0.0:3000";
+/// Proxies whose forwarding headers are believed by default
+pub const TRUSTED_PROXIES: &str = "127.0.0.0/8,::1/128";
+pub const CONFIG_PATH: &str = "mpow.toml";
 pub const COOKIE_NAME: &str = "mpow_token";
-pub const TOKEN_BYTE_LENGTH: usize = 24;
 pub const TOKEN_EXPIRY_SECS: u64 = 36 * 3600;
 pub const CHALLENGE_EXPIRY_SECS: u64 = 300;
-pub const POW_DIFFICULTY: usize = 4;
+pub const POW_DIFFICULTY_BITS: u32 = 16;
+pub const SCRYPT_LOG_N: u8 = 14;
+pub const SCRYPT_R: u32 = 8;
+pub const SCRYPT_P: u32 = 1;
+/// Highest difficulty accepted with scrypt; each attempt already costs milliseconds
+pub const SCRYPT_MAX_DIFFICULTY_BITS: u32 = 12;
 pub const MAX_ATTEMPTS: u32 = 15;
-pub const POW_DIFFICULTY_PREFIX: &str = "0000";
 pub const MAX_NONCE_LENGTH: usize = 128;
-pub const MAX_CHALLENGE_ATTEMPTS: usize = 15;
-pub const USE_LOKI: bool = false;
-
-pub use time::Duration;
-
-/// Debug helper
-pub fn demo_values() {
-	println!("values module demo called");
-}
+pub const MAX_OUTSTANDING_CHALLENGES: usize = 100_000;
+pub const JANITOR_INTERVAL_SECS: u64 = 30;
+pub const ADAPTIVE_WINDOW_SECS: u64 = 10;
+/// Default ceiling above the floor when `max_bits` is not configured
+pub const ADAPTIVE_HEADROOM_BITS: u32 = 4;
+pub const ADAPTIVE_ISSUE_RATE_HIGH: f64 = 20.0;
+pub const ADAPTIVE_FAILURE_RATIO_HIGH: f64 = 0.5;
+pub const ADAPTIVE_OUTSTANDING_HIGH: usize = 10_000;
+pub const REPUTATION_HALF_LIFE_SECS: u64 = 600;
+pub const REPUTATION_POINTS_PER_BIT: f64 = 8.0;
+pub const REPUTATION_SUBNET_POINTS_PER_BIT: f64 = 64.0;
+pub const REPUTATION_MAX_EXTRA_BITS: u32 = 4;
+pub const REPUTATION_MAX_CLIENTS: usize = 100_000;
+/// Default `iss` and `aud` of session tokens
+pub const JWT_ISSUER: &str = "mpow";
+pub const JWT_AUDIENCE: &str = "mpow";
+pub const MAX_SESSION_SECS: u64 = 7 * 24 * 3600;
+/// Response header carrying a refreshed session cookie out of `/validate`,
+/// for `auth_request_set` in nginx
+pub const REFRESH_COOKIE_HEADER: &str = "x-mpow-set-cookie";
+pub const QUOTA_RATE_WINDOW_SECS: u64 = 60;
+pub const QUOTA_MAX_TRACKED_TOKENS: usize = 1_000_000;
+pub const BINDING_IPV4_PREFIX: u8 = 24;
+pub const BINDING_IPV6_PREFIX: u8 = 64;
+pub const MAX_RETURN_TO_LENGTH: usize = 2048;
+pub const STORE_SHARDS: usize = 16;
+pub const REDIS_PREFIX: &str = "mpow:";
 
 #[cfg(test)]
 mod tests {
@@ -22,20 +50,41 @@ mod tests {
 
 	#[test]
 	fn constants_exist() {
+		let _ = BIND_ADDR;
+		let _ = TRUSTED_PROXIES;
+		let _ = CONFIG_PATH;
 		let _ = COOKIE_NAME;
-		let _ = TOKEN_BYTE_LENGTH;
 		let _ = TOKEN_EXPIRY_SECS;
 		let _ = CHALLENGE_EXPIRY_SECS;
-		let _ = POW_DIFFICULTY_PREFIX;
 		let _ = MAX_NONCE_LENGTH;
-		let _ = MAX_CHALLENGE_ATTEMPTS;
-		let _ = USE_LOKI;
-		let _ = POW_DIFFICULTY;
+		let _ = POW_DIFFICULTY_BITS;
+		let _ = SCRYPT_LOG_N;
+		let _ = SCRYPT_R;
+		let _ = SCRYPT_P;
+		let _ = SCRYPT_MAX_DIFFICULTY_BITS;
 		let _ = MAX_ATTEMPTS;
-	}
-
-	#[test]
-	fn demo_function_exists_values() {
-		demo_values();
+		let _ = MAX_OUTSTANDING_CHALLENGES;
+		let _ = JANITOR_INTERVAL_SECS;
+		let _ = ADAPTIVE_WINDOW_SECS;
+		let _ = ADAPTIVE_HEADROOM_BITS;
+		let _ = ADAPTIVE_ISSUE_RATE_HIGH;
+		let _ = ADAPTIVE_FAILURE_RATIO_HIGH;
+		let _ = ADAPTIVE_OUTSTANDING_HIGH;
+		let _ = REPUTATION_HALF_LIFE_SECS;
+		let _ = REPUTATION_POINTS_PER_BIT;
+		let _ = REPUTATION_SUBNET_POINTS_PER_BIT;
+		let _ = REPUTATION_MAX_EXTRA_BITS;
+		let _ = REPUTATION_MAX_CLIENTS;
+		let _ = JWT_ISSUER;
+		let _ = JWT_AUDIENCE;
+		let _ = MAX_SESSION_SECS;
+		let _ = REFRESH_COOKIE_HEADER;
+		let _ = QUOTA_RATE_WINDOW_SECS;
+		let _ = QUOTA_MAX_TRACKED_TOKENS;
+		let _ = BINDING_IPV4_PREFIX;
+		let _ = BINDING_IPV6_PREFIX;
+		let _ = MAX_RETURN_TO_LENGTH;
+		let _ = STORE_SHARDS;
+		let _ = REDIS_PREFIX;
 	}
 }